@@ -0,0 +1,139 @@
+use std::{
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::mpsc::{Receiver, TryRecvError},
+    time::{Duration, Instant},
+};
+
+use color_eyre::{eyre::WrapErr, Result};
+use image::{codecs::gif::GifDecoder, AnimationDecoder, DynamicImage};
+use log::warn;
+
+/// The delay of the frames that have none, or one too short to be meant, like browsers do
+const DEFAULT_DELAY: Duration = Duration::from_millis(100);
+const MIN_DELAY: Duration = Duration::from_millis(20);
+
+/// The frames of an animated image and the one drawn
+pub struct Animation {
+    pub source: PathBuf,
+    /// Each frame, composited on the whole canvas, with how long it is shown
+    frames: Vec<(DynamicImage, Duration)>,
+    index: usize,
+    /// When the frame at `index` has been shown
+    shown_at: Instant,
+    /// The frames being decoded
+    loading: Option<Receiver<Result<Vec<(DynamicImage, Duration)>>>>,
+}
+
+impl Animation {
+    /// Wait for the frames of `source` from `loading`; until then, none is drawn
+    pub fn new(source: PathBuf, loading: Receiver<Result<Vec<(DynamicImage, Duration)>>>) -> Self {
+        Self {
+            source,
+            frames: Vec::new(),
+            index: 0,
+            shown_at: Instant::now(),
+            loading: Some(loading),
+        }
+    }
+
+    /// Take the frames once they have been decoded, the first one is shown from `now`
+    fn poll(&mut self, now: &Instant) {
+        let loading = match &self.loading {
+            Some(loading) => loading,
+            None => return,
+        };
+        let frames = match loading.try_recv() {
+            Ok(Ok(frames)) => frames,
+            Ok(Err(err)) => {
+                warn!("{err:?}");
+                Vec::new()
+            }
+            Err(TryRecvError::Empty) => return,
+            Err(TryRecvError::Disconnected) => {
+                warn!("decoding the frames of {:?} has failed", self.source);
+                Vec::new()
+            }
+        };
+        self.loading = None;
+        self.frames = frames;
+        self.index = 0;
+        self.shown_at = *now;
+    }
+
+    /// Move to the frame to show at `now`, skipping the ones that are late. Return true if
+    /// the frame has changed
+    pub fn advance(&mut self, now: &Instant) -> bool {
+        self.poll(now);
+        if self.frames.len() < 2 {
+            return false;
+        }
+        let start = self.index;
+        while now.saturating_duration_since(self.shown_at) >= self.delay() {
+            self.shown_at += self.delay();
+            self.index = (self.index + 1) % self.frames.len();
+        }
+        self.index != start
+    }
+
+    /// Show the frame drawn again for its whole delay from `now`, after it has been held
+    pub fn restart(&mut self, now: &Instant) {
+        self.shown_at = *now;
+    }
+
+    /// The frame to draw, None until the frames have been decoded
+    pub fn frame(&self) -> Option<&DynamicImage> {
+        self.frames.get(self.index).map(|(frame, _)| frame)
+    }
+
+    /// How long the frame drawn is shown
+    pub fn delay(&self) -> Duration {
+        self.frames
+            .get(self.index)
+            .map_or(DEFAULT_DELAY, |(_, delay)| *delay)
+    }
+
+    /// How long until the next frame is shown, None if there is only one frame or they
+    /// have not been decoded yet
+    pub fn until_next(&mut self, now: &Instant) -> Option<Duration> {
+        self.poll(now);
+        if self.frames.len() < 2 {
+            return None;
+        }
+        Some(
+            self.delay()
+                .saturating_sub(now.saturating_duration_since(self.shown_at)),
+        )
+    }
+}
+
+/// Guess from its extension if `path` is an image that can be animated
+pub fn is_animated(path: &Path) -> bool {
+    path.extension()
+        .map_or(false, |extension| extension.eq_ignore_ascii_case("gif"))
+}
+
+/// Decode all the frames of the animated image at `path`
+pub fn decode_frames(path: &Path) -> Result<Vec<(DynamicImage, Duration)>> {
+    let file = File::open(path).with_context(|| format!("opening the image {path:?}"))?;
+    let decoder = GifDecoder::new(BufReader::new(file))
+        .with_context(|| format!("decoding the image {path:?}"))?;
+    let frames = decoder
+        .into_frames()
+        .collect_frames()
+        .with_context(|| format!("decoding the frames of {path:?}"))?;
+    Ok(frames
+        .into_iter()
+        .map(|frame| {
+            let (numer, denom) = frame.delay().numer_denom_ms();
+            let delay = Duration::from_millis(u64::from(numer / denom.max(1)));
+            let delay = if delay < MIN_DELAY {
+                DEFAULT_DELAY
+            } else {
+                delay
+            };
+            (DynamicImage::ImageRgba8(frame.into_buffer()), delay)
+        })
+        .collect())
+}
@@ -0,0 +1,92 @@
+use std::path::Path;
+#[cfg(feature = "archive")]
+use std::{fs::File, io::Read, path::PathBuf};
+
+#[cfg(feature = "archive")]
+use color_eyre::{
+    eyre::{ensure, eyre, WrapErr},
+    Result,
+};
+
+#[cfg(feature = "archive")]
+use crate::wallpaper_info::is_image;
+
+/// Return true if `path` points to an archive containing wallpapers
+pub fn is_archive(path: &Path) -> bool {
+    path.is_file()
+        && matches!(
+            path.extension().and_then(|ext| ext.to_str()),
+            Some("zip" | "tar")
+        )
+}
+
+#[cfg(feature = "archive")]
+fn is_zip(path: &Path) -> bool {
+    path.extension().map_or(false, |ext| ext == "zip")
+}
+
+/// Tar archives can store the entries prefixed by `./`
+#[cfg(feature = "archive")]
+fn entry_name(path: &Path) -> &Path {
+    path.strip_prefix(".").unwrap_or(path)
+}
+
+/// List the images contained in the archive. Each entry is joined to the path of the
+/// archive, so that it can be told apart from the images of other sources
+#[cfg(feature = "archive")]
+pub fn list_images(archive: &Path) -> Result<Vec<PathBuf>> {
+    let file = File::open(archive).with_context(|| format!("opening the archive {archive:?}"))?;
+    let entries: Vec<PathBuf> = if is_zip(archive) {
+        let zip = zip::ZipArchive::new(file)
+            .with_context(|| format!("reading the zip archive {archive:?}"))?;
+        zip.file_names().map(PathBuf::from).collect()
+    } else {
+        let mut tar = tar::Archive::new(file);
+        let mut entries = Vec::new();
+        for entry in tar.entries()? {
+            let entry = entry?;
+            if entry.header().entry_type().is_file() {
+                entries.push(entry_name(&entry.path()?).to_path_buf());
+            }
+        }
+        entries
+    };
+
+    Ok(entries
+        .into_iter()
+        .filter(|entry| is_image(entry))
+        .map(|entry| archive.join(entry))
+        .collect())
+}
+
+/// Read the (still encoded) content of an entry of the archive
+#[cfg(feature = "archive")]
+pub fn read_entry(archive: &Path, entry: &Path) -> Result<Vec<u8>> {
+    let file = File::open(archive).with_context(|| format!("opening the archive {archive:?}"))?;
+    let mut data = Vec::new();
+    if is_zip(archive) {
+        let mut zip = zip::ZipArchive::new(file)
+            .with_context(|| format!("reading the zip archive {archive:?}"))?;
+        let name = entry
+            .to_str()
+            .ok_or_else(|| eyre!("invalid entry name {entry:?}"))?;
+        zip.by_name(name)?.read_to_end(&mut data)?;
+    } else {
+        let mut tar = tar::Archive::new(file);
+        let mut found = false;
+        for tar_entry in tar.entries()? {
+            let mut tar_entry = tar_entry?;
+            if entry_name(&tar_entry.path()?) == entry {
+                tar_entry.read_to_end(&mut data)?;
+                found = true;
+                break;
+            }
+        }
+        ensure!(
+            found,
+            "entry {entry:?} not found in the archive {archive:?}"
+        );
+    }
+
+    Ok(data)
+}
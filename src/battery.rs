@@ -0,0 +1,61 @@
+use std::{fs, path::Path, time::Duration};
+
+use log::info;
+
+const POWER_SUPPLY_DIR: &str = "/sys/class/power_supply";
+/// How often the battery level is read
+pub const POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Tell when the rotation should be paused to save power, because the battery is discharging
+/// and its level is below the threshold
+pub struct BatteryMonitor {
+    threshold: u8,
+    paused: bool,
+}
+
+impl BatteryMonitor {
+    pub fn new(threshold: u8) -> Self {
+        Self {
+            threshold,
+            paused: false,
+        }
+    }
+
+    /// Read the battery level and return true if the rotation should be paused
+    pub fn check(&mut self) -> bool {
+        let paused = battery_state().map_or(false, |(level, discharging)| {
+            discharging && level < self.threshold
+        });
+        if paused != self.paused {
+            if paused {
+                info!("battery below {}%, pausing the rotation", self.threshold);
+            } else {
+                info!("resuming the rotation");
+            }
+            self.paused = paused;
+        }
+        paused
+    }
+}
+
+/// Read the level (in percentage) of the first battery found and whether it is discharging
+fn battery_state() -> Option<(u8, bool)> {
+    fs::read_dir(POWER_SUPPLY_DIR)
+        .ok()?
+        .filter_map(Result::ok)
+        .find_map(|entry| {
+            let path = entry.path();
+            if read_attribute(&path, "type")? != "Battery" {
+                return None;
+            }
+            let level = read_attribute(&path, "capacity")?.parse().ok()?;
+            let discharging = read_attribute(&path, "status")? == "Discharging";
+            Some((level, discharging))
+        })
+}
+
+fn read_attribute(power_supply: &Path, attribute: &str) -> Option<String> {
+    fs::read_to_string(power_supply.join(attribute))
+        .ok()
+        .map(|value| value.trim().to_string())
+}
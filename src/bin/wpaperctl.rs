@@ -0,0 +1,62 @@
+use std::{
+    io::{Read, Write},
+    net::Shutdown,
+    os::unix::net::UnixStream,
+    process::exit,
+};
+
+use clap::Parser;
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Result,
+};
+use xdg::BaseDirectories;
+
+#[derive(Parser)]
+#[clap(
+    author = "Danilo Spinella <danilo.spinella@suse.com>",
+    version,
+    about = "Send commands to a running wpaperd instance"
+)]
+struct Opts {
+    #[clap(
+        required = true,
+        trailing_var_arg = true,
+        allow_hyphen_values = true,
+        help = "The command to send, followed by its arguments"
+    )]
+    command: Vec<String>,
+}
+
+fn main() -> Result<()> {
+    color_eyre::install()?;
+
+    let opts = Opts::parse();
+
+    let xdg_dirs = BaseDirectories::with_prefix("wpaperd")?;
+    let socket_path = xdg_dirs
+        .find_runtime_file("wpaperd.sock")
+        .ok_or_else(|| eyre!("wpaperd socket not found, is wpaperd running?"))?;
+
+    let mut stream = UnixStream::connect(&socket_path)
+        .with_context(|| format!("connecting to the socket {socket_path:?}"))?;
+    stream
+        .write_all(opts.command.join("\0").as_bytes())
+        .context("sending the command")?;
+    stream.shutdown(Shutdown::Write)?;
+
+    let mut reply = String::new();
+    stream
+        .read_to_string(&mut reply)
+        .context("reading the reply")?;
+
+    if let Some(err) = reply.strip_prefix("error: ") {
+        eprintln!("{}", err.trim_end());
+        exit(1);
+    }
+    if !reply.is_empty() {
+        println!("{}", reply.trim_end());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,129 @@
+use std::{fs::File, io::BufReader, path::Path};
+
+use image::{
+    codecs::{jpeg::JpegDecoder, png::PngDecoder},
+    DynamicImage, ImageDecoder, ImageFormat,
+};
+
+use crate::wallpaper_info::ColorSpace;
+
+/// The linear Display P3 primaries in the linear sRGB ones, both with a D65 white point
+const DISPLAY_P3_TO_SRGB: [[f32; 3]; 3] = [
+    [1.224_940_2, -0.224_940_4, 0.0],
+    [-0.042_056_955, 1.042_057_1, 0.0],
+    [-0.019_637_555, -0.078_636_05, 1.098_273_5],
+];
+
+/// Return true if the image at `path` embeds an ICC profile; only PNG and JPEG images are
+/// read, the others count as untagged
+pub fn has_profile(path: &Path) -> bool {
+    let reader = match File::open(path) {
+        Ok(file) => BufReader::new(file),
+        Err(_) => return false,
+    };
+    match ImageFormat::from_path(path) {
+        Ok(ImageFormat::Png) => PngDecoder::new(reader)
+            .ok()
+            .and_then(|mut decoder| decoder.icc_profile())
+            .is_some(),
+        Ok(ImageFormat::Jpeg) => JpegDecoder::new(reader)
+            .ok()
+            .and_then(|mut decoder| decoder.icc_profile())
+            .is_some(),
+        _ => false,
+    }
+}
+
+/// Convert `image`, whose colors are in `space`, to sRGB, keeping its bit depth
+pub fn to_srgb(image: DynamicImage, space: ColorSpace) -> DynamicImage {
+    if space == ColorSpace::Srgb {
+        return image;
+    }
+    let high_depth = image.color().bytes_per_pixel() > image.color().channel_count();
+    let mut buffer = image.into_rgba32f();
+    for pixel in buffer.pixels_mut() {
+        let [r, g, b, a] = pixel.0;
+        pixel.0 = match space {
+            ColorSpace::Srgb => [r, g, b, a],
+            ColorSpace::Linear => [encode(r), encode(g), encode(b), a],
+            ColorSpace::DisplayP3 => {
+                let rgb = [decode(r), decode(g), decode(b)];
+                let [r, g, b] = DISPLAY_P3_TO_SRGB
+                    .map(|row| encode(row[0] * rgb[0] + row[1] * rgb[1] + row[2] * rgb[2]));
+                [r, g, b, a]
+            }
+        };
+    }
+    let image = DynamicImage::ImageRgba32F(buffer);
+    if high_depth {
+        DynamicImage::ImageRgba16(image.into_rgba16())
+    } else {
+        DynamicImage::ImageRgba8(image.into_rgba8())
+    }
+}
+
+/// The sRGB transfer function, also used by Display P3, from encoded to linear values
+fn decode(value: f32) -> f32 {
+    if value <= 0.040_45 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// The inverse of `decode`, clamping the colors out of the sRGB gamut
+fn encode(value: f32) -> f32 {
+    let value = value.clamp(0.0, 1.0);
+    if value <= 0.003_130_8 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use image::{Rgba, RgbaImage};
+
+    fn convert(pixel: [u8; 4], space: ColorSpace) -> [u8; 4] {
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_pixel(1, 1, Rgba(pixel)));
+        to_srgb(image, space).to_rgba8().get_pixel(0, 0).0
+    }
+
+    #[test]
+    fn linear_to_srgb() {
+        assert_eq!(convert([0, 0, 0, 255], ColorSpace::Linear), [0, 0, 0, 255]);
+        assert_eq!(
+            convert([255, 255, 255, 128], ColorSpace::Linear),
+            [255, 255, 255, 128]
+        );
+        // Half the light is brighter than half the sRGB value
+        assert_eq!(
+            convert([128, 128, 128, 255], ColorSpace::Linear),
+            [188, 188, 188, 255]
+        );
+    }
+
+    #[test]
+    fn display_p3_to_srgb() {
+        // The white point is shared, the greys don't change
+        assert_eq!(
+            convert([255, 255, 255, 255], ColorSpace::DisplayP3),
+            [255, 255, 255, 255]
+        );
+        assert_eq!(
+            convert([128, 128, 128, 255], ColorSpace::DisplayP3),
+            [128, 128, 128, 255]
+        );
+        // The red of Display P3 is out of the sRGB gamut
+        assert_eq!(
+            convert([255, 0, 0, 255], ColorSpace::DisplayP3),
+            [255, 0, 0, 255]
+        );
+        // A less saturated red becomes more saturated in sRGB
+        let [r, g, b, _] = convert([200, 100, 100, 255], ColorSpace::DisplayP3);
+        assert!(r > 200 && g < 100 && b < 100);
+    }
+}
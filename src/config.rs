@@ -29,6 +29,14 @@ pub struct Config {
     )]
     #[serde(rename = "no-daemon")]
     pub no_daemon: bool,
+    #[clap(
+        action,
+        long = "daemon-output",
+        value_enum,
+        help = "Where the standard output and error of the daemon go once it has detached. Defaults to log"
+    )]
+    #[serde(rename = "daemon-output")]
+    pub daemon_output: Option<DaemonOutput>,
     #[clap(
         action,
         long = "use-scaled-window",
@@ -36,6 +44,100 @@ pub struct Config {
     )]
     #[serde(rename = "use-scaled-window")]
     pub use_scaled_window: bool,
+    #[clap(
+        action,
+        long = "random-seed",
+        help = "Seed used to choose the random images. Each output derives its own seed from it"
+    )]
+    #[serde(rename = "random-seed")]
+    pub random_seed: Option<u64>,
+    #[clap(
+        action,
+        long = "pause-below-battery",
+        help = "Pause the rotation while the battery is discharging and below this percentage"
+    )]
+    #[serde(rename = "pause-below-battery")]
+    pub pause_below_battery: Option<u8>,
+    #[clap(
+        action,
+        long = "startup-stagger",
+        help = "Milliseconds to wait between the first wallpaper of each output, drawn by `startup-priority`"
+    )]
+    #[serde(rename = "startup-stagger")]
+    pub startup_stagger: Option<u64>,
+    #[clap(
+        action,
+        long = "max-commit-fps",
+        help = "Redraw each output at most this many times per second, merging the redraws in between"
+    )]
+    #[serde(rename = "max-commit-fps")]
+    pub max_commit_fps: Option<u32>,
+    #[clap(
+        action,
+        long = "history-length",
+        help = "How many of the last wallpapers of each output to remember. Defaults to 32"
+    )]
+    #[serde(rename = "history-length")]
+    pub history_length: Option<usize>,
+    #[clap(
+        action,
+        long = "max-buffer-size",
+        help = "Maximum size of the buffer of an output, in MiB. Lower its scale to fit in it"
+    )]
+    #[serde(rename = "max-buffer-size")]
+    pub max_buffer_size: Option<u64>,
+    #[clap(
+        action,
+        long = "decode-threads",
+        help = "How many images can be decoded at the same time. Defaults to the number of outputs, up to the number of CPUs"
+    )]
+    #[serde(rename = "decode-threads")]
+    pub decode_threads: Option<usize>,
+    #[clap(
+        action,
+        long = "idle-dim-timeout",
+        help = "Dim the wallpapers after this many seconds of inactivity"
+    )]
+    #[serde(rename = "idle-dim-timeout")]
+    pub idle_dim_timeout: Option<u64>,
+    #[clap(
+        action,
+        long = "idle-dim-brightness",
+        help = "Brightness of the wallpapers while idle, between 0 and 1. Defaults to 0.5"
+    )]
+    #[serde(rename = "idle-dim-brightness")]
+    pub idle_dim_brightness: Option<f32>,
+    #[clap(
+        action,
+        long = "rotate-only-when-idle",
+        help = "Defer the change of the wallpapers until there has been no activity for this many seconds"
+    )]
+    #[serde(rename = "rotate-only-when-idle")]
+    pub rotate_only_when_idle: Option<u64>,
+    #[clap(
+        action,
+        long = "disconnect-grace",
+        help = "Seconds to remember the wallpaper of a disconnected output, resuming it if the output comes back"
+    )]
+    #[serde(rename = "disconnect-grace")]
+    pub disconnect_grace: Option<u64>,
+    #[clap(
+        action,
+        long,
+        help = "Check the configuration and the compositor before starting, printing a report. Exit on failure"
+    )]
+    #[serde(skip)]
+    pub selftest: bool,
+}
+
+/// Where the standard output and error of the daemon are redirected after forking
+#[derive(Clone, Copy, Deserialize, PartialEq, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum DaemonOutput {
+    /// Write each line in the log file
+    Log,
+    /// Discard them
+    Null,
 }
 
 impl Config {
@@ -44,6 +146,55 @@ impl Config {
             self.output_config = Some(output_config);
         }
 
+        if let Some(daemon_output) = o.daemon_output {
+            self.daemon_output = Some(daemon_output);
+        }
+
+        if let Some(random_seed) = o.random_seed {
+            self.random_seed = Some(random_seed);
+        }
+
+        if let Some(pause_below_battery) = o.pause_below_battery {
+            self.pause_below_battery = Some(pause_below_battery);
+        }
+
+        if let Some(startup_stagger) = o.startup_stagger {
+            self.startup_stagger = Some(startup_stagger);
+        }
+
+        if let Some(max_commit_fps) = o.max_commit_fps {
+            self.max_commit_fps = Some(max_commit_fps);
+        }
+
+        if let Some(history_length) = o.history_length {
+            self.history_length = Some(history_length);
+        }
+
+        if let Some(max_buffer_size) = o.max_buffer_size {
+            self.max_buffer_size = Some(max_buffer_size);
+        }
+
+        if let Some(decode_threads) = o.decode_threads {
+            self.decode_threads = Some(decode_threads);
+        }
+
+        if let Some(idle_dim_timeout) = o.idle_dim_timeout {
+            self.idle_dim_timeout = Some(idle_dim_timeout);
+        }
+
+        if let Some(idle_dim_brightness) = o.idle_dim_brightness {
+            self.idle_dim_brightness = Some(idle_dim_brightness);
+        }
+
+        if let Some(rotate_only_when_idle) = o.rotate_only_when_idle {
+            self.rotate_only_when_idle = Some(rotate_only_when_idle);
+        }
+
+        if let Some(disconnect_grace) = o.disconnect_grace {
+            self.disconnect_grace = Some(disconnect_grace);
+        }
+
         self.no_daemon |= o.no_daemon;
+        self.selftest |= o.selftest;
     }
 }
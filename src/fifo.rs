@@ -0,0 +1,84 @@
+use std::{
+    fs::{self, File},
+    io::{ErrorKind, Read},
+    os::unix::fs::FileTypeExt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use log::{error, info};
+use smithay_client_toolkit::reexports::calloop::channel::Sender;
+
+/// How often to check if a FIFO that doesn't exist yet has been created
+const CREATION_POLL: Duration = Duration::from_secs(1);
+
+/// Return true if `path` points to a FIFO
+pub fn is_fifo(path: &Path) -> bool {
+    fs::metadata(path).map_or(false, |metadata| metadata.file_type().is_fifo())
+}
+
+/// The images listed in a FIFO, one path per line. The list is replaced every time a
+/// new one is written into the FIFO
+#[derive(Clone)]
+pub struct FifoPool {
+    images: Arc<Mutex<Vec<PathBuf>>>,
+}
+
+impl FifoPool {
+    /// Start reading the lists written into the FIFO at `path`. `ev_tx` wakes up the event
+    /// loop every time a new list has been read
+    pub fn new(path: &Path, ev_tx: Sender<()>) -> Self {
+        let images = Arc::new(Mutex::new(Vec::new()));
+        let pool = Self {
+            images: images.clone(),
+        };
+        let path = path.to_path_buf();
+        thread::spawn(move || loop {
+            // Opening blocks until a writer opens the FIFO, and reading returns once all
+            // the writers have closed it: partial writes are never seen
+            let mut content = String::new();
+            match File::open(&path).and_then(|mut fifo| fifo.read_to_string(&mut content)) {
+                Ok(_) => {}
+                // With `type = "fifo"`, wait for the writer to create it
+                Err(err) if err.kind() == ErrorKind::NotFound => {
+                    thread::sleep(CREATION_POLL);
+                    continue;
+                }
+                Err(err) => {
+                    error!("reading the image list from the FIFO {path:?}: {err:?}");
+                    break;
+                }
+            }
+            let list: Vec<PathBuf> = content
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(PathBuf::from)
+                .collect();
+            // Ignore writers that closed the FIFO without writing anything
+            if list.is_empty() {
+                continue;
+            }
+            info!("read {} images from the FIFO {path:?}", list.len());
+            *images.lock().unwrap() = list;
+            if ev_tx.send(()).is_err() {
+                break;
+            }
+        });
+        pool
+    }
+
+    pub fn images(&self) -> Vec<PathBuf> {
+        self.images.lock().unwrap().clone()
+    }
+
+    pub fn contains(&self, img_path: &Path) -> bool {
+        self.images
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|image| image == img_path)
+    }
+}
@@ -0,0 +1,221 @@
+use std::time::{Duration, Instant};
+
+use color_eyre::{eyre::WrapErr, Result};
+use log::info;
+use smithay_client_toolkit::reexports::calloop::timer::{TimeoutAction, Timer};
+use smithay_client_toolkit::reexports::calloop::LoopHandle;
+use smithay_client_toolkit::reexports::client::globals::GlobalList;
+use smithay_client_toolkit::reexports::client::protocol::wl_seat::{self, WlSeat};
+use smithay_client_toolkit::reexports::client::{Connection, Dispatch, QueueHandle};
+use wayland_protocols::ext::idle_notify::v1::client::{
+    ext_idle_notification_v1::{self, ExtIdleNotificationV1},
+    ext_idle_notifier_v1::{self, ExtIdleNotifierV1},
+};
+
+use crate::wpaperd::Wpaperd;
+
+/// The brightness of the wallpapers while idle, when not configured
+pub const DEFAULT_BRIGHTNESS: f32 = 0.5;
+/// How long it takes to go from the full brightness to the dimmed one, and back
+const FADE_DURATION: Duration = Duration::from_secs(2);
+/// How often the brightness is updated while fading
+const FADE_STEP: Duration = Duration::from_millis(200);
+
+/// What an idle notification is used for
+#[derive(Clone, Copy)]
+pub enum IdleUse {
+    Dim,
+    Rotation,
+}
+
+/// Ask the compositor to notify when the user has been idle for `timeout`, and when they
+/// are active again
+fn idle_notification(
+    globals: &GlobalList,
+    qh: &QueueHandle<Wpaperd>,
+    timeout: Duration,
+    usage: IdleUse,
+) -> Result<(WlSeat, ExtIdleNotificationV1)> {
+    let seat: WlSeat = globals
+        .bind(qh, 1..=1, ())
+        .context("binding the seat for the idle notification")?;
+    let notifier: ExtIdleNotifierV1 = globals
+        .bind(qh, 1..=1, ())
+        .context("the compositor does not support the idle-notify protocol")?;
+    let notification =
+        notifier.get_idle_notification(timeout.as_millis().try_into()?, &seat, qh, usage);
+    // The notification lives on after the notifier has been destroyed
+    notifier.destroy();
+    Ok((seat, notification))
+}
+
+/// Dim all the wallpapers when the compositor reports that the user is idle, and restore them
+/// on activity
+pub struct IdleDim {
+    /// The brightness the wallpapers fade to, between 0 and 1
+    brightness: f32,
+    idle: bool,
+    /// The brightness when `idle` has last changed, to fade from it
+    from: f32,
+    changed_at: Instant,
+    /// Whether the timer for the next fade step has been added to the event loop
+    step_armed: bool,
+    // Keep the objects alive, the notification stops when they are destroyed
+    _seat: WlSeat,
+    _notification: ExtIdleNotificationV1,
+}
+
+impl IdleDim {
+    pub fn new(
+        globals: &GlobalList,
+        qh: &QueueHandle<Wpaperd>,
+        timeout: Duration,
+        brightness: f32,
+    ) -> Result<Self> {
+        let (seat, notification) = idle_notification(globals, qh, timeout, IdleUse::Dim)?;
+        Ok(Self {
+            brightness,
+            idle: false,
+            from: 1.0,
+            changed_at: Instant::now(),
+            step_armed: false,
+            _seat: seat,
+            _notification: notification,
+        })
+    }
+
+    fn set_idle(&mut self, idle: bool) {
+        if self.idle != idle {
+            let now = Instant::now();
+            self.from = self.current_brightness(&now);
+            self.changed_at = now;
+            self.idle = idle;
+            info!(
+                "{}, fading the wallpapers",
+                if idle { "idle" } else { "resumed" }
+            );
+        }
+    }
+
+    fn target(&self) -> f32 {
+        if self.idle {
+            self.brightness
+        } else {
+            1.0
+        }
+    }
+
+    /// Interpolate linearly between the brightness at the last change and the target one
+    fn current_brightness(&self, now: &Instant) -> f32 {
+        let elapsed = now.saturating_duration_since(self.changed_at);
+        let progress = (elapsed.as_secs_f32() / FADE_DURATION.as_secs_f32()).min(1.0);
+        self.from + (self.target() - self.from) * progress
+    }
+
+    fn is_fading(&self, now: &Instant) -> bool {
+        now.saturating_duration_since(self.changed_at) < FADE_DURATION
+    }
+}
+
+/// Tell when a due change of wallpaper has to be deferred, because the user is active
+pub struct IdleRotation {
+    idle: bool,
+    _seat: WlSeat,
+    _notification: ExtIdleNotificationV1,
+}
+
+impl IdleRotation {
+    pub fn new(globals: &GlobalList, qh: &QueueHandle<Wpaperd>, timeout: Duration) -> Result<Self> {
+        let (seat, notification) = idle_notification(globals, qh, timeout, IdleUse::Rotation)?;
+        Ok(Self {
+            idle: false,
+            _seat: seat,
+            _notification: notification,
+        })
+    }
+
+    /// Return true if the change of wallpaper has to wait for the next idle period
+    pub fn defer(&self) -> bool {
+        !self.idle
+    }
+}
+
+/// Set the brightness of all the surfaces and, while fading, wake up the event loop again
+/// for the next step
+pub fn update_brightness(wpaperd: &mut Wpaperd, handle: LoopHandle<Wpaperd>, now: &Instant) {
+    let idle_dim = match &mut wpaperd.idle_dim {
+        Some(idle_dim) => idle_dim,
+        None => return,
+    };
+    let brightness = idle_dim.current_brightness(now);
+    for surface in &mut wpaperd.surfaces {
+        surface.set_brightness(brightness);
+    }
+    if idle_dim.is_fading(now) && !idle_dim.step_armed {
+        idle_dim.step_armed = true;
+        handle
+            .insert_source(Timer::from_duration(FADE_STEP), |_, _, wpaperd| {
+                if let Some(idle_dim) = &mut wpaperd.idle_dim {
+                    idle_dim.step_armed = false;
+                }
+                TimeoutAction::Drop
+            })
+            .expect("Failed to insert event source!");
+    }
+}
+
+impl Dispatch<WlSeat, ()> for Wpaperd {
+    fn event(
+        _state: &mut Self,
+        _seat: &WlSeat,
+        _event: wl_seat::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        // The seat is only needed to create the idle notification
+    }
+}
+
+impl Dispatch<ExtIdleNotifierV1, ()> for Wpaperd {
+    fn event(
+        _state: &mut Self,
+        _notifier: &ExtIdleNotifierV1,
+        _event: ext_idle_notifier_v1::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        // The notifier has no events
+    }
+}
+
+impl Dispatch<ExtIdleNotificationV1, IdleUse> for Wpaperd {
+    fn event(
+        state: &mut Self,
+        _notification: &ExtIdleNotificationV1,
+        event: ext_idle_notification_v1::Event,
+        usage: &IdleUse,
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        let idle = match event {
+            ext_idle_notification_v1::Event::Idled => true,
+            ext_idle_notification_v1::Event::Resumed => false,
+            _ => return,
+        };
+        match usage {
+            IdleUse::Dim => {
+                if let Some(idle_dim) = &mut state.idle_dim {
+                    idle_dim.set_idle(idle);
+                }
+            }
+            // The wallpapers whose change has been deferred are drawn in the next loop
+            IdleUse::Rotation => {
+                if let Some(idle_rotation) = &mut state.idle_rotation {
+                    idle_rotation.idle = idle;
+                }
+            }
+        }
+    }
+}
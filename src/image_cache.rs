@@ -0,0 +1,87 @@
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+};
+
+use image::DynamicImage;
+
+/// Keep the most recently used decoded images, to avoid decoding them again
+pub struct ImageCache {
+    entries: VecDeque<(PathBuf, DynamicImage)>,
+    capacity: usize,
+    hits: u64,
+    misses: u64,
+}
+
+impl ImageCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: VecDeque::with_capacity(capacity),
+            capacity,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub fn get(&mut self, path: &Path) -> Option<DynamicImage> {
+        let index = match self.entries.iter().position(|(p, _)| p == path) {
+            Some(index) => index,
+            None => {
+                self.misses += 1;
+                return None;
+            }
+        };
+        self.hits += 1;
+        // Move the entry in front, it is now the most recently used
+        let entry = self.entries.remove(index)?;
+        let image = entry.1.clone();
+        self.entries.push_front(entry);
+        Some(image)
+    }
+
+    /// Whether `path` is in the cache, without counting it as a lookup
+    pub fn contains(&self, path: &Path) -> bool {
+        self.entries.iter().any(|(p, _)| p == path)
+    }
+
+    pub fn insert(&mut self, path: PathBuf, image: DynamicImage) {
+        if self.entries.len() == self.capacity {
+            self.entries.pop_back();
+        }
+        self.entries.push_front((path, image));
+    }
+
+    /// Change how many images are kept, dropping the least recently used ones if needed
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.entries.truncate(capacity);
+        self.capacity = capacity;
+    }
+
+    /// Drop all the cached images, freeing their memory
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.entries.shrink_to_fit();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// The memory used by the decoded images
+    pub fn bytes(&self) -> usize {
+        self.entries
+            .iter()
+            .map(|(_, image)| image.as_bytes().len())
+            .sum()
+    }
+
+    /// The fraction of lookups that found the image in the cache, if any lookup happened
+    pub fn hit_ratio(&self) -> Option<f64> {
+        let lookups = self.hits + self.misses;
+        if lookups == 0 {
+            None
+        } else {
+            Some(self.hits as f64 / lookups as f64)
+        }
+    }
+}
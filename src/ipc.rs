@@ -0,0 +1,454 @@
+use std::{
+    cell::Cell,
+    fs,
+    io::{ErrorKind, Read, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::Path,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use color_eyre::{
+    eyre::{bail, ensure, eyre, WrapErr},
+    Result,
+};
+use log::error;
+use smithay_client_toolkit::reexports::calloop::{
+    generic::Generic,
+    timer::{TimeoutAction, Timer},
+    Interest, LoopHandle, Mode, PostAction, RegistrationToken,
+};
+
+use crate::output_filter::OutputFilter;
+use crate::surface::Effect;
+use crate::wpaperd::Wpaperd;
+
+/// How long to wait for a client to send its request
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Bind the socket used by wpaperctl to send commands to the daemon
+pub fn listen(socket_path: &Path) -> Result<UnixListener> {
+    // A previous instance could have left its socket behind; it is only stale if nobody
+    // is listening on it anymore
+    if socket_path.exists() {
+        match UnixStream::connect(socket_path) {
+            Ok(_) => bail!("daemon already running, listening on {socket_path:?}"),
+            Err(err) if err.kind() == ErrorKind::ConnectionRefused => {
+                fs::remove_file(socket_path)
+                    .with_context(|| format!("removing the stale socket {socket_path:?}"))?;
+            }
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("connecting to the socket {socket_path:?}"))
+            }
+        }
+    }
+    let listener = UnixListener::bind(socket_path)
+        .with_context(|| format!("binding the socket {socket_path:?}"))?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+/// Accept all the clients waiting on the socket. Each one is then served by its own source
+/// of the event loop, so that a slow client can't block it
+pub fn handle_connections(listener: &UnixListener, handle: &LoopHandle<Wpaperd>) {
+    loop {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                if let Err(err) = add_client(stream, handle) {
+                    error!("{err:?}");
+                }
+            }
+            Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+            Err(err) => {
+                error!("accepting an IPC connection: {err:?}");
+                break;
+            }
+        }
+    }
+}
+
+/// The source of the event loop currently serving a client, None once it has been removed
+/// (its token could then be reused by another source)
+type ClientToken = Rc<Cell<Option<RegistrationToken>>>;
+
+/// Serve `stream` from the event loop: read its request, run it and write the reply. The
+/// client is dropped if it hasn't received its reply after `CLIENT_TIMEOUT`
+fn add_client(stream: UnixStream, handle: &LoopHandle<Wpaperd>) -> Result<()> {
+    stream.set_nonblocking(true)?;
+    let current: ClientToken = Rc::new(Cell::new(None));
+
+    let mut request = Vec::new();
+    let reader_current = current.clone();
+    let reader_handle = handle.clone();
+    let token = handle
+        .insert_source(
+            Generic::new(stream, Interest::READ, Mode::Level),
+            move |_, stream, wpaperd| {
+                let result = read_request(stream, &mut request).and_then(|complete| {
+                    if !complete {
+                        return Ok(false);
+                    }
+                    // Another instance checking if the daemon is running, nothing to reply
+                    if request.is_empty() {
+                        reader_current.set(None);
+                        return Ok(true);
+                    }
+                    let reply = run_request(&request, wpaperd);
+                    add_writer(stream, reply, &reader_handle, &reader_current)
+                });
+                match result {
+                    Ok(false) => return Ok(PostAction::Continue),
+                    Ok(true) => {}
+                    Err(err) => {
+                        error!("{err:?}");
+                        reader_current.set(None);
+                    }
+                }
+                Ok(PostAction::Remove)
+            },
+        )
+        .map_err(|err| eyre!("adding the IPC client to the event loop: {err}"))?;
+    current.set(Some(token));
+
+    let timeout_handle = handle.clone();
+    handle
+        .insert_source(Timer::from_duration(CLIENT_TIMEOUT), move |_, _, _| {
+            if let Some(token) = current.take() {
+                error!("the IPC client has not completed its request in {CLIENT_TIMEOUT:?}");
+                timeout_handle.remove(token);
+            }
+            TimeoutAction::Drop
+        })
+        .map_err(|err| eyre!("adding the IPC client timeout to the event loop: {err}"))?;
+    Ok(())
+}
+
+/// Read as much of the request as the socket allows without blocking. Return true once
+/// the whole request has been received
+///
+/// A request is made of the command arguments separated by NUL bytes; the client
+/// closes its write side when it has finished sending it
+fn read_request(stream: &mut UnixStream, request: &mut Vec<u8>) -> Result<bool> {
+    let mut buf = [0; 4096];
+    loop {
+        match stream.read(&mut buf) {
+            Ok(0) => return Ok(true),
+            Ok(n) => request.extend_from_slice(&buf[..n]),
+            Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(false),
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err).context("reading the IPC request"),
+        }
+    }
+}
+
+/// Write as much of `reply` as the socket allows without blocking, removing what has been
+/// written. Return true once the whole reply has been sent
+fn write_reply(stream: &mut UnixStream, reply: &mut Vec<u8>) -> Result<bool> {
+    while !reply.is_empty() {
+        match stream.write(reply) {
+            Ok(n) => {
+                reply.drain(..n);
+            }
+            Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(false),
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err).context("writing the IPC reply"),
+        }
+    }
+    Ok(true)
+}
+
+/// Send `reply` to the client. What can't be written right away is written by a new
+/// source, waiting for the socket to be writable. Return true, as the reading source is
+/// done in both cases
+fn add_writer(
+    stream: &mut UnixStream,
+    mut reply: Vec<u8>,
+    handle: &LoopHandle<Wpaperd>,
+    current: &ClientToken,
+) -> Result<bool> {
+    if write_reply(stream, &mut reply)? {
+        current.set(None);
+        return Ok(true);
+    }
+    let stream = stream
+        .try_clone()
+        .context("cloning the IPC client socket")?;
+    let writer_current = current.clone();
+    let token = handle
+        .insert_source(
+            Generic::new(stream, Interest::WRITE, Mode::Level),
+            move |_, stream, _| match write_reply(stream, &mut reply) {
+                Ok(false) => Ok(PostAction::Continue),
+                Ok(true) => {
+                    writer_current.set(None);
+                    Ok(PostAction::Remove)
+                }
+                Err(err) => {
+                    error!("{err:?}");
+                    writer_current.set(None);
+                    Ok(PostAction::Remove)
+                }
+            },
+        )
+        .map_err(|err| eyre!("adding the IPC reply to the event loop: {err}"))?;
+    current.set(Some(token));
+    Ok(true)
+}
+
+/// Run the command in `request`, errors are sent back to the client
+fn run_request(request: &[u8], wpaperd: &mut Wpaperd) -> Vec<u8> {
+    let request = String::from_utf8_lossy(request);
+    let args: Vec<&str> = request.split('\0').collect();
+    let reply = match run_command(&args, wpaperd) {
+        Ok(reply) => reply,
+        Err(err) => format!("error: {err}"),
+    };
+    reply.into_bytes()
+}
+
+fn run_command(args: &[&str], wpaperd: &mut Wpaperd) -> Result<String> {
+    match args {
+        ["effect", "dim", amount, outputs @ ..] => {
+            let effect = Effect::Dim(parse_amount(amount)?);
+            set_effect(wpaperd, outputs, Some(effect))
+        }
+        ["effect", "clear", outputs @ ..] => set_effect(wpaperd, outputs, None),
+        ["effect", ..] => {
+            bail!("usage: effect dim <amount> [<output>...] | effect clear [<output>...]")
+        }
+        ["next", outputs @ ..] => {
+            for surface in wpaperd.surfaces_by_name(outputs)? {
+                // Without outputs, only the ones rotating their images are changed
+                if outputs.is_empty() && !surface.wallpaper_info.is_pool() {
+                    continue;
+                }
+                surface.next()?;
+            }
+            Ok(String::new())
+        }
+        ["restart-surface", output] => {
+            wpaperd.restart_surface(output)?;
+            Ok(String::new())
+        }
+        ["restart-surface", ..] => bail!("usage: restart-surface <output>"),
+        ["status", outputs @ ..] => Ok(wpaperd
+            .surfaces_by_name(outputs)?
+            .into_iter()
+            .map(|surface| surface.status())
+            .collect::<Vec<_>>()
+            .join("\n")),
+        ["pixel", output, x, y] => {
+            let x = x.parse().with_context(|| format!("parsing the x {x:?}"))?;
+            let y = y.parse().with_context(|| format!("parsing the y {y:?}"))?;
+            let [r, g, b, a] = wpaperd.surface_by_name(output)?.pixel(x, y)?;
+            Ok(format!("{r} {g} {b} {a}"))
+        }
+        ["pixel", ..] => bail!("usage: pixel <output> <x> <y>"),
+        ["palette", output] => {
+            let surface = wpaperd.surface_by_name(output)?;
+            Ok(surface.palette()?.join("\n"))
+        }
+        ["palette", ..] => bail!("usage: palette <output>"),
+        ["hq-redraw", output] => {
+            wpaperd.surface_by_name(output)?.request_hq_redraw();
+            Ok(String::new())
+        }
+        ["hq-redraw", ..] => bail!("usage: hq-redraw <output>"),
+        ["pipeline", output] => {
+            let surface = wpaperd.surface_by_name(output)?;
+            Ok(surface.pipeline().join("\n"))
+        }
+        ["pipeline", ..] => bail!("usage: pipeline <output>"),
+        ["scaling-info", output] => {
+            let surface = wpaperd.surface_by_name(output)?;
+            Ok(surface.scaling_info()?.join("\n"))
+        }
+        ["scaling-info", ..] => bail!("usage: scaling-info <output>"),
+        ["cache-stats", outputs @ ..] => Ok(wpaperd
+            .surfaces_by_name(outputs)?
+            .into_iter()
+            .map(|surface| {
+                let cache = &surface.archive_cache;
+                let hit_ratio = cache
+                    .hit_ratio()
+                    .map_or("n/a".to_string(), |ratio| format!("{:.0}%", ratio * 100.0));
+                format!(
+                    "{}: {} entries, {:.1} MiB, hit ratio {hit_ratio}",
+                    surface.name(),
+                    cache.len(),
+                    cache.bytes() as f64 / (1024.0 * 1024.0),
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n")),
+        ["cache-clear", outputs @ ..] => {
+            for surface in wpaperd.surfaces_by_name(outputs)? {
+                surface.clear_caches();
+            }
+            Ok(String::new())
+        }
+        ["apply", args @ ..] => apply(wpaperd, args),
+        ["ab-set", output, first, second] => {
+            let (first, second) = (Path::new(first), Path::new(second));
+            for path in [first, second] {
+                ensure!(path.is_absolute(), "the path {path:?} must be absolute");
+                ensure!(path.is_file(), "the path {path:?} is not an image");
+            }
+            wpaperd.surface_by_name(output)?.set_ab(first, second);
+            Ok(String::new())
+        }
+        ["ab-set", ..] => bail!("usage: ab-set <output> <path-a> <path-b>"),
+        ["ab-toggle", output] => {
+            let path = wpaperd.surface_by_name(output)?.toggle_ab()?;
+            Ok(path.display().to_string())
+        }
+        ["ab-toggle", ..] => bail!("usage: ab-toggle <output>"),
+        ["ab-clear", output] => {
+            wpaperd.clear_ab(output)?;
+            Ok(String::new())
+        }
+        ["ab-clear", ..] => bail!("usage: ab-clear <output>"),
+        ["freeze", output] => {
+            ensure!(
+                wpaperd.surface_by_name(output)?.freeze(),
+                "{output} is already frozen"
+            );
+            Ok(String::new())
+        }
+        ["freeze", ..] => bail!("usage: freeze <output>"),
+        ["unfreeze", output] => {
+            ensure!(
+                wpaperd.surface_by_name(output)?.unfreeze(&Instant::now()),
+                "{output} is not frozen"
+            );
+            Ok(String::new())
+        }
+        ["unfreeze", ..] => bail!("usage: unfreeze <output>"),
+        ["set-runtime", key, value] => {
+            wpaperd.set_runtime(key, value)?;
+            Ok(String::new())
+        }
+        ["set-runtime", ..] => bail!("usage: set-runtime <key> <value>"),
+        ["set-output-log-level", output, level, duration @ ..] if duration.len() <= 1 => {
+            let duration = duration
+                .first()
+                .map(|duration| {
+                    humantime_serde::re::humantime::parse_duration(duration)
+                        .with_context(|| format!("parsing the duration {duration:?}"))
+                })
+                .transpose()?;
+            wpaperd.set_output_log_level(output, level, duration)?;
+            Ok(String::new())
+        }
+        ["set-output-log-level", ..] => {
+            bail!("usage: set-output-log-level <output> <level> [<duration>]")
+        }
+        ["reset-output-log-level", outputs @ ..] => {
+            wpaperd.reset_output_log_level(outputs)?;
+            Ok(String::new())
+        }
+        ["save"] => {
+            let saved = wpaperd.save_config()?;
+            Ok(if saved.is_empty() {
+                "the configuration is already up to date".to_string()
+            } else {
+                format!("saved {}", saved.join(", "))
+            })
+        }
+        ["save", ..] => bail!("usage: save"),
+        ["audit"] => Ok(wpaperd.audit().join("\n")),
+        ["layout"] => Ok(wpaperd.layout(false).join("\n")),
+        ["layout", "--ascii"] => Ok(wpaperd.layout(true).join("\n")),
+        ["layout", ..] => bail!("usage: layout [--ascii]"),
+        ["audit", ..] => bail!("usage: audit"),
+        ["stats", outputs @ ..] => Ok(wpaperd.stats.report(outputs).join("\n")),
+        ["stats-reset", outputs @ ..] => {
+            wpaperd.reset_stats(outputs)?;
+            Ok(String::new())
+        }
+        ["history", output] => {
+            let surface = wpaperd.surface_by_name(output)?;
+            Ok(surface.history(&Instant::now()).join("\n"))
+        }
+        ["history", ..] => bail!("usage: history <output>"),
+        ["history-goto", output, index] => {
+            let index = index
+                .parse()
+                .with_context(|| format!("parsing the index {index:?}"))?;
+            let surface = wpaperd.surface_by_name(output)?;
+            surface.goto_history(index, &Instant::now())?;
+            Ok(String::new())
+        }
+        ["history-goto", ..] => bail!("usage: history-goto <output> <index>"),
+        ["position", output] => {
+            let (position, len) = wpaperd.surface_by_name(output)?.position()?;
+            Ok(match position {
+                Some(position) => format!("{position} of {len}"),
+                None => format!("not in the pool of {len} images"),
+            })
+        }
+        ["position", ..] => bail!("usage: position <output>"),
+        ["seek", output, position] => {
+            let position = position
+                .parse()
+                .with_context(|| format!("parsing the position {position:?}"))?;
+            let surface = wpaperd.surface_by_name(output)?;
+            surface.seek(position, &Instant::now())?;
+            Ok(String::new())
+        }
+        ["seek", ..] => bail!("usage: seek <output> <position>"),
+        [command, ..] => bail!("unknown command {command:?}"),
+        [] => bail!("no command given"),
+    }
+}
+
+fn set_effect(wpaperd: &mut Wpaperd, outputs: &[&str], effect: Option<Effect>) -> Result<String> {
+    for surface in wpaperd.surfaces_by_name(outputs)? {
+        surface.set_effect(effect);
+    }
+    Ok(String::new())
+}
+
+/// Draw `path` on the outputs matching any of the `--match <filter>` arguments, or on all of
+/// them when no filter is given. Reply with the outputs affected
+fn apply(wpaperd: &mut Wpaperd, args: &[&str]) -> Result<String> {
+    const USAGE: &str = "usage: apply [--match <filter>]... <path>";
+    let (path, filter_args) = args.split_last().ok_or_else(|| eyre!(USAGE))?;
+    let mut filters = Vec::new();
+    let mut filter_args = filter_args.iter();
+    while let Some(arg) = filter_args.next() {
+        ensure!(*arg == "--match", USAGE);
+        let filter = filter_args.next().ok_or_else(|| eyre!(USAGE))?;
+        filters.push(OutputFilter::parse(filter)?);
+    }
+
+    let path = Path::new(path);
+    ensure!(path.is_absolute(), "the path {path:?} must be absolute");
+    ensure!(path.exists(), "the path {path:?} does not exist");
+
+    let mut affected = Vec::new();
+    for surface in wpaperd.surfaces.iter_mut().filter(|surface| {
+        filters.is_empty() || filters.iter().any(|filter| filter.matches(&surface.info))
+    }) {
+        surface.apply(path);
+        affected.push(surface.name().to_string());
+    }
+    ensure!(!affected.is_empty(), "no output matches the filters");
+    Ok(affected.join("\n"))
+}
+
+/// Parse an amount either as a fraction (`0.4`) or as a percentage (`40%`)
+fn parse_amount(amount: &str) -> Result<f32> {
+    let value = if let Some(percentage) = amount.strip_suffix('%') {
+        percentage.parse::<f32>().map(|p| p / 100.0)
+    } else {
+        amount.parse::<f32>()
+    }
+    .with_context(|| format!("parsing the amount {amount:?}"))?;
+    ensure!(
+        (0.0..=1.0).contains(&value),
+        "the amount must be between 0 and 1 (or 0% and 100%)"
+    );
+    Ok(value)
+}
@@ -1,42 +1,103 @@
+mod animation;
+mod archive;
+#[cfg(feature = "battery")]
+mod battery;
+#[cfg(feature = "color-management")]
+mod color;
 mod config;
+mod fifo;
+#[cfg(feature = "idle")]
+mod idle;
+mod image_cache;
+mod ipc;
+#[cfg(feature = "media")]
+mod media;
+mod output_filter;
+mod output_log;
+mod palette;
+mod scan;
+mod selftest;
+#[cfg(feature = "shader")]
+mod shader;
+mod sqlite;
+mod stats;
 mod surface;
+mod time_ranges;
 mod wallpaper_config;
 mod wallpaper_info;
+mod watch;
+mod workers;
+#[cfg(feature = "workspace")]
+mod workspace;
 mod wpaperd;
 
 use std::{
-    collections::HashSet,
-    fs,
-    path::Path,
+    fs::{self, File},
+    io::{BufRead, BufReader},
+    os::unix::io::FromRawFd,
+    path::{Path, PathBuf},
     process::exit,
     sync::{Arc, Mutex},
-    time::Instant,
+    time::{Duration, Instant},
 };
 
 use clap::Parser;
-use color_eyre::{eyre::WrapErr, Result};
-use flexi_logger::{Duplicate, FileSpec, Logger};
+use color_eyre::{
+    eyre::{ensure, WrapErr},
+    Result,
+};
+use flexi_logger::{Duplicate, FileSpec, Logger, LoggerHandle};
 use hotwatch::{Event, Hotwatch};
-use log::error;
-use nix::unistd::fork;
+use log::{error, info};
+use nix::{
+    fcntl::{open, OFlag},
+    sys::stat,
+    unistd::{close, dup2, fork, pipe, setsid},
+};
 use smithay_client_toolkit::reexports::{
-    calloop::{self, channel::Sender},
+    calloop::{self, channel::Sender, generic::Generic, Interest, Mode, PostAction},
     client::{globals::registry_queue_init, Connection, WaylandSource},
 };
 use xdg::BaseDirectories;
 
-use crate::config::Config;
+#[cfg(feature = "battery")]
+use crate::battery::BatteryMonitor;
+use crate::config::{Config, DaemonOutput};
+#[cfg(feature = "idle")]
+use crate::idle::{IdleDim, IdleRotation};
+#[cfg(feature = "media")]
+use crate::media::MediaMonitor;
+use crate::output_log::{OutputLevels, OutputScope};
+use crate::palette::PaletteCache;
+use crate::stats::UsageStats;
+use crate::surface::ARCHIVE_CACHE_SIZE;
 use crate::wallpaper_config::WallpaperConfig;
-use crate::wpaperd::Wpaperd;
+use crate::workers::WorkerPool;
+#[cfg(feature = "workspace")]
+use crate::workspace::Workspaces;
+use crate::wpaperd::{Options, Wpaperd};
 
-fn run(config: Config, xdg_dirs: BaseDirectories) -> Result<()> {
-    let output_config_file = if let Some(output_config_file) = &config.output_config {
+/// How many of the last wallpapers of each output are remembered, when not configured
+const DEFAULT_HISTORY_LENGTH: usize = 32;
+
+fn output_config_path(config: &Config, xdg_dirs: &BaseDirectories) -> PathBuf {
+    if let Some(output_config_file) = &config.output_config {
         output_config_file.to_path_buf()
     } else {
         xdg_dirs.place_config_file("output.conf").unwrap()
-    };
-    let mut wallpaper_config = WallpaperConfig::new_from_path(&output_config_file)?;
-    wallpaper_config.reloaded = false;
+    }
+}
+
+fn run(
+    config: Config,
+    xdg_dirs: BaseDirectories,
+    logger: LoggerHandle,
+    output_levels: OutputLevels,
+) -> Result<()> {
+    let output_config_file = output_config_path(&config, &xdg_dirs);
+    // The surfaces are created with the configuration of their output, `reloaded` is only set
+    // when the file is read again
+    let wallpaper_config = WallpaperConfig::new_from_path(&output_config_file)?;
     let wallpaper_config = Arc::new(Mutex::new(wallpaper_config));
 
     let conn = Connection::connect_to_env().unwrap();
@@ -56,53 +117,191 @@ fn run(config: Config, xdg_dirs: BaseDirectories) -> Result<()> {
         .insert_source(ev_rx, |_, _, _| {})
         .unwrap();
 
-    let _hotwatch = setup_hotwatch(&output_config_file, wallpaper_config.clone(), ev_tx);
+    let _hotwatch = setup_hotwatch(&output_config_file, wallpaper_config.clone(), ev_tx.clone());
 
+    let socket_path = xdg_dirs
+        .place_runtime_file("wpaperd.sock")
+        .context("placing the IPC socket")?;
+    let listener = ipc::listen(&socket_path)?;
+    let ipc_handle = event_loop.handle();
+    event_loop
+        .handle()
+        .insert_source(
+            Generic::new(listener, Interest::READ, Mode::Level),
+            move |_, listener, _| {
+                ipc::handle_connections(listener, &ipc_handle);
+                Ok(PostAction::Continue)
+            },
+        )
+        .unwrap();
+
+    ensure!(
+        config.max_commit_fps != Some(0),
+        "max-commit-fps must be greater than 0"
+    );
+    ensure!(
+        config.decode_threads != Some(0),
+        "decode-threads must be greater than 0"
+    );
+    // Resized when the outputs are known, unless `decode-threads` is set
+    let decode_pool = WorkerPool::new("decode", config.decode_threads.unwrap_or(1), ev_tx.clone());
+    #[cfg(feature = "media")]
+    let media = MediaMonitor::new(ev_tx.clone());
     let mut wpaperd = Wpaperd::new(
         &qh,
         &globals,
         &conn,
         wallpaper_config.clone(),
-        config.use_scaled_window,
+        ev_tx,
+        Options {
+            use_scaled_window: config.use_scaled_window,
+            random_seed: config.random_seed.unwrap_or_else(rand::random),
+            history_length: config.history_length.unwrap_or(DEFAULT_HISTORY_LENGTH),
+            max_commit_fps: config.max_commit_fps,
+            max_buffer_size: config.max_buffer_size,
+            archive_cache_size: ARCHIVE_CACHE_SIZE,
+            disconnect_grace: Duration::from_secs(config.disconnect_grace.unwrap_or(0)),
+            palettes: PaletteCache::new(decode_pool.clone()),
+            decode_pool,
+            decode_threads: config.decode_threads,
+        },
     )?;
+    wpaperd.logger = Some(logger);
+    wpaperd.output_levels = output_levels;
+    #[cfg(feature = "media")]
+    {
+        wpaperd.media = Some(media);
+    }
+    let stats_path = xdg_dirs
+        .place_state_file("stats.toml")
+        .context("placing the statistics file")?;
+    wpaperd.stats = UsageStats::load(&stats_path)?;
+
+    #[cfg(feature = "battery")]
+    if let Some(threshold) = config.pause_below_battery {
+        let mut battery_monitor = BatteryMonitor::new(threshold);
+        wpaperd.battery_paused = battery_monitor.check();
+        event_loop
+            .handle()
+            .insert_source(
+                calloop::timer::Timer::from_duration(battery::POLL_INTERVAL),
+                move |_, _, wpaperd| {
+                    wpaperd.battery_paused = battery_monitor.check();
+                    calloop::timer::TimeoutAction::ToDuration(battery::POLL_INTERVAL)
+                },
+            )
+            .unwrap();
+    }
+    #[cfg(not(feature = "battery"))]
+    if config.pause_below_battery.is_some() {
+        log::warn!(
+            "pause-below-battery is ignored, wpaperd has been built without the battery feature"
+        );
+    }
+
+    #[cfg(feature = "idle")]
+    if let Some(timeout) = config.idle_dim_timeout {
+        let brightness = config
+            .idle_dim_brightness
+            .unwrap_or(idle::DEFAULT_BRIGHTNESS);
+        ensure!(
+            (0.0..=1.0).contains(&brightness),
+            "idle-dim-brightness must be between 0 and 1"
+        );
+        match IdleDim::new(&globals, &qh, Duration::from_secs(timeout), brightness) {
+            Ok(idle_dim) => wpaperd.idle_dim = Some(idle_dim),
+            // Keep drawing the wallpapers, without dimming them
+            Err(err) => log::warn!("{err:?}"),
+        }
+    }
+    #[cfg(not(feature = "idle"))]
+    if config.idle_dim_timeout.is_some() {
+        log::warn!("idle-dim-timeout is ignored, wpaperd has been built without the idle feature");
+    }
+
+    #[cfg(feature = "idle")]
+    if let Some(timeout) = config.rotate_only_when_idle {
+        match IdleRotation::new(&globals, &qh, Duration::from_secs(timeout)) {
+            Ok(idle_rotation) => wpaperd.idle_rotation = Some(idle_rotation),
+            // Keep changing the wallpapers, without waiting for the user to be idle
+            Err(err) => log::warn!("{err:?}"),
+        }
+    }
+    #[cfg(not(feature = "idle"))]
+    if config.rotate_only_when_idle.is_some() {
+        log::warn!(
+            "rotate-only-when-idle is ignored, wpaperd has been built without the idle feature"
+        );
+    }
+
+    #[cfg(feature = "workspace")]
+    match Workspaces::new(&globals, &qh) {
+        Ok(workspaces) => wpaperd.workspaces = Some(workspaces),
+        // Every workspace shows the configured wallpapers
+        Err(err) => info!("{err:?}"),
+    }
 
     // Loop until the wayland server has sent us the configure event and
-    // scale for all the displays
+    // scale for all the displays, then until all of them have drawn their
+    // first wallpaper, one after the other
+    let mut scheduled = false;
     loop {
-        let now = Instant::now();
-        let mut configured = HashSet::new();
-        let all_configured = if !wpaperd.surfaces.is_empty() {
-            wpaperd
-                .surfaces
-                .iter_mut()
-                .map(|surface| {
-                    let res = surface
-                        .draw(&now)
-                        .with_context(|| format!("drawing surface for {}", surface.name()));
-                    match res {
-                        Ok(t) => t,
-                        // Do not panic here, there could be other display working
-                        Err(e) => error!("{e:?}"),
-                    }
+        #[cfg(feature = "media")]
+        wpaperd.update_media();
+        #[cfg(feature = "workspace")]
+        wpaperd.update_workspaces();
+        wpaperd.update_time_ranges(event_loop.handle());
+        wpaperd.update_fifo_pools();
+        wpaperd.update_dir_scans();
+        wpaperd.update_distribution();
+        wpaperd.update_transition_stagger();
+        if !scheduled
+            && !wpaperd.surfaces.is_empty()
+            && wpaperd.surfaces.iter().all(|surface| surface.configured)
+        {
+            wpaperd.schedule_first_draws(
+                event_loop.handle(),
+                Duration::from_millis(config.startup_stagger.unwrap_or(0)),
+            );
+            scheduled = true;
+        }
 
-                    // We need to add the first timer here, so that in the next
-                    // loop we will always receive timeout events and create
-                    // them when that happens
-                    if surface.configured && !configured.contains(surface.name()) {
-                        configured.insert(surface.name());
-                        surface.set_next_duration(event_loop.handle());
-                    }
+        if scheduled {
+            let now = Instant::now();
+            wpaperd.surfaces.iter_mut().for_each(|surface| {
+                let _scope = OutputScope::enter(surface.name());
+                let res = surface
+                    .draw(&now)
+                    .with_context(|| format!("drawing surface for {}", surface.name()));
+                match res {
+                    Ok(t) => t,
+                    // Do not panic here, there could be other display working
+                    Err(e) => error!("{e:?}"),
+                }
 
-                    surface.configured
-                })
-                .all(|b| b)
-        } else {
-            false
-        };
+                // We need to add the first timer here, so that in the next
+                // loop we will always receive timeout events and create
+                // them when that happens
+                if surface.configured && !surface.timer_armed {
+                    surface.set_next_duration(event_loop.handle());
+                }
+                surface.schedule_pending_commit(event_loop.handle(), &now);
+                surface.schedule_scan_timeout(event_loop.handle());
+                surface.schedule_frame_fallback(event_loop.handle());
+                surface.schedule_animation(event_loop.handle(), &now);
+                surface.schedule_transition_start(event_loop.handle(), &now);
+                #[cfg(feature = "shader")]
+                surface.schedule_shader(event_loop.handle());
+            });
 
-        // Break to the actual event_loop
-        if all_configured {
-            break;
+            // Break to the actual event_loop
+            if wpaperd
+                .surfaces
+                .iter()
+                .all(|surface| surface.draw_not_before.map_or(true, |t| t <= now))
+            {
+                break;
+            }
         }
 
         event_loop
@@ -115,7 +314,9 @@ fn run(config: Config, xdg_dirs: BaseDirectories) -> Result<()> {
         if output_config.reloaded {
             wpaperd.surfaces.iter_mut().for_each(|surface| {
                 let wallpaper_info = output_config.get_output_by_name(surface.name());
-                if surface.update_wallpaper_info(wallpaper_info) {
+                // The configuration replaces the images set with `apply` and `ab-set`
+                surface.clear_ab();
+                if surface.reload(wallpaper_info) {
                     // The new config could have a new duration that is less
                     // then the previous one. Add it to the event_loop
                     surface.set_next_duration(event_loop.handle());
@@ -124,12 +325,41 @@ fn run(config: Config, xdg_dirs: BaseDirectories) -> Result<()> {
             output_config.reloaded = false;
         }
         drop(output_config);
+        #[cfg(feature = "media")]
+        wpaperd.update_media();
+        #[cfg(feature = "workspace")]
+        wpaperd.update_workspaces();
+        wpaperd.update_time_ranges(event_loop.handle());
+        wpaperd.update_fifo_pools();
+        wpaperd.update_dir_scans();
+        wpaperd.update_distribution();
+        wpaperd.update_transition_stagger();
+        wpaperd.update_current_watches();
+        wpaperd.update_output_levels(event_loop.handle());
+        wpaperd.schedule_forget_disconnected(event_loop.handle());
+
+        #[cfg(feature = "battery")]
+        let paused = wpaperd.battery_paused;
+        #[cfg(not(feature = "battery"))]
+        let paused = false;
+
+        #[cfg(feature = "idle")]
+        let defer = wpaperd
+            .idle_rotation
+            .as_ref()
+            .map_or(false, IdleRotation::defer);
+        #[cfg(not(feature = "idle"))]
+        let defer = false;
 
         let now = Instant::now();
+        #[cfg(feature = "idle")]
+        idle::update_brightness(&mut wpaperd, event_loop.handle(), &now);
+
         // Iterate over all surfaces and check if we should change the
         // wallpaper or draw it again
         wpaperd.surfaces.iter_mut().for_each(|surface| {
-            surface.update_duration(event_loop.handle(), &now);
+            let _scope = OutputScope::enter(surface.name());
+            surface.update_duration(event_loop.handle(), &now, paused, defer);
             let res = surface
                 .draw(&now)
                 .with_context(|| format!("drawing surface for {}", surface.name()));
@@ -138,7 +368,21 @@ fn run(config: Config, xdg_dirs: BaseDirectories) -> Result<()> {
                 // Do not panic here, there could be other display working
                 Err(e) => error!("{e:?}"),
             }
+
+            // Surfaces created after startup (new outputs or restarted ones)
+            // need their first timer
+            if surface.configured && !surface.timer_armed {
+                surface.set_next_duration(event_loop.handle());
+            }
+            surface.schedule_pending_commit(event_loop.handle(), &now);
+            surface.schedule_scan_timeout(event_loop.handle());
+            surface.schedule_frame_fallback(event_loop.handle());
+            surface.schedule_animation(event_loop.handle(), &now);
+            surface.schedule_transition_start(event_loop.handle(), &now);
+            #[cfg(feature = "shader")]
+            surface.schedule_shader(event_loop.handle());
         });
+        wpaperd.update_stats();
 
         event_loop
             .dispatch(None, &mut wpaperd)
@@ -165,7 +409,13 @@ fn main() -> Result<()> {
     };
     config.merge(opts);
 
-    let mut logger = Logger::try_with_env_or_str("info")?;
+    // Run it before forking, so that the report is printed on the terminal
+    if config.selftest && !selftest::run(&output_config_path(&config, &xdg_dirs)) {
+        exit(1);
+    }
+
+    let output_levels = OutputLevels::default();
+    let mut logger = Logger::try_with_env_or_str("info")?.filter(Box::new(output_levels.clone()));
 
     if config.no_daemon {
         logger = logger.duplicate_to_stderr(Duplicate::Warn);
@@ -175,11 +425,18 @@ fn main() -> Result<()> {
             nix::unistd::ForkResult::Parent { child: _ } => exit(0),
             nix::unistd::ForkResult::Child => {}
         }
+        // Leave the session of the terminal, so that closing it doesn't stop the daemon
+        setsid().context("detaching the daemon from the terminal")?;
     }
 
-    logger.start()?;
+    let logger = logger.start()?;
 
-    if let Err(err) = run(config, xdg_dirs) {
+    if !config.no_daemon {
+        redirect_stdio(config.daemon_output.unwrap_or(DaemonOutput::Log))
+            .context("redirecting the standard output and error of the daemon")?;
+    }
+
+    if let Err(err) = run(config, xdg_dirs, logger, output_levels) {
         error!("{err:?}");
         Err(err)
     } else {
@@ -187,6 +444,33 @@ fn main() -> Result<()> {
     }
 }
 
+/// Redirect the standard input to /dev/null and the standard output and error according to
+/// `output`, so that nothing is written to the terminal the daemon has left. With
+/// `DaemonOutput::Log` they are read from a pipe by a thread, that logs every line
+fn redirect_stdio(output: DaemonOutput) -> Result<()> {
+    let null = open("/dev/null", OFlag::O_RDWR, stat::Mode::empty())?;
+    dup2(null, 0)?;
+    let fd = match output {
+        DaemonOutput::Null => null,
+        DaemonOutput::Log => {
+            close(null)?;
+            let (read, write) = pipe()?;
+            // The pipe is only read here, the write side is kept by the standard output
+            let reader = BufReader::new(unsafe { File::from_raw_fd(read) });
+            std::thread::spawn(move || {
+                for line in reader.lines().map_while(|line| line.ok()) {
+                    info!("output: {line}");
+                }
+            });
+            write
+        }
+    };
+    dup2(fd, 1)?;
+    dup2(fd, 2)?;
+    close(fd)?;
+    Ok(())
+}
+
 fn setup_hotwatch(
     output_config_file: &Path,
     output_config: Arc<Mutex<WallpaperConfig>>,
@@ -203,12 +487,11 @@ fn setup_hotwatch(
                         format!("reading configuration from file {:?}", output_config.path)
                     });
                 match new_config {
-                    Ok(new_config) if new_config != *output_config => {
-                        *output_config = new_config;
-                        ev_tx.send(()).unwrap();
-                    }
-                    Ok(_) => {
-                        // Do nothing, the new config is the same as the loaded one
+                    Ok(new_config) => {
+                        // Do nothing if the new config is the same as the loaded one
+                        if output_config.reload(new_config) {
+                            ev_tx.send(()).unwrap();
+                        }
                     }
                     Err(err) => {
                         error!("{:?}", err);
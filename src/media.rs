@@ -0,0 +1,117 @@
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use log::{error, info};
+use mpris::{MetadataValue, PlaybackStatus, PlayerFinder};
+use smithay_client_toolkit::reexports::calloop::channel::Sender;
+
+/// How often the active player is asked what it is playing
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// What the active MPRIS player is playing
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Track {
+    /// The album art, when it is a local file
+    pub art: Option<PathBuf>,
+    /// The genres of the track, in lowercase
+    pub genres: Vec<String>,
+}
+
+/// Follow the track played by the active media player, over D-Bus. The player is asked by a
+/// thread, so that a player slow to answer doesn't block the event loop
+pub struct MediaMonitor {
+    track: Arc<Mutex<Option<Track>>>,
+}
+
+impl MediaMonitor {
+    /// `ev_tx` wakes up the event loop every time the track changes or the playback stops
+    pub fn new(ev_tx: Sender<()>) -> Self {
+        let track = Arc::new(Mutex::new(None));
+        let monitor = Self {
+            track: track.clone(),
+        };
+        thread::spawn(move || {
+            let finder = match PlayerFinder::new() {
+                Ok(finder) => finder,
+                Err(err) => {
+                    error!("connecting to D-Bus to follow the media players: {err}");
+                    return;
+                }
+            };
+            loop {
+                let playing = playing_track(&finder);
+                let mut current = track.lock().unwrap();
+                if *current != playing {
+                    match &playing {
+                        Some(playing) => info!("playing a new track: {playing:?}"),
+                        None => info!("the playback has stopped"),
+                    }
+                    *current = playing;
+                    drop(current);
+                    let _ = ev_tx.send(());
+                }
+                thread::sleep(POLL_INTERVAL);
+            }
+        });
+        monitor
+    }
+
+    /// The track being played, None when no player is playing
+    pub fn track(&self) -> Option<Track> {
+        self.track.lock().unwrap().clone()
+    }
+}
+
+/// Ask the active player what it is playing, None if it is paused or stopped
+fn playing_track(finder: &PlayerFinder) -> Option<Track> {
+    let player = finder.find_active().ok()?;
+    if player.get_playback_status().ok()? != PlaybackStatus::Playing {
+        return None;
+    }
+    let metadata = player.get_metadata().ok()?;
+    let art = metadata
+        .art_url()
+        .and_then(|url| url.strip_prefix("file://"))
+        .map(|path| PathBuf::from(percent_decode(path)))
+        .filter(|path| path.is_file());
+    let genres = match metadata.get("xesam:genre") {
+        Some(MetadataValue::Array(genres)) => genres
+            .iter()
+            .filter_map(|genre| match genre {
+                MetadataValue::String(genre) => Some(genre.to_lowercase()),
+                _ => None,
+            })
+            .collect(),
+        Some(MetadataValue::String(genre)) => vec![genre.to_lowercase()],
+        _ => Vec::new(),
+    };
+    Some(Track { art, genres })
+}
+
+/// Decode the `%xx` escapes of a file URL
+fn percent_decode(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
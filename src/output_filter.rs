@@ -0,0 +1,75 @@
+use color_eyre::{eyre::bail, Result};
+use smithay_client_toolkit::output::OutputInfo;
+
+#[derive(Clone, Copy)]
+enum Property {
+    Name,
+    Make,
+    Model,
+    Description,
+}
+
+/// Select outputs by their properties. A filter is made of comma separated `property=pattern`
+/// terms, all of which must match; `property` can be `name`, `make`, `model` or
+/// `description`, and a term without it matches the name. Patterns can contain the
+/// wildcards `*` and `?`
+pub struct OutputFilter {
+    terms: Vec<(Property, String)>,
+}
+
+impl OutputFilter {
+    pub fn parse(filter: &str) -> Result<Self> {
+        let mut terms = Vec::new();
+        for term in filter.split(',') {
+            let (property, pattern) = match term.split_once('=') {
+                Some(("name", pattern)) => (Property::Name, pattern),
+                Some(("make", pattern)) => (Property::Make, pattern),
+                Some(("model", pattern)) => (Property::Model, pattern),
+                Some(("description", pattern)) => (Property::Description, pattern),
+                Some((property, _)) => bail!("unknown output property {property:?}"),
+                None => (Property::Name, term),
+            };
+            terms.push((property, pattern.to_string()));
+        }
+        Ok(Self { terms })
+    }
+
+    pub fn matches(&self, info: &OutputInfo) -> bool {
+        self.terms.iter().all(|(property, pattern)| {
+            let value = match property {
+                Property::Name => info.name.as_deref(),
+                Property::Make => Some(info.make.as_str()),
+                Property::Model => Some(info.model.as_str()),
+                Property::Description => info.description.as_deref(),
+            };
+            value.map_or(false, |value| glob_match(pattern, value))
+        })
+    }
+}
+
+/// Match `text` against `pattern`, where `*` matches any sequence of characters and `?`
+/// matches any single character
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` seen in the pattern and of the text it has been matched at
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            // Let the last `*` match one more character
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
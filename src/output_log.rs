@@ -0,0 +1,139 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    io,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use flexi_logger::{
+    filter::{LogLineFilter, LogLineWriter},
+    DeferredNow,
+};
+use log::{LevelFilter, Record};
+
+thread_local! {
+    /// The output the event loop is working on, set by `OutputScope`
+    static CURRENT_OUTPUT: RefCell<Option<String>> = RefCell::new(None);
+}
+
+/// Tag the records logged by the current thread with `output`, until it is dropped
+pub struct OutputScope;
+
+impl OutputScope {
+    pub fn enter(output: &str) -> Self {
+        CURRENT_OUTPUT.with(|current| *current.borrow_mut() = Some(output.to_string()));
+        Self
+    }
+}
+
+impl Drop for OutputScope {
+    fn drop(&mut self) {
+        CURRENT_OUTPUT.with(|current| *current.borrow_mut() = None);
+    }
+}
+
+/// The log levels raised for single outputs with `set-output-log-level`. While a level is
+/// raised, the log specification lets through everything up to it, and the records of the
+/// other outputs are held to the maximum level of the specification before
+#[derive(Clone, Default)]
+pub struct OutputLevels {
+    state: Arc<Mutex<LevelsState>>,
+}
+
+struct LevelsState {
+    /// The level of each output, with when it goes back to the global one
+    levels: HashMap<String, (LevelFilter, Option<Instant>)>,
+    /// The maximum level of the log specification before the first level was raised
+    global: LevelFilter,
+}
+
+impl Default for LevelsState {
+    fn default() -> Self {
+        Self {
+            levels: HashMap::new(),
+            global: LevelFilter::Info,
+        }
+    }
+}
+
+impl OutputLevels {
+    /// Raise the level of `output` to `level`, for `duration` if any
+    pub fn set(&self, output: &str, level: LevelFilter, duration: Option<Duration>) {
+        let mut state = self.state.lock().unwrap();
+        if state.levels.is_empty() {
+            state.global = log::max_level();
+        }
+        let expires = duration.map(|duration| Instant::now() + duration);
+        state.levels.insert(output.to_string(), (level, expires));
+    }
+
+    /// Put the outputs in `outputs` back to the global level, or all of them if it's empty
+    pub fn reset(&self, outputs: &[&str]) {
+        self.state
+            .lock()
+            .unwrap()
+            .levels
+            .retain(|output, _| !outputs.is_empty() && !outputs.contains(&output.as_str()));
+    }
+
+    /// Put the outputs whose duration has passed back to the global level. Return true if
+    /// there were any
+    pub fn expire(&self) -> bool {
+        let now = Instant::now();
+        let levels = &mut self.state.lock().unwrap().levels;
+        let count = levels.len();
+        levels.retain(|_, (_, expires)| expires.map_or(true, |expires| expires > now));
+        levels.len() != count
+    }
+
+    /// The level the log specification has to let through, None when no level is raised
+    pub fn max_level(&self) -> Option<LevelFilter> {
+        let state = self.state.lock().unwrap();
+        (!state.levels.is_empty()).then(|| {
+            state
+                .levels
+                .values()
+                .map(|(level, _)| *level)
+                .fold(state.global, Ord::max)
+        })
+    }
+
+    /// How long until the first raised level goes back to the global one
+    pub fn until_next_expiry(&self) -> Option<Duration> {
+        self.state
+            .lock()
+            .unwrap()
+            .levels
+            .values()
+            .filter_map(|(_, expires)| *expires)
+            .min()
+            .map(|expires| expires.saturating_duration_since(Instant::now()))
+    }
+}
+
+impl LogLineFilter for OutputLevels {
+    fn write(
+        &self,
+        now: &mut DeferredNow,
+        record: &Record,
+        log_line_writer: &dyn LogLineWriter,
+    ) -> io::Result<()> {
+        let allowed = {
+            let state = self.state.lock().unwrap();
+            state.levels.is_empty()
+                || record.level() <= state.global
+                || CURRENT_OUTPUT.with(|current| {
+                    current
+                        .borrow()
+                        .as_ref()
+                        .and_then(|output| state.levels.get(output))
+                        .map_or(false, |(level, _)| record.level() <= *level)
+                })
+        };
+        if allowed {
+            log_line_writer.write(now, record)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,204 @@
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use color_eyre::Result;
+use image::DynamicImage;
+use log::warn;
+
+use crate::workers::WorkerPool;
+
+/// The size of the thumbnail the colors are extracted from
+const THUMBNAIL_SIZE: u32 = 64;
+
+/// The palettes of the images compared by `cohesion`, shared by all the outputs. They are
+/// extracted in the decode workers, so that comparing the candidates doesn't block the event
+/// loop
+#[derive(Clone)]
+pub struct PaletteCache {
+    /// `None` while the palette is being extracted, or when the image could not be decoded
+    palettes: Arc<Mutex<HashMap<PathBuf, Option<Vec<[u8; 3]>>>>>,
+    workers: WorkerPool,
+}
+
+impl PaletteCache {
+    pub fn new(workers: WorkerPool) -> Self {
+        Self {
+            palettes: Arc::new(Mutex::new(HashMap::new())),
+            workers,
+        }
+    }
+
+    /// Return the palette of `img_path` if it is known; otherwise start extracting it with
+    /// `extract`, for the next changes
+    pub fn get_or_extract<F>(&self, img_path: &Path, extract: F) -> Option<Vec<[u8; 3]>>
+    where
+        F: FnOnce() -> Result<Vec<[u8; 3]>> + Send + 'static,
+    {
+        let mut palettes = self.palettes.lock().unwrap();
+        if let Some(palette) = palettes.get(img_path) {
+            return palette.clone();
+        }
+        palettes.insert(img_path.to_path_buf(), None);
+        drop(palettes);
+        let palettes = self.palettes.clone();
+        let img_path = img_path.to_path_buf();
+        let _ = self.workers.run(move || match extract() {
+            Ok(palette) => {
+                palettes.lock().unwrap().insert(img_path, Some(palette));
+            }
+            Err(err) => warn!("extracting the palette of {img_path:?}: {err:?}"),
+        });
+        None
+    }
+
+    /// Keep the palette of an image that has been drawn
+    pub fn insert(&self, img_path: PathBuf, palette: Vec<[u8; 3]>) {
+        self.palettes.lock().unwrap().insert(img_path, Some(palette));
+    }
+}
+
+/// Extract up to `count` dominant colors from the image with the median cut algorithm, the
+/// most common first
+pub fn median_cut(image: &DynamicImage, count: usize) -> Vec<[u8; 3]> {
+    let pixels = image
+        .thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE)
+        .to_rgb8()
+        .pixels()
+        .map(|p| p.0)
+        .collect();
+    let mut boxes: Vec<Vec<[u8; 3]>> = vec![pixels];
+    while boxes.len() < count {
+        // Split the box with the widest range in one of the channels
+        let (index, channel, range) = boxes
+            .iter()
+            .enumerate()
+            .map(|(index, colors)| {
+                let (channel, range) = widest_channel(colors);
+                (index, channel, range)
+            })
+            .max_by_key(|(_, _, range)| *range)
+            .unwrap();
+        if range == 0 {
+            break;
+        }
+        let mut colors = boxes.swap_remove(index);
+        colors.sort_unstable_by_key(|color| color[channel]);
+        let upper = colors.split_off(colors.len() / 2);
+        boxes.push(colors);
+        boxes.push(upper);
+    }
+
+    boxes.sort_by_key(|colors| Reverse(colors.len()));
+    boxes
+        .iter()
+        .filter(|colors| !colors.is_empty())
+        .map(|colors| average(colors))
+        .collect()
+}
+
+/// Return the channel with the widest range of values in `colors`, and the range
+fn widest_channel(colors: &[[u8; 3]]) -> (usize, u8) {
+    (0..3)
+        .map(|channel| {
+            let values = colors.iter().map(|color| color[channel]);
+            let range = values.clone().max().unwrap_or(0) - values.min().unwrap_or(0);
+            (channel, range)
+        })
+        .max_by_key(|(_, range)| *range)
+        .unwrap()
+}
+
+fn average(colors: &[[u8; 3]]) -> [u8; 3] {
+    let mut sum = [0_u64; 3];
+    for color in colors {
+        for (sum, value) in sum.iter_mut().zip(color) {
+            *sum += u64::from(*value);
+        }
+    }
+    sum.map(|value| (value / colors.len() as u64) as u8)
+}
+
+/// Convert an sRGB color to CIELAB, with the D65 white point
+fn to_lab(color: [u8; 3]) -> [f32; 3] {
+    let [r, g, b] = color.map(|c| {
+        let c = f32::from(c) / 255.0;
+        if c <= 0.04045 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    });
+    let x = (0.4124 * r + 0.3576 * g + 0.1805 * b) / 0.95047;
+    let y = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+    let z = (0.0193 * r + 0.1192 * g + 0.9505 * b) / 1.08883;
+    let f = |t: f32| {
+        if t > 0.008856 {
+            t.cbrt()
+        } else {
+            7.787 * t + 16.0 / 116.0
+        }
+    };
+    let (fx, fy, fz) = (f(x), f(y), f(z));
+    [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+}
+
+/// The distance between two palettes: the average distance in CIELAB of each color to the
+/// closest one of the other palette, in both directions
+pub fn palette_distance(a: &[[u8; 3]], b: &[[u8; 3]]) -> f32 {
+    let a: Vec<[f32; 3]> = a.iter().copied().map(to_lab).collect();
+    let b: Vec<[f32; 3]> = b.iter().copied().map(to_lab).collect();
+    let closest = |from: &[[f32; 3]], to: &[[f32; 3]]| {
+        from.iter()
+            .map(|color| {
+                to.iter()
+                    .map(|other| {
+                        color
+                            .iter()
+                            .zip(other)
+                            .map(|(c, o)| (c - o).powi(2))
+                            .sum::<f32>()
+                            .sqrt()
+                    })
+                    .fold(f32::INFINITY, f32::min)
+            })
+            .sum::<f32>()
+            / from.len().max(1) as f32
+    };
+    (closest(&a, &b) + closest(&b, &a)) / 2.0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use color_eyre::eyre::eyre;
+    use smithay_client_toolkit::reexports::calloop::channel;
+
+    use super::*;
+
+    #[test]
+    fn extracted_in_background() {
+        let (ev_tx, _channel) = channel::channel();
+        let cache = PaletteCache::new(WorkerPool::new("test", 1, ev_tx));
+        let path = Path::new("a.png");
+        // The first lookup doesn't wait for the palette
+        assert_eq!(cache.get_or_extract(path, || Ok(vec![[1, 2, 3]])), None);
+        let mut palette = None;
+        for _ in 0..100 {
+            palette = cache.get_or_extract(path, || panic!("extracted twice"));
+            if palette.is_some() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(palette, Some(vec![[1, 2, 3]]));
+        // An image that can't be decoded is not tried again
+        let broken = Path::new("b.png");
+        assert_eq!(cache.get_or_extract(broken, || Err(eyre!("broken"))), None);
+        assert_eq!(cache.get_or_extract(broken, || panic!("extracted twice")), None);
+    }
+}
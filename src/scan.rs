@@ -0,0 +1,195 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use color_eyre::{eyre::WrapErr, Result};
+use hotwatch::{Event, Hotwatch};
+use log::warn;
+use smithay_client_toolkit::reexports::calloop::channel::Sender;
+use walkdir::WalkDir;
+
+use crate::workers::WorkerPool;
+
+/// How long to wait for the first listing of a directory, when `scan-timeout` is not set
+pub const DEFAULT_SCAN_TIMEOUT: Duration = Duration::from_secs(5);
+/// The listing is done again after this time, for the filesystems whose changes are not
+/// notified (e.g. a network mount)
+const MAX_AGE: Duration = Duration::from_secs(15 * 60);
+
+/// The directories listed for the outputs, each one shared by all the outputs using it. They
+/// are listed by a single worker, so that a slow filesystem doesn't block the event loop, and
+/// listed again only when they change
+pub struct DirScans {
+    scans: HashMap<PathBuf, DirScan>,
+    worker: WorkerPool,
+    hotwatch: Hotwatch,
+    ev_tx: Sender<()>,
+}
+
+impl DirScans {
+    /// `ev_tx` wakes up the event loop when a listing is complete or a directory changes
+    pub fn new(ev_tx: Sender<()>) -> Result<Self> {
+        Ok(Self {
+            scans: HashMap::new(),
+            worker: WorkerPool::new("scan", 1, ev_tx.clone()),
+            hotwatch: Hotwatch::new().context("hotwatch failed to initialize")?,
+            ev_tx,
+        })
+    }
+
+    /// Return the listing of `path`, starting it the first time the directory is used
+    pub fn get(&mut self, path: &Path) -> DirScan {
+        if let Some(scan) = self.scans.get(path) {
+            return scan.clone();
+        }
+        let scan = DirScan::new(path);
+        let stale = scan.stale.clone();
+        let ev_tx = self.ev_tx.clone();
+        // A file added or removed in the directory, or in its subdirectories, makes the
+        // listing outdated
+        if let Err(err) = self.hotwatch.watch(path, move |event: Event| {
+            if let Event::Create(_) | Event::Remove(_) | Event::Rename(_, _) | Event::Rescan = event
+            {
+                stale.store(true, Ordering::SeqCst);
+                let _ = ev_tx.send(());
+            }
+        }) {
+            warn!("watching the directory {path:?}, it will be listed again every {MAX_AGE:?}: {err:?}");
+        }
+        scan.rescan(&self.worker);
+        self.scans.insert(path.to_path_buf(), scan.clone());
+        scan
+    }
+
+    /// Drop the listings of the directories not in `used`, and list again the ones that are
+    /// outdated
+    pub fn update(&mut self, used: &HashSet<PathBuf>) {
+        let hotwatch = &mut self.hotwatch;
+        self.scans.retain(|path, _| {
+            let keep = used.contains(path);
+            if !keep {
+                let _ = hotwatch.unwatch(path);
+            }
+            keep
+        });
+        for scan in self.scans.values() {
+            if scan.is_outdated() {
+                scan.rescan(&self.worker);
+            }
+        }
+    }
+}
+
+/// The files in a directory. The last complete listing is kept until the directory changes
+#[derive(Clone)]
+pub struct DirScan {
+    path: PathBuf,
+    state: Arc<Mutex<ScanState>>,
+    /// Set by the watcher when the directory has changed
+    stale: Arc<AtomicBool>,
+}
+
+#[derive(Default)]
+struct ScanState {
+    /// The files found by the last complete listing, and when it was started
+    files: Option<(Vec<PathBuf>, Instant)>,
+    /// The files found so far by the running listing
+    partial: Vec<PathBuf>,
+    /// When the running listing has started
+    started: Option<Instant>,
+}
+
+impl DirScan {
+    fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            state: Arc::new(Mutex::new(ScanState::default())),
+            stale: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Whether the directory has changed or has been listed too long ago, and it is not being
+    /// listed already. The flag set by the watcher is only cleared in that case, so that a
+    /// change during a listing is not lost
+    fn is_outdated(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        if state.started.is_some() {
+            return false;
+        }
+        let too_old = state
+            .files
+            .as_ref()
+            .map_or(false, |(_, listed_at)| listed_at.elapsed() >= MAX_AGE);
+        self.stale.swap(false, Ordering::SeqCst) || too_old
+    }
+
+    /// List the directory in `worker`, unless it is already being listed
+    fn rescan(&self, worker: &WorkerPool) {
+        let mut state = self.state.lock().unwrap();
+        if state.started.is_some() {
+            return;
+        }
+        let started = Instant::now();
+        state.started = Some(started);
+        state.partial.clear();
+        drop(state);
+
+        let path = self.path.clone();
+        let state = self.state.clone();
+        // The files are read from the shared state, wait for nothing else
+        let _ = worker.run(move || {
+            for entry in WalkDir::new(&path).into_iter().filter_map(|e| e.ok()) {
+                if entry.file_type().is_file() {
+                    state.lock().unwrap().partial.push(entry.into_path());
+                }
+            }
+            let mut state = state.lock().unwrap();
+            let files = std::mem::take(&mut state.partial);
+            state.files = Some((files, started));
+            state.started = None;
+        });
+    }
+
+    /// Return the files of the directory. While the first listing is running, return the
+    /// files found so far once `timeout` has passed, or None if there are none yet
+    pub fn files(&self, timeout: Duration) -> Option<Vec<PathBuf>> {
+        let state = self.state.lock().unwrap();
+        match (&state.files, state.started) {
+            (Some((files, _)), _) => Some(files.clone()),
+            (None, Some(started)) if started.elapsed() >= timeout && !state.partial.is_empty() => {
+                warn!(
+                    "listing the directory {:?} is taking more than {timeout:?}, using the {} files found so far",
+                    self.path,
+                    state.partial.len()
+                );
+                Some(state.partial.clone())
+            }
+            _ => None,
+        }
+    }
+
+    /// Return the files found by the last complete listing, None while the first one is
+    /// running
+    pub fn listed(&self) -> Option<Vec<PathBuf>> {
+        let state = self.state.lock().unwrap();
+        state.files.as_ref().map(|(files, _)| files.clone())
+    }
+
+    /// How long until the first listing has been running for `timeout`, None if there is
+    /// already a complete listing or the timeout has passed
+    pub fn until_timeout(&self, timeout: Duration) -> Option<Duration> {
+        let state = self.state.lock().unwrap();
+        match (&state.files, state.started) {
+            (None, Some(started)) => timeout
+                .checked_sub(started.elapsed())
+                .filter(|wait| !wait.is_zero()),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,86 @@
+use std::path::Path;
+
+use color_eyre::{
+    eyre::{ensure, eyre, WrapErr},
+    Result,
+};
+use smithay_client_toolkit::compositor::CompositorState;
+use smithay_client_toolkit::reexports::client::{globals::registry_queue_init, Connection};
+use smithay_client_toolkit::shell::layer::LayerShell;
+use smithay_client_toolkit::shm::ShmState;
+
+use crate::wallpaper_config::WallpaperConfig;
+use crate::wallpaper_info::{PathType, WallpaperInfo};
+use crate::wpaperd::Wpaperd;
+
+/// Check the configuration and the Wayland compositor, printing a report on stdout.
+/// Return false if any of the checks failed
+pub fn run(output_config_file: &Path) -> bool {
+    println!("wpaperd self-test:");
+
+    let mut success = report("wayland", check_wayland());
+    match WallpaperConfig::new_from_path(output_config_file) {
+        Ok(wallpaper_config) => {
+            report(
+                "configuration",
+                Ok(format!("read from {output_config_file:?}")),
+            );
+            for (name, wallpaper_info) in wallpaper_config.sections() {
+                success &= report(name, check_source(wallpaper_info));
+            }
+        }
+        Err(err) => success &= report("configuration", Err(err)),
+    }
+
+    success
+}
+
+fn report(name: &str, res: Result<String>) -> bool {
+    match res {
+        Ok(msg) => {
+            println!("  [ok] {name}: {msg}");
+            true
+        }
+        Err(err) => {
+            println!("  [error] {name}: {err:#}");
+            false
+        }
+    }
+}
+
+fn check_wayland() -> Result<String> {
+    let conn = Connection::connect_to_env().context("connecting to the Wayland compositor")?;
+    let (globals, event_queue) =
+        registry_queue_init::<Wpaperd>(&conn).context("reading the Wayland globals")?;
+    let qh = event_queue.handle();
+    CompositorState::bind(&globals, &qh).context("binding wl_compositor")?;
+    ShmState::bind(&globals, &qh).context("binding wl_shm")?;
+    LayerShell::bind(&globals, &qh).context("binding zwlr_layer_shell_v1")?;
+    Ok("wl_compositor, wl_shm and zwlr_layer_shell_v1 are available".to_string())
+}
+
+/// Check that at least an image can be read for the section
+fn check_source(wallpaper_info: &WallpaperInfo) -> Result<String> {
+    let path = match (&wallpaper_info.path, wallpaper_info.test_pattern) {
+        (_, Some(test_pattern)) => return Ok(format!("draws the {test_pattern:?} test pattern")),
+        (Some(path), None) => path,
+        (None, None) => unreachable!("the sections without `path` are refused when reading them"),
+    };
+    if wallpaper_info.path_type() == Some(PathType::Fifo) {
+        // Reading from the FIFO would steal the list from the daemon
+        Ok(format!("images will be read from the FIFO {path:?}"))
+    } else if wallpaper_info.is_pool() {
+        let images = wallpaper_info.list_images()?;
+        ensure!(!images.is_empty(), "no images found in {path:?}");
+        images
+            .iter()
+            .find(|img_path| wallpaper_info.open_image(img_path, (0, 0)).is_ok())
+            .ok_or_else(|| eyre!("none of the images in {path:?} can be read"))?;
+        Ok(format!("{} images found in {path:?}", images.len()))
+    } else {
+        wallpaper_info
+            .open_image(path, (0, 0))
+            .with_context(|| format!("opening the image {path:?}"))?;
+        Ok(format!("{path:?} can be read"))
+    }
+}
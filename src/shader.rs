@@ -0,0 +1,279 @@
+use std::{
+    ffi::c_void,
+    fs,
+    path::Path,
+    ptr,
+    time::{Duration, Instant},
+};
+
+use color_eyre::{
+    eyre::{bail, eyre, WrapErr},
+    Result,
+};
+use glow::HasContext;
+use khronos_egl as egl;
+
+/// Covers the whole buffer with a single triangle; the texture coordinates go from (0, 0) at
+/// the top left of the image to (1, 1) at the bottom right
+const VERTEX_SHADER: &str = "#version 100
+attribute vec2 position;
+varying vec2 v_texcoord;
+void main() {
+    v_texcoord = position * 0.5 + 0.5;
+    gl_Position = vec4(position, 0.0, 1.0);
+}
+";
+const TRIANGLE: [f32; 6] = [-1.0, -1.0, 3.0, -1.0, -1.0, 3.0];
+/// The location of `position` in the vertex shader
+const POSITION: u32 = 0;
+
+/// A fragment shader applied to the wallpaper, run in an offscreen OpenGL ES 2 context. The
+/// shader reads the wallpaper from the texture `wallpaper` at `v_texcoord`, and gets the
+/// uniforms `resolution`, the size of the buffer in pixels, and `time`, the seconds since it
+/// has been loaded
+pub struct Shader {
+    egl: egl::Instance<egl::Static>,
+    display: egl::Display,
+    context: egl::Context,
+    surface: egl::Surface,
+    gl: glow::Context,
+    program: glow::Program,
+    vertices: glow::Buffer,
+    loaded_at: Instant,
+}
+
+impl Shader {
+    /// Compile the fragment shader at `path`, failing with its info log when it is invalid
+    pub fn new(path: &Path) -> Result<Self> {
+        let source =
+            fs::read_to_string(path).with_context(|| format!("reading the shader {path:?}"))?;
+        let egl = egl::Instance::new(egl::Static);
+        // SAFETY: the default display is always a valid display id
+        let display = unsafe { egl.get_display(egl::DEFAULT_DISPLAY) }
+            .ok_or_else(|| eyre!("no EGL display is available to run the shader"))?;
+        egl.initialize(display).context("initializing EGL")?;
+        egl.bind_api(egl::OPENGL_ES_API)
+            .context("binding the OpenGL ES API")?;
+        let attributes = [
+            egl::RENDERABLE_TYPE,
+            egl::OPENGL_ES2_BIT,
+            egl::SURFACE_TYPE,
+            egl::PBUFFER_BIT,
+            egl::RED_SIZE,
+            8,
+            egl::GREEN_SIZE,
+            8,
+            egl::BLUE_SIZE,
+            8,
+            egl::ALPHA_SIZE,
+            8,
+            egl::NONE,
+        ];
+        let config = egl
+            .choose_first_config(display, &attributes)
+            .context("choosing the EGL config")?
+            .ok_or_else(|| eyre!("no EGL config supports OpenGL ES 2"))?;
+        let context = egl
+            .create_context(
+                display,
+                config,
+                None,
+                &[egl::CONTEXT_CLIENT_VERSION, 2, egl::NONE],
+            )
+            .context("creating the OpenGL ES context")?;
+        // The shader draws into a framebuffer, the surface is only needed to make the
+        // context current
+        let surface = egl
+            .create_pbuffer_surface(display, config, &[egl::WIDTH, 1, egl::HEIGHT, 1, egl::NONE])
+            .context("creating the EGL surface")?;
+        egl.make_current(display, Some(surface), Some(surface), Some(context))
+            .context("making the OpenGL ES context current")?;
+        // SAFETY: the context is current, the functions loaded belong to it
+        let gl = unsafe {
+            glow::Context::from_loader_function(|name| {
+                egl.get_proc_address(name)
+                    .map_or(ptr::null(), |function| function as *const c_void)
+            })
+        };
+
+        // SAFETY: all the objects are created in the current context
+        let (program, vertices) = unsafe {
+            let program = link_program(&gl, &source)
+                .with_context(|| format!("compiling the shader {path:?}"))?;
+            let vertices = gl.create_buffer().map_err(|err| eyre!(err))?;
+            gl.bind_buffer(glow::ARRAY_BUFFER, Some(vertices));
+            let bytes: Vec<u8> = TRIANGLE.iter().flat_map(|v| v.to_ne_bytes()).collect();
+            gl.buffer_data_u8_slice(glow::ARRAY_BUFFER, &bytes, glow::STATIC_DRAW);
+            (program, vertices)
+        };
+        Ok(Self {
+            egl,
+            display,
+            context,
+            surface,
+            gl,
+            program,
+            vertices,
+            loaded_at: Instant::now(),
+        })
+    }
+
+    /// Run the shader on `pixels`, `width`x`height` in RGBA, replacing them with its output
+    pub fn run(&mut self, pixels: &mut [u8], width: u32, height: u32) -> Result<()> {
+        self.egl
+            .make_current(
+                self.display,
+                Some(self.surface),
+                Some(self.surface),
+                Some(self.context),
+            )
+            .context("making the OpenGL ES context current")?;
+        let (w, h) = (i32::try_from(width)?, i32::try_from(height)?);
+        let gl = &self.gl;
+        // SAFETY: the context is current and `pixels` holds `width`x`height` RGBA pixels
+        unsafe {
+            let input = texture(gl, w, h, Some(&*pixels))?;
+            let output = texture(gl, w, h, None)?;
+            let framebuffer = gl.create_framebuffer().map_err(|err| eyre!(err))?;
+            gl.bind_framebuffer(glow::FRAMEBUFFER, Some(framebuffer));
+            gl.framebuffer_texture_2d(
+                glow::FRAMEBUFFER,
+                glow::COLOR_ATTACHMENT0,
+                glow::TEXTURE_2D,
+                Some(output),
+                0,
+            );
+            let complete =
+                gl.check_framebuffer_status(glow::FRAMEBUFFER) == glow::FRAMEBUFFER_COMPLETE;
+            if complete {
+                gl.viewport(0, 0, w, h);
+                gl.use_program(Some(self.program));
+                gl.active_texture(glow::TEXTURE0);
+                gl.bind_texture(glow::TEXTURE_2D, Some(input));
+                let location = |name| gl.get_uniform_location(self.program, name);
+                gl.uniform_1_i32(location("wallpaper").as_ref(), 0);
+                gl.uniform_2_f32(location("resolution").as_ref(), width as f32, height as f32);
+                gl.uniform_1_f32(
+                    location("time").as_ref(),
+                    self.loaded_at.elapsed().as_secs_f32(),
+                );
+                gl.bind_buffer(glow::ARRAY_BUFFER, Some(self.vertices));
+                gl.enable_vertex_attrib_array(POSITION);
+                gl.vertex_attrib_pointer_f32(POSITION, 2, glow::FLOAT, false, 0, 0);
+                gl.draw_arrays(glow::TRIANGLES, 0, 3);
+                gl.read_pixels(
+                    0,
+                    0,
+                    w,
+                    h,
+                    glow::RGBA,
+                    glow::UNSIGNED_BYTE,
+                    glow::PixelPackData::Slice(pixels),
+                );
+            }
+            gl.bind_framebuffer(glow::FRAMEBUFFER, None);
+            gl.delete_framebuffer(framebuffer);
+            gl.delete_texture(input);
+            gl.delete_texture(output);
+            if !complete {
+                bail!("the framebuffer of the shader can't be drawn at {width}x{height}");
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Shader {
+    fn drop(&mut self) {
+        // SAFETY: the objects are deleted in the context they have been created in
+        unsafe {
+            if self
+                .egl
+                .make_current(
+                    self.display,
+                    Some(self.surface),
+                    Some(self.surface),
+                    Some(self.context),
+                )
+                .is_ok()
+            {
+                self.gl.delete_program(self.program);
+                self.gl.delete_buffer(self.vertices);
+            }
+        }
+        let _ = self.egl.make_current(self.display, None, None, None);
+        let _ = self.egl.destroy_surface(self.display, self.surface);
+        let _ = self.egl.destroy_context(self.display, self.context);
+    }
+}
+
+/// How long an animated shader waits before being drawn again, at `fps` frames per second
+pub fn interval(fps: u32) -> Duration {
+    Duration::from_secs_f64(1.0 / f64::from(fps.max(1)))
+}
+
+/// Compile the vertex shader and the fragment shader `source`, and link them
+unsafe fn link_program(gl: &glow::Context, source: &str) -> Result<glow::Program> {
+    let program = gl.create_program().map_err(|err| eyre!(err))?;
+    let mut shaders = Vec::new();
+    for (kind, source) in [
+        (glow::VERTEX_SHADER, VERTEX_SHADER),
+        (glow::FRAGMENT_SHADER, source),
+    ] {
+        let shader = gl.create_shader(kind).map_err(|err| eyre!(err))?;
+        gl.shader_source(shader, source);
+        gl.compile_shader(shader);
+        if !gl.get_shader_compile_status(shader) {
+            let log = gl.get_shader_info_log(shader);
+            shaders.push(shader);
+            shaders.iter().for_each(|shader| gl.delete_shader(*shader));
+            gl.delete_program(program);
+            bail!("{}", log.trim());
+        }
+        gl.attach_shader(program, shader);
+        shaders.push(shader);
+    }
+    gl.bind_attrib_location(program, POSITION, "position");
+    gl.link_program(program);
+    for shader in shaders {
+        gl.detach_shader(program, shader);
+        gl.delete_shader(shader);
+    }
+    if !gl.get_program_link_status(program) {
+        let log = gl.get_program_info_log(program);
+        gl.delete_program(program);
+        bail!("linking: {}", log.trim());
+    }
+    Ok(program)
+}
+
+/// Create a `width`x`height` RGBA texture, filled with `pixels` if any
+unsafe fn texture(
+    gl: &glow::Context,
+    width: i32,
+    height: i32,
+    pixels: Option<&[u8]>,
+) -> Result<glow::Texture> {
+    let texture = gl.create_texture().map_err(|err| eyre!(err))?;
+    gl.bind_texture(glow::TEXTURE_2D, Some(texture));
+    for (parameter, value) in [
+        (glow::TEXTURE_MIN_FILTER, glow::LINEAR),
+        (glow::TEXTURE_MAG_FILTER, glow::LINEAR),
+        (glow::TEXTURE_WRAP_S, glow::CLAMP_TO_EDGE),
+        (glow::TEXTURE_WRAP_T, glow::CLAMP_TO_EDGE),
+    ] {
+        gl.tex_parameter_i32(glow::TEXTURE_2D, parameter, value as i32);
+    }
+    gl.tex_image_2d(
+        glow::TEXTURE_2D,
+        0,
+        glow::RGBA as i32,
+        width,
+        height,
+        0,
+        glow::RGBA,
+        glow::UNSIGNED_BYTE,
+        pixels,
+    );
+    Ok(texture)
+}
@@ -0,0 +1,153 @@
+use std::path::Path;
+#[cfg(feature = "sqlite")]
+use std::path::PathBuf;
+
+#[cfg(feature = "sqlite")]
+use color_eyre::{eyre::WrapErr, Result};
+use serde::{Deserialize, Serialize};
+
+/// Which images of an SQLite index to use as the pool of wallpapers
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct IndexQuery {
+    /// The table listing the images
+    pub table: String,
+    /// The column of `table` containing the paths of the images. Relative paths are
+    /// joined to the directory of the index
+    #[serde(default = "default_column")]
+    pub column: String,
+    /// The conditions an image must meet to be chosen, all of them
+    #[serde(default)]
+    pub filter: Vec<Condition>,
+    /// The column ordering the images, in ascending order
+    #[serde(rename = "order-by")]
+    pub order_by: Option<String>,
+}
+
+/// Compare `column` to `value`; the value is passed to SQLite as a parameter, never as SQL
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Condition {
+    pub column: String,
+    #[serde(default)]
+    pub op: Operator,
+    pub value: Value,
+}
+
+/// How a condition compares its column to its value
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub enum Operator {
+    #[serde(rename = "=")]
+    Equal,
+    #[serde(rename = "!=")]
+    NotEqual,
+    #[serde(rename = "<")]
+    Less,
+    #[serde(rename = "<=")]
+    LessOrEqual,
+    #[serde(rename = ">")]
+    Greater,
+    #[serde(rename = ">=")]
+    GreaterOrEqual,
+    #[serde(rename = "like")]
+    Like,
+}
+
+impl Default for Operator {
+    fn default() -> Self {
+        Operator::Equal
+    }
+}
+
+impl Operator {
+    #[cfg(feature = "sqlite")]
+    fn sql(self) -> &'static str {
+        match self {
+            Operator::Equal => "=",
+            Operator::NotEqual => "!=",
+            Operator::Less => "<",
+            Operator::LessOrEqual => "<=",
+            Operator::Greater => ">",
+            Operator::GreaterOrEqual => ">=",
+            Operator::Like => "LIKE",
+        }
+    }
+}
+
+/// The value compared by a condition, as written in the configuration
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum Value {
+    Integer(i64),
+    Real(f64),
+    Text(String),
+}
+
+fn default_column() -> String {
+    "path".to_string()
+}
+
+/// Return true if `path` points to an SQLite index of wallpapers
+pub fn is_index(path: &Path) -> bool {
+    path.is_file()
+        && matches!(
+            path.extension().and_then(|ext| ext.to_str()),
+            Some("db" | "sqlite" | "sqlite3")
+        )
+}
+
+/// Quote an identifier coming from the configuration, so that it can only name a table or
+/// a column
+#[cfg(feature = "sqlite")]
+fn quote_identifier(identifier: &str) -> String {
+    format!("\"{}\"", identifier.replace('"', "\"\""))
+}
+
+/// Query the index for the paths of the images. The database is opened read-only, the
+/// identifiers are quoted and the values are bound as parameters, so the configuration
+/// cannot modify it
+#[cfg(feature = "sqlite")]
+pub fn list_images(index: &Path, query: &IndexQuery) -> Result<Vec<PathBuf>> {
+    use rusqlite::{params_from_iter, types::Value as SqlValue, Connection, OpenFlags};
+
+    let mut sql = format!(
+        "SELECT {} FROM {}",
+        quote_identifier(&query.column),
+        quote_identifier(&query.table)
+    );
+    if !query.filter.is_empty() {
+        let conditions: Vec<String> = query
+            .filter
+            .iter()
+            .map(|condition| {
+                format!(
+                    "{} {} ?",
+                    quote_identifier(&condition.column),
+                    condition.op.sql()
+                )
+            })
+            .collect();
+        sql.push_str(" WHERE ");
+        sql.push_str(&conditions.join(" AND "));
+    }
+    if let Some(column) = &query.order_by {
+        sql.push_str(" ORDER BY ");
+        sql.push_str(&quote_identifier(column));
+    }
+    let params = query.filter.iter().map(|condition| match &condition.value {
+        Value::Integer(value) => SqlValue::Integer(*value),
+        Value::Real(value) => SqlValue::Real(*value),
+        Value::Text(value) => SqlValue::Text(value.clone()),
+    });
+
+    let connection = Connection::open_with_flags(index, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .with_context(|| format!("opening the index {index:?}"))?;
+    let mut statement = connection
+        .prepare(&sql)
+        .with_context(|| format!("preparing the query {sql:?} on the index {index:?}"))?;
+    let paths = statement
+        .query_map(params_from_iter(params), |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<Vec<String>>>()
+        .with_context(|| format!("querying the index {index:?}"))?;
+
+    let dir = index.parent().unwrap_or_else(|| Path::new("/"));
+    Ok(paths.into_iter().map(|path| dir.join(path)).collect())
+}
@@ -0,0 +1,90 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use color_eyre::{eyre::WrapErr, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::surface::format_age;
+
+/// How many times each image has been shown on each output and for how long, kept in the
+/// state directory across restarts
+#[derive(Default, Deserialize, Serialize)]
+pub struct UsageStats {
+    #[serde(skip)]
+    path: PathBuf,
+    #[serde(flatten)]
+    outputs: BTreeMap<String, BTreeMap<PathBuf, ImageStats>>,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+pub struct ImageStats {
+    pub count: u64,
+    /// The cumulative time the image has been shown, in seconds
+    pub seconds: u64,
+}
+
+impl UsageStats {
+    /// Read the statistics saved in `path`, or start from scratch if it doesn't exist yet
+    pub fn load(path: &Path) -> Result<Self> {
+        let mut stats: Self = if path.exists() {
+            toml::from_str(&fs::read_to_string(path)?)
+                .with_context(|| format!("reading the statistics in {path:?}"))?
+        } else {
+            Self::default()
+        };
+        stats.path = path.to_path_buf();
+        Ok(stats)
+    }
+
+    /// Count that `output` has shown `img_path` for `shown`, until it changed to another image
+    pub fn record(&mut self, output: &str, img_path: PathBuf, shown: Duration) {
+        let image = self
+            .outputs
+            .entry(output.to_string())
+            .or_default()
+            .entry(img_path)
+            .or_default();
+        image.count += 1;
+        image.seconds += shown.as_secs();
+    }
+
+    /// Forget the statistics of `outputs`, or of all the outputs when it is empty
+    pub fn reset(&mut self, outputs: &[&str]) {
+        if outputs.is_empty() {
+            self.outputs.clear();
+        } else {
+            self.outputs
+                .retain(|output, _| !outputs.contains(&output.as_str()));
+        }
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let content = toml::to_string(self).context("serializing the statistics")?;
+        fs::write(&self.path, content)
+            .with_context(|| format!("writing the statistics to {:?}", self.path))
+    }
+
+    /// List the images shown on `outputs`, or on all the outputs when it is empty, the ones
+    /// shown the longest first
+    pub fn report(&self, outputs: &[&str]) -> Vec<String> {
+        self.outputs
+            .iter()
+            .filter(|(output, _)| outputs.is_empty() || outputs.contains(&output.as_str()))
+            .flat_map(|(output, images)| {
+                let mut images: Vec<_> = images.iter().collect();
+                images.sort_by_key(|(_, image)| std::cmp::Reverse(image.seconds));
+                images.into_iter().map(move |(img_path, image)| {
+                    format!(
+                        "{output}: {img_path:?}, shown {} times for {}",
+                        image.count,
+                        format_age(Duration::from_secs(image.seconds))
+                    )
+                })
+            })
+            .collect()
+    }
+}
@@ -1,13 +1,22 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs;
 use std::io::{BufWriter, Write};
-use std::path::PathBuf;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{Receiver, TryRecvError};
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant, SystemTime};
 
-use color_eyre::eyre::{ensure, Context};
+use color_eyre::eyre::{ensure, eyre, Context};
 use color_eyre::Result;
-use image::imageops::FilterType;
-use image::{open, DynamicImage, ImageBuffer, Pixel, Rgba};
-use log::warn;
+use image::imageops::{self, overlay, FilterType};
+use image::{
+    image_dimensions, DynamicImage, GenericImageView, ImageBuffer, Pixel, Rgba, RgbaImage,
+};
+use log::{debug, info, warn};
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::seq::SliceRandom;
+use rand::{rngs::StdRng, Rng, SeedableRng};
 use smithay_client_toolkit::output::OutputInfo;
 use smithay_client_toolkit::reexports::calloop::timer::{TimeoutAction, Timer};
 use smithay_client_toolkit::reexports::calloop::LoopHandle;
@@ -17,25 +26,327 @@ use smithay_client_toolkit::reexports::client::QueueHandle;
 use smithay_client_toolkit::shell::layer::{Anchor, LayerShell, LayerSurface};
 use smithay_client_toolkit::shm::slot::SlotPool;
 use smithay_client_toolkit::shm::ShmState;
-use walkdir::WalkDir;
 
-use crate::wallpaper_info::WallpaperInfo;
+use crate::animation::{self, Animation};
+use crate::archive::is_archive;
+use crate::fifo::FifoPool;
+use crate::image_cache::ImageCache;
+use crate::palette::{median_cut, palette_distance, PaletteCache};
+use crate::scan::{DirScan, DEFAULT_SCAN_TIMEOUT};
+#[cfg(feature = "shader")]
+use crate::shader::{self, Shader};
+use crate::time_ranges;
+use crate::wallpaper_config::DEFAULT_MOSAIC_GRID;
+use crate::wallpaper_info::{
+    Dither, Mode, RegionEffect, ReloadTransition, ResizePolicy, SameImagePolicy, TestPattern,
+    TransitionStyle, WallpaperInfo,
+};
+use crate::workers::WorkerPool;
 use crate::wpaperd::Wpaperd;
 
+/// How many images decoded from an archive are kept in memory
+pub const ARCHIVE_CACHE_SIZE: usize = 4;
+
+/// The filter used to scale the images to the size of the output
+const SCALING_FILTER: FilterType = FilterType::Lanczos3;
+/// The filter `hq-redraw` scales the image with, whatever the normal one is
+const HQ_FILTER: FilterType = FilterType::Lanczos3;
+/// The format of the buffers attached to the surfaces
+const BUFFER_FORMAT: wl_shm::Format = wl_shm::Format::Abgr8888;
+/// Height of the shadow gradient, before scaling it
+const GRADIENT_HEIGHT: u32 = 11;
+
+/// How long a step of a transition waits for the frame callback of the previous one, before
+/// being drawn anyway
+const FRAME_FALLBACK: Duration = Duration::from_millis(50);
+
+/// The range the `scale` set in the configuration is clamped to
+const MIN_SCALE: i32 = 1;
+const MAX_SCALE: i32 = 4;
+
+/// The region of the source image drawn on the surface
+#[derive(Clone, Copy)]
+struct Crop {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+/// How the last image has been scaled, reported by the `scaling-info` command
+struct Scaling {
+    /// The size of the image, before cropping it
+    source: (u32, u32),
+    crop: Crop,
+    /// The region of the buffer the crop has been scaled to
+    destination: Crop,
+    filter: FilterType,
+    scale: i32,
+}
+
+impl Scaling {
+    fn new(
+        source: (u32, u32),
+        crop: Crop,
+        frame: (u32, u32),
+        surface: (u32, u32),
+        filter: FilterType,
+        scale: i32,
+    ) -> Self {
+        // The frame is centered on the surface
+        let destination = Crop {
+            x: (surface.0 - frame.0) / 2,
+            y: (surface.1 - frame.1) / 2,
+            width: frame.0,
+            height: frame.1,
+        };
+        Self {
+            source,
+            crop,
+            destination,
+            filter,
+            scale,
+        }
+    }
+}
+
+/// How many colors are extracted from each image for the `palette` command
+const PALETTE_SIZE: usize = 5;
+
+/// How many images of the pool are compared with the current one when `cohesion` is set
+const COHESION_CANDIDATES: usize = 8;
+
+/// The distance between two palettes, in CIELAB, at which the weight of an image is divided
+/// by e when `cohesion` is 1
+const COHESION_DISTANCE: f32 = 20.0;
+
+/// The fraction of the darkest and of the brightest pixels clipped by `auto-contrast`
+const CONTRAST_CLIP: f64 = 0.005;
+
+/// A transient effect applied on top of the configured rendering
+#[derive(Clone, Copy, PartialEq)]
+pub enum Effect {
+    /// Darken the wallpaper by the given fraction, between 0 and 1
+    Dim(f32),
+}
+
 pub struct Surface {
     pub surface: wl_surface::WlSurface,
     pub output: WlOutput,
     pub layer: LayerSurface,
     pub dimensions: (u32, u32),
+    /// The scale the wallpaper is drawn with, see `Surface::effective_scale`
     pub scale: i32,
+    /// The scale advertised by the compositor for the output
+    pub compositor_scale: i32,
+    /// The scale of the last buffer attached to the surface
+    buffer_scale: i32,
+    /// Draw the next wallpaper at the native resolution of the output, once
+    hq_redraw: bool,
+    use_scaled_window: bool,
+    /// The seed of `rng`, derived from the global seed and the name of the output
+    pub seed: u64,
+    rng: StdRng,
     pub pool: SlotPool,
     pub wallpaper_info: Arc<WallpaperInfo>,
     pub need_redraw: bool,
     pub timer_expired: bool,
     pub time_changed: Instant,
+    /// When the rotation has been paused, if it is
+    paused_at: Option<Instant>,
     pub current_img: PathBuf,
+    /// The portrait image drawn at the right of `current_img`, when `portrait-pair` is set
+    pair_img: Option<PathBuf>,
+    /// The two images `ab-toggle` switches between, and whether the second one is drawn
+    ab_pair: Option<(PathBuf, PathBuf, bool)>,
     pub info: OutputInfo,
     pub configured: bool,
+    /// Effect set at runtime, independent from the configuration
+    pub effect: Option<Effect>,
+    /// The region of `current_source` drawn, kept when `on-resize` is `preserve-crop`
+    crop: Option<(PathBuf, Crop)>,
+    /// How the image drawn has been scaled
+    scaling: Option<Scaling>,
+    /// The dominant colors of the image drawn, and the image they were extracted from
+    palette: Option<(PathBuf, Vec<[u8; 3]>)>,
+    /// The palettes of the images compared by `cohesion`, shared by all the outputs
+    palettes: PaletteCache,
+    /// The range of luminance stretched by `auto-contrast` and the image it was computed for
+    contrast_range: Option<(PathBuf, (u8, u8))>,
+    /// The 10 bit format supported by the compositor, if any
+    ten_bit_format: Option<wl_shm::Format>,
+    /// The format of the last buffer attached to the surface
+    buffer_format: wl_shm::Format,
+    /// The width and the pixels of the last buffer attached to the surface
+    last_frame: Option<(u32, Vec<u8>)>,
+    /// Multiplier of the brightness of the wallpaper, set when the user is idle
+    brightness: f32,
+    /// The images listed in the FIFO, when `path` points to one
+    pub fifo_pool: Option<FifoPool>,
+    /// The files of the directory, when `path` points to one
+    pub dir_scan: Option<DirScan>,
+    /// Set while the change of image waits for the first listing of the directory
+    waiting_scan: bool,
+    /// Whether a timer has been added to wake up the event loop at the `scan-timeout`
+    scan_timer_armed: bool,
+    /// Decodes the images chosen from the pool out of the event loop
+    decode_pool: WorkerPool,
+    /// The image chosen from the pool being decoded by `decode_pool`
+    decoding: Option<Decoding>,
+    /// The index of the output and the number of outputs the images are distributed
+    /// between, when `distribute` is set
+    pub distribution: Option<(usize, usize)>,
+    created_at: Instant,
+    /// When the compositor has configured the surface for the first time
+    pub configured_at: Option<Instant>,
+    /// When the first wallpaper is allowed to be drawn, to stagger the outputs at startup
+    pub draw_not_before: Option<Instant>,
+    first_draw_at: Option<Instant>,
+    /// Whether the rotation timer has been added to the event loop
+    pub timer_armed: bool,
+    /// The minute of the day the range of `time-ranges` drawn starts at, and the settings of
+    /// the output before the directory of the range replaced `path`
+    time_range: Option<(u32, Arc<WallpaperInfo>)>,
+    /// Whether a timer has been added to switch to the next range of `time-ranges`
+    time_range_timer_armed: bool,
+    /// The album art or the directory of the genre drawn for the track played
+    #[cfg(feature = "media")]
+    media_source: Option<PathBuf>,
+    /// The image or the directory drawn for the active workspace
+    #[cfg(feature = "workspace")]
+    workspace_source: Option<PathBuf>,
+    /// How long the transitions to a new image wait before starting, to stagger the outputs
+    /// changing image at the same time
+    pub transition_delay: Duration,
+    /// Whether a timer has been added to draw the first step of a staggered transition
+    stagger_timer_armed: bool,
+    /// The shader compiled from the file in `shader`, None if it has failed
+    #[cfg(feature = "shader")]
+    shader: Option<(PathBuf, Option<Shader>)>,
+    /// Whether a timer has been added to draw the animated shader again
+    #[cfg(feature = "shader")]
+    shader_timer_armed: bool,
+    /// The crossfade to the image drawn last, while `transition-time` has not passed
+    transition: Option<Transition>,
+    /// Set when the compositor has shown the last step of the transition, to draw the next
+    transition_step: bool,
+    /// When the last step of the transition has been committed, until its frame callback
+    frame_pending: Option<Instant>,
+    /// Whether a timer has been added to draw the next step if the frame callback stalls
+    frame_timer_armed: bool,
+    /// The configuration reloaded during the transition, applied once it is complete when
+    /// `reload-transition` is `finish`
+    pending_reload: Option<Arc<WallpaperInfo>>,
+    /// Set when the image to draw has been asked with a command, until it is drawn
+    manual_change: bool,
+    /// The frames of the current image, when it is animated
+    animation: Option<Animation>,
+    /// Set when the animation has moved to the next frame, until it is drawn
+    frame_advanced: bool,
+    /// Whether a timer has been added to draw the next frame of the animation
+    animation_timer_armed: bool,
+    /// Set with `freeze`, the animations hold the frame drawn until `unfreeze`
+    frozen: bool,
+    qh: QueueHandle<Wpaperd>,
+    /// Decoding an image stored in an archive is slower, keep the last ones around
+    pub archive_cache: ImageCache,
+    /// The sprite sheet split in `grid`, with its modification time when it was decoded
+    sheet: Option<(PathBuf, SystemTime, DynamicImage)>,
+    /// The images chosen from the pool and when, the most recent first
+    history: VecDeque<(PathBuf, Instant)>,
+    /// The image chosen from the pool and when, counted in the statistics once it changes
+    shown: Option<(PathBuf, Instant)>,
+    /// The images changed away from and for how long they have been shown, until they are
+    /// taken with `take_viewed`
+    viewed: Vec<(PathBuf, Duration)>,
+    /// The size of the cells of the mosaic and the thumbnails of the images it shows
+    thumbnails: Option<((u32, u32), HashMap<PathBuf, DynamicImage>)>,
+    history_length: usize,
+    /// The global `max-commit-fps`, overridden by the one of the output
+    max_commit_fps: Option<u32>,
+    /// The maximum size of a buffer, in MiB
+    max_buffer_size: Option<u64>,
+    last_commit: Option<Instant>,
+    /// Whether a timer has been added to draw the redraw held back by `max-commit-fps`
+    commit_timer_armed: bool,
+}
+
+/// What a surface is drawn with, besides the Wayland objects: the configuration of its
+/// output and the global options
+pub struct SurfaceOptions {
+    pub wallpaper_info: Arc<WallpaperInfo>,
+    pub use_scaled_window: bool,
+    /// The seed of the random choices, derived from the global seed and the name of the output
+    pub seed: u64,
+    pub history_length: usize,
+    /// The global `max-commit-fps`, overridden by the one of the output
+    pub max_commit_fps: Option<u32>,
+    /// The maximum size of a buffer, in MiB
+    pub max_buffer_size: Option<u64>,
+    /// How many images decoded from archives are kept
+    pub archive_cache_size: usize,
+    pub decode_pool: WorkerPool,
+    pub palettes: PaletteCache,
+}
+
+/// An image chosen from the pool, waiting to be decoded
+struct Decoding {
+    img_path: PathBuf,
+    /// The images it has been chosen from
+    files: Vec<PathBuf>,
+    /// How many of the images chosen before it could not be decoded
+    tries: u32,
+    result: Receiver<Result<DynamicImage>>,
+}
+
+/// A crossfade between two frames of the same size, in `BUFFER_FORMAT`
+struct Transition {
+    from: Vec<u8>,
+    to: Vec<u8>,
+    started: Instant,
+    duration: Duration,
+    /// The color passed through halfway, with `transition = dip`
+    dip: Option<[u8; 3]>,
+}
+
+impl Transition {
+    /// How far the crossfade has gone, from 0 to 1
+    fn progress(&self, now: &Instant) -> f32 {
+        let elapsed = now.saturating_duration_since(self.started);
+        (elapsed.as_secs_f32() / self.duration.as_secs_f32()).min(1.0)
+    }
+
+    /// The blended frame at `now`, None once the crossfade is complete and `to` is drawn
+    fn step(&self, now: &Instant) -> Option<Vec<u8>> {
+        let progress = self.progress(now);
+        (progress < 1.0).then(|| match self.dip {
+            Some(color) if progress < 0.5 => fade(&self.from, color, progress * 2.0),
+            Some(color) => fade(&self.to, color, 2.0 - progress * 2.0),
+            None => crossfade(&self.from, &self.to, progress),
+        })
+    }
+}
+
+/// Reconcile `transition` with a reloaded configuration, as set by `policy`: with `finish`
+/// it keeps running and None is returned; with `snap` it ends and its last frame is returned
+fn reload_transition(
+    transition: &mut Option<Transition>,
+    policy: ReloadTransition,
+) -> Option<Vec<u8>> {
+    match policy {
+        ReloadTransition::Finish => None,
+        ReloadTransition::Snap => transition.take().map(|transition| transition.to),
+    }
+}
+
+/// How the frame to commit differs from the one drawn last
+enum Redraw {
+    /// The same image drawn again, e.g. for the idle dimming
+    Same,
+    /// A new image, or a new frame of an animation, crossfaded over the duration if any
+    Change(Option<Duration>),
+    /// A new image, faded through the color over the duration
+    Dip(Duration, [u8; 3]),
 }
 
 impl Surface {
@@ -46,8 +357,19 @@ impl Surface {
         surface: wl_surface::WlSurface,
         shm_state: &ShmState,
         info: OutputInfo,
-        wallpaper_info: Arc<WallpaperInfo>,
+        options: SurfaceOptions,
     ) -> Self {
+        let SurfaceOptions {
+            wallpaper_info,
+            use_scaled_window,
+            seed,
+            history_length,
+            max_commit_fps,
+            max_buffer_size,
+            archive_cache_size,
+            decode_pool,
+            palettes,
+        } = options;
         // TODO: error handling
         let layer = LayerSurface::builder()
             .namespace(format!("wpaperd-{}", info.name.as_ref().unwrap()))
@@ -66,11 +388,20 @@ impl Surface {
         // Commit the surface
         surface.commit();
         let pool = SlotPool::new(1200, shm_state).unwrap();
-        Self {
+        let ten_bit_format = [wl_shm::Format::Xbgr2101010, wl_shm::Format::Xrgb2101010]
+            .into_iter()
+            .find(|format| shm_state.formats().contains(format));
+        let mut res = Self {
             output,
             layer,
             dimensions: (0, 0),
-            scale: info.scale_factor,
+            scale: 0,
+            compositor_scale: info.scale_factor,
+            buffer_scale: 1,
+            hq_redraw: false,
+            use_scaled_window,
+            seed,
+            rng: StdRng::seed_from_u64(seed),
             pool,
             surface,
             info,
@@ -78,23 +409,98 @@ impl Surface {
             need_redraw: false,
             timer_expired: true,
             time_changed: Instant::now(),
+            paused_at: None,
             current_img: PathBuf::from("/"),
+            pair_img: None,
+            ab_pair: None,
             configured: false,
-        }
+            effect: None,
+            crop: None,
+            scaling: None,
+            contrast_range: None,
+            palette: None,
+            palettes,
+            ten_bit_format,
+            buffer_format: BUFFER_FORMAT,
+            last_frame: None,
+            brightness: 1.0,
+            fifo_pool: None,
+            dir_scan: None,
+            waiting_scan: false,
+            scan_timer_armed: false,
+            decode_pool,
+            decoding: None,
+            distribution: None,
+            created_at: Instant::now(),
+            configured_at: None,
+            draw_not_before: None,
+            first_draw_at: None,
+            timer_armed: false,
+            time_range: None,
+            time_range_timer_armed: false,
+            #[cfg(feature = "media")]
+            media_source: None,
+            #[cfg(feature = "workspace")]
+            workspace_source: None,
+            transition_delay: Duration::ZERO,
+            stagger_timer_armed: false,
+            #[cfg(feature = "shader")]
+            shader: None,
+            #[cfg(feature = "shader")]
+            shader_timer_armed: false,
+            transition: None,
+            transition_step: false,
+            frame_pending: None,
+            frame_timer_armed: false,
+            pending_reload: None,
+            manual_change: false,
+            animation: None,
+            frame_advanced: false,
+            animation_timer_armed: false,
+            frozen: false,
+            qh: qh.clone(),
+            archive_cache: ImageCache::new(archive_cache_size),
+            sheet: None,
+            history: VecDeque::with_capacity(history_length),
+            shown: None,
+            viewed: Vec::new(),
+            thumbnails: None,
+            history_length,
+            max_commit_fps,
+            max_buffer_size,
+            last_commit: None,
+            commit_timer_armed: false,
+        };
+        res.update_scale();
+        res
     }
 
-    /// Returns true if something has been drawn to the surface
+    /// Draw the wallpaper, if needed, and commit it to the surface
     pub fn draw(&mut self, now: &Instant) -> Result<()> {
+        // The configuration reloaded during the transition, now that it is complete
+        if self.transition.is_none() {
+            if let Some(wallpaper_info) = self.pending_reload.take() {
+                if self.update_wallpaper_info(wallpaper_info) {
+                    // Add the timer for the new duration in the next loop
+                    self.timer_armed = false;
+                }
+            }
+        }
         // No need to draw yet
         if (self.dimensions.0 == 0 || self.dimensions.1 == 0)
-            || (!self.need_redraw && !self.timer_expired)
+            || (!self.need_redraw && !self.timer_expired && !self.transition_step)
+            || self.draw_not_before.map_or(false, |t| *now < t)
+            // Drawn later by the timer of `schedule_pending_commit`
+            || self.commit_delay(now).is_some()
         {
             return Ok(());
         }
 
-        let stride = 4 * self.dimensions.0 as i32 * self.scale;
-        let width = self.dimensions.0 as i32 * self.scale;
-        let height = self.dimensions.1 as i32 * self.scale;
+        let (scale, filter) = redraw_quality(self.hq_redraw, self.scale, self.compositor_scale);
+        let scale = self.fit_buffer_scale(scale)?;
+        let stride = 4 * self.dimensions.0 as i32 * scale;
+        let width = self.dimensions.0 as i32 * scale;
+        let height = self.dimensions.1 as i32 * scale;
         let size = (stride * height) as usize;
 
         self.pool
@@ -102,102 +508,1075 @@ impl Surface {
             .context("resizing the wayland pool")?;
         let slot = self.pool.new_slot((stride * height) as usize)?;
 
+        let pixels = if !self.configured {
+            None
+        } else if self.need_redraw || self.timer_expired {
+            let previous = (self.current_source().to_path_buf(), self.pair_img.clone());
+            match self.render(width.try_into()?, height.try_into()?, scale, filter, now)? {
+                Some((format, pixels)) => {
+                    let changed = (self.current_source(), self.pair_img.as_ref())
+                        != (previous.0.as_path(), previous.1.as_ref());
+                    // The asked image is drawn now, even when it is not crossfaded
+                    let manual = mem::take(&mut self.manual_change);
+                    let (redraw, delay) = if changed {
+                        (self.image_redraw(manual), self.transition_delay)
+                    } else if mem::take(&mut self.frame_advanced) {
+                        (Redraw::Change(self.frame_interpolation()), Duration::ZERO)
+                    } else {
+                        (Redraw::Same, Duration::ZERO)
+                    };
+                    let pixels =
+                        self.transition_to(format, pixels, width as u32, redraw, delay, now);
+                    Some((format, pixels))
+                }
+                // The same image has been chosen again and `on-same-image` is `skip`, or the
+                // directory is still being listed, or the new image is still being decoded
+                None => {
+                    self.timer_expired = self.waiting();
+                    return Ok(());
+                }
+            }
+        } else {
+            // The next step of the transition
+            self.transition_step = false;
+            match self.transition_frame(now) {
+                Some(pixels) => Some((BUFFER_FORMAT, pixels)),
+                None => return Ok(()),
+            }
+        };
+        self.buffer_format = pixels.as_ref().map_or(BUFFER_FORMAT, |(format, _)| *format);
+
         let buffer =
             self.pool
-                .create_buffer_in(&slot, width, height, stride, wl_shm::Format::Abgr8888)?;
-        if self.configured {
-            let image = self.get_image(self.timer_expired, now)?;
-
-            let mut image = image
-                .resize_to_fill(width.try_into()?, height.try_into()?, FilterType::Lanczos3)
-                .into_rgba8();
-
-            self.apply_shadow(&mut image, width.try_into()?);
-
+                .create_buffer_in(&slot, width, height, stride, self.buffer_format)?;
+        if let Some((_, pixels)) = pixels {
             let canvas = slot.canvas(&mut self.pool).unwrap();
 
             let mut writer = BufWriter::new(canvas);
             writer
-                .write_all(image.as_raw())
+                .write_all(&pixels)
                 .context("writing the image to the surface")?;
             writer.flush().context("flushing the surface writer")?;
+            // The buffer can't be read back while the compositor holds it
+            self.last_frame = Some((width as u32, pixels));
         }
 
+        if self.buffer_scale != scale {
+            self.buffer_scale = scale;
+            self.surface.set_buffer_scale(scale);
+        }
         // Attach the buffer to the surface and mark the entire surface as damaged
         self.surface.attach(Some(buffer.wl_buffer()), 0, 0);
         self.surface.damage_buffer(0, 0, width, height);
+        // Draw the next step once the compositor has shown this one, so that each output
+        // steps at its own refresh rate. A staggered transition that has not started yet is
+        // woken up by `schedule_transition_start`
+        if self
+            .transition
+            .as_ref()
+            .map_or(false, |transition| transition.started <= *now)
+        {
+            self.surface.frame(&self.qh, self.surface.clone());
+            self.frame_pending = Some(*now);
+        }
 
         // Finally, commit the surface
+        debug!(
+            "{}: committing a {width}x{height} buffer at scale {scale}",
+            self.name()
+        );
         self.surface.commit();
+        self.last_commit = Some(*now);
 
         // Update status
         self.need_redraw = false;
-        self.timer_expired = false;
+        // After the placeholder, the image is chosen once the directory has been listed
+        self.timer_expired = self.waiting();
+        if self.configured {
+            // The next redraw goes back to the normal settings
+            self.hq_redraw = false;
+        }
+        if self.configured && self.first_draw_at.is_none() {
+            let now = Instant::now();
+            self.first_draw_at = Some(now);
+            info!(
+                "{}: first wallpaper drawn {:?} after the surface was created",
+                self.name(),
+                now - self.created_at
+            );
+        }
         Ok(())
     }
 
-    fn apply_shadow(&self, image: &mut ImageBuffer<Rgba<u8>, Vec<u8>>, width: u32) {
-        if self.wallpaper_info.apply_shadow.unwrap_or(false) {
-            const GRADIENT_HEIGHT: u32 = 11;
-            type RgbaImage = image::ImageBuffer<image::Rgba<u8>, Vec<u8>>;
-            let gradient = DynamicImage::ImageRgba8(
-                RgbaImage::from_raw(
-                    1,
-                    GRADIENT_HEIGHT,
-                    vec![
-                        0, 0, 0, 225, 0, 0, 0, 202, 0, 0, 0, 178, 0, 0, 0, 154, 0, 0, 0, 130, 0, 0,
-                        0, 107, 0, 0, 0, 83, 0, 0, 0, 59, 0, 0, 0, 36, 0, 0, 0, 12, 0, 0, 0, 0,
-                    ],
-                )
-                .unwrap(),
-            )
-            .resize_exact(
-                width,
-                GRADIENT_HEIGHT * 4 * self.scale as u32,
-                FilterType::Triangle,
+    /// The frame of the animation of the current image to draw at `now`, None if it is not
+    /// animated or its frames are still being decoded
+    fn animation_frame(&mut self, now: &Instant) -> Option<DynamicImage> {
+        let source = self.current_source().to_path_buf();
+        let animation = self
+            .animation
+            .as_mut()
+            .filter(|animation| animation.source == source)?;
+        if !self.frozen && animation.advance(now) {
+            self.frame_advanced = true;
+        }
+        animation.frame().cloned()
+    }
+
+    /// Start decoding the frames of the current image, when it is animated and drawn alone.
+    /// `image`, its first frame, is drawn until then
+    fn start_animation(&mut self, image: DynamicImage) -> DynamicImage {
+        let source = self.current_source().to_path_buf();
+        let in_archive = self
+            .wallpaper_info
+            .path
+            .as_ref()
+            .map_or(false, |path| is_archive(path));
+        if !animation::is_animated(&source)
+            || in_archive
+            || self.wallpaper_info.grid.is_some()
+            || self.wallpaper_info.mode == Some(Mode::Mosaic)
+            || self.pair_img.is_some()
+        {
+            self.animation = None;
+            return image;
+        }
+        if self
+            .animation
+            .as_ref()
+            .map_or(true, |animation| animation.source != source)
+        {
+            let path = source.clone();
+            let frames = self
+                .decode_pool
+                .run(move || animation::decode_frames(&path));
+            self.animation = Some(Animation::new(source, frames));
+        }
+        image
+    }
+
+    /// Add a timer to draw the next frame of the animation
+    pub fn schedule_animation(&mut self, handle: LoopHandle<Wpaperd>, now: &Instant) {
+        if self.animation_timer_armed || self.frozen {
+            return;
+        }
+        if let Some(wait) = self
+            .animation
+            .as_mut()
+            .and_then(|animation| animation.until_next(now))
+        {
+            self.animation_timer_armed = true;
+            let name = self.name().to_string();
+            handle
+                .insert_source(Timer::from_duration(wait), move |_, _, wpaperd| {
+                    if let Ok(surface) = wpaperd.surface_by_name(&name) {
+                        surface.animation_timer_armed = false;
+                        surface.need_redraw = true;
+                    }
+                    TimeoutAction::Drop
+                })
+                .expect("Failed to insert event source!");
+        }
+    }
+
+    /// The duration of the crossfade to a new image, None to draw it at once. `manual` is
+    /// true when the image has been asked with a command
+    fn transition_time(&self, manual: bool) -> Option<Duration> {
+        if manual && !self.wallpaper_info.manual_transition.unwrap_or(true) {
+            return None;
+        }
+        self.wallpaper_info
+            .transition_time
+            .filter(|duration| !duration.is_zero())
+    }
+
+    /// How a new image is drawn, as set by `transition`. `manual` is true when the image has
+    /// been asked with a command
+    fn image_redraw(&self, manual: bool) -> Redraw {
+        match (self.transition_time(manual), self.wallpaper_info.transition) {
+            (Some(duration), Some(TransitionStyle::Dip)) => {
+                Redraw::Dip(duration, self.wallpaper_info.dip_color.unwrap_or([0, 0, 0]))
+            }
+            (duration, _) => Redraw::Change(duration),
+        }
+    }
+
+    /// The duration of the crossfade to the frame of the animation drawn, when
+    /// `interpolate-frames` is set: the whole delay of the frame
+    fn frame_interpolation(&self) -> Option<Duration> {
+        self.animation
+            .as_ref()
+            .filter(|_| self.wallpaper_info.interpolate_frames.unwrap_or(false))
+            .map(Animation::delay)
+    }
+
+    /// Start the crossfade from the frame drawn last to `pixels`, `width` pixels wide, after
+    /// `delay`, when they are a `Redraw::Change` or a `Redraw::Dip`; otherwise, during a
+    /// crossfade, make them its new end. Return the pixels to commit now. The 10 bit buffers
+    /// are never crossfaded
+    fn transition_to(
+        &mut self,
+        format: wl_shm::Format,
+        pixels: Vec<u8>,
+        width: u32,
+        redraw: Redraw,
+        delay: Duration,
+        now: &Instant,
+    ) -> Vec<u8> {
+        let from = match &self.last_frame {
+            Some((last_width, last_pixels))
+                if *last_width == width
+                    && last_pixels.len() == pixels.len()
+                    && self.buffer_format == BUFFER_FORMAT
+                    && format == BUFFER_FORMAT =>
+            {
+                last_pixels
+            }
+            // The size or the format has changed, the transition can't continue
+            _ => {
+                self.transition = None;
+                return pixels;
+            }
+        };
+        match redraw {
+            Redraw::Change(Some(duration)) => {
+                debug!("{}: crossfading over {duration:?}", self.name());
+                // During a crossfade, the new one starts from the frame on screen
+                self.transition = Some(Transition {
+                    from: from.clone(),
+                    to: pixels,
+                    started: *now + delay,
+                    duration,
+                    dip: None,
+                });
+            }
+            Redraw::Dip(duration, color) => {
+                debug!("{}: dipping over {duration:?}", self.name());
+                self.transition = Some(Transition {
+                    from: from.clone(),
+                    to: pixels,
+                    started: *now + delay,
+                    duration,
+                    dip: Some(color),
+                });
+            }
+            Redraw::Change(None) => {
+                self.transition = None;
+                return pixels;
+            }
+            Redraw::Same => match &mut self.transition {
+                Some(transition) => transition.to = pixels,
+                None => return pixels,
+            },
+        }
+        self.transition_frame(now).unwrap()
+    }
+
+    /// The pixels of the transition at `now`, ending it when it is complete
+    fn transition_frame(&mut self, now: &Instant) -> Option<Vec<u8>> {
+        match self.transition.as_ref()?.step(now) {
+            Some(pixels) => Some(pixels),
+            None => self.transition.take().map(|transition| transition.to),
+        }
+    }
+
+    /// Called when the compositor has shown the last frame committed
+    pub fn frame_done(&mut self) {
+        self.frame_pending = None;
+        self.transition_step = self.transition.is_some();
+    }
+
+    /// When the frame callback of the last step of the transition doesn't come, e.g. because
+    /// the output is hidden, draw the next step after `FRAME_FALLBACK`
+    pub fn schedule_frame_fallback(&mut self, handle: LoopHandle<Wpaperd>) {
+        if self.frame_timer_armed || self.frame_pending.is_none() {
+            return;
+        }
+        self.frame_timer_armed = true;
+        let name = self.name().to_string();
+        handle
+            .insert_source(
+                Timer::from_duration(FRAME_FALLBACK),
+                move |_, _, wpaperd| {
+                    if let Ok(surface) = wpaperd.surface_by_name(&name) {
+                        surface.frame_timer_armed = false;
+                        // A step committed after this timer was added is waited for again
+                        if surface
+                            .frame_pending
+                            .map_or(false, |committed| committed.elapsed() >= FRAME_FALLBACK)
+                        {
+                            surface.frame_done();
+                        }
+                    }
+                    TimeoutAction::Drop
+                },
             )
-            .into_rgba8();
+            .expect("Failed to insert event source!");
+    }
+
+    /// Add a timer to draw the first step of the transition staggered by `transition_delay`,
+    /// once it starts
+    pub fn schedule_transition_start(&mut self, handle: LoopHandle<Wpaperd>, now: &Instant) {
+        if self.stagger_timer_armed {
+            return;
+        }
+        let wait = match &self.transition {
+            Some(transition) if transition.started > *now => transition.started - *now,
+            _ => return,
+        };
+        self.stagger_timer_armed = true;
+        let name = self.name().to_string();
+        handle
+            .insert_source(Timer::from_duration(wait), move |_, _, wpaperd| {
+                if let Ok(surface) = wpaperd.surface_by_name(&name) {
+                    surface.stagger_timer_armed = false;
+                    surface.frame_done();
+                }
+                TimeoutAction::Drop
+            })
+            .expect("Failed to insert event source!");
+    }
+
+    /// Lower `scale` until the buffer fits in `max-buffer-size`, and in any case in the
+    /// sizes supported by wl_shm
+    fn fit_buffer_scale(&self, scale: i32) -> Result<i32> {
+        let max = buffer_limit(self.max_buffer_size);
+        let (fitted, bytes) = fit_scale(self.dimensions, scale, max);
+        ensure!(
+            bytes <= max,
+            "the output {} needs a buffer of {} MiB, more than the maximum of {} MiB",
+            self.name(),
+            bytes / (1024 * 1024),
+            max / (1024 * 1024)
+        );
+        if fitted != scale {
+            warn!(
+                "the buffer of the output {} at scale {scale} would be too big, drawing it at scale {fitted}",
+                self.name()
+            );
+        }
+        Ok(fitted)
+    }
 
+    /// Draw the wallpaper, returning the pixels in the buffer format they are encoded in, or
+    /// None when there is nothing new to draw. The image is scaled with `filter` if set,
+    /// otherwise with `SCALING_FILTER`
+    fn render(
+        &mut self,
+        width: u32,
+        height: u32,
+        scale: i32,
+        filter: Option<FilterType>,
+        now: &Instant,
+    ) -> Result<Option<(wl_shm::Format, Vec<u8>)>> {
+        // Drawn at the exact size of the buffer, without any of the steps for the images
+        if let Some(pattern) = self.wallpaper_info.test_pattern {
+            let image = test_pattern(pattern, width, height, scale.try_into()?);
+            return Ok(Some((BUFFER_FORMAT, image.into_raw())));
+        }
+        let frame = if self.timer_expired {
+            None
+        } else {
+            self.animation_frame(now)
+        };
+        let image = match frame {
+            // The animation is drawn without decoding the image again
+            Some(frame) => frame,
+            None => match self.get_image(self.timer_expired, now)? {
+                Some(image) => self.start_animation(image),
+                None => return Ok(None),
+            },
+        };
+        let source = self.current_source().to_path_buf();
+        if self
+            .palette
+            .as_ref()
+            .map_or(true, |(palette_source, _)| *palette_source != source)
+        {
+            let palette = median_cut(&image, PALETTE_SIZE);
+            if self.wallpaper_info.cohesion.is_some() {
+                self.palettes.insert(source.clone(), palette.clone());
+            }
+            self.palette = Some((source, palette));
+        }
+        // The image fills the frame, that can't be bigger than the surface
+        let (surface_width, surface_height) = (width, height);
+        let (width, height) = self.frame_size(width, height);
+        let image = if self.wallpaper_info.mode == Some(Mode::Mosaic) {
+            self.mosaic(image, width, height)
+        } else {
+            image
+        };
+        let (image_width, image_height) = GenericImageView::dimensions(&image);
+        let filter = filter.unwrap_or(SCALING_FILTER);
+        let image = if self.resize_policy() == ResizePolicy::PreserveCrop {
+            let source = self.current_source().to_path_buf();
+            let crop = match &self.crop {
+                Some((crop_source, crop)) if *crop_source == source => {
+                    preserve_crop(crop, image_width, image_height, width, height)
+                }
+                _ => fill_crop(image_width, image_height, width, height),
+            };
+            self.crop = Some((source, crop));
+            self.scaling = Some(Scaling::new(
+                (image_width, image_height),
+                crop,
+                (width, height),
+                (surface_width, surface_height),
+                filter,
+                scale,
+            ));
             image
-                .pixels_mut()
-                .zip(gradient.pixels())
-                .for_each(|(p, g)| p.blend(g));
+                .crop_imm(crop.x, crop.y, crop.width, crop.height)
+                .resize_exact(width, height, filter)
+        } else {
+            // The same region resize_to_fill keeps
+            self.scaling = Some(Scaling::new(
+                (image_width, image_height),
+                fill_crop(image_width, image_height, width, height),
+                (width, height),
+                (surface_width, surface_height),
+                filter,
+                scale,
+            ));
+            image.resize_to_fill(width, height, filter)
+        };
+        let image = if self.wallpaper_info.auto_contrast.unwrap_or(false) {
+            let source = self.current_source().to_path_buf();
+            let range = match &self.contrast_range {
+                Some((range_source, range)) if *range_source == source => *range,
+                _ => contrast_range(&image),
+            };
+            self.contrast_range = Some((source, range));
+            stretch_contrast(image, range)
+        } else {
+            image
+        };
+        let image = if (width, height) != (surface_width, surface_height) {
+            letterbox(
+                &image,
+                surface_width,
+                surface_height,
+                self.wallpaper_info.background_color.unwrap_or([0, 0, 0]),
+            )
+        } else {
+            image
+        };
+        let (width, height) = (surface_width, surface_height);
+        let image = if self.wallpaper_info.region_effects.is_empty() {
+            image
+        } else {
+            region_effects(image, &self.wallpaper_info.region_effects, scale as u32)
+        };
+        let gradient = self.shadow_gradient(width, scale);
+        let brightness = self.brightness_factor();
+
+        let format = self.buffer_format(&image);
+        // Dithering needs more precision than the 8 bit buffer, it's useless on 10 bit ones
+        let dither = self
+            .wallpaper_info
+            .dither
+            .filter(|_| format == BUFFER_FORMAT);
+        if format == BUFFER_FORMAT && dither.is_none() {
+            let mut image = image.into_rgba8();
+            if let Some(gradient) = gradient {
+                image
+                    .pixels_mut()
+                    .zip(gradient.into_rgba8().pixels())
+                    .for_each(|(p, g)| p.blend(g));
+            }
+            if brightness < 1.0 {
+                image
+                    .pixels_mut()
+                    .for_each(|p| p.apply_without_alpha(|c| (c as f32 * brightness) as u8));
+            }
+            let pixels = image.into_raw();
+            #[cfg(feature = "shader")]
+            let pixels = self.run_shader(pixels, width, height);
+            Ok(Some((format, pixels)))
+        } else {
+            // Keep the 16 bits per channel of the decoded image until packing them
+            let mut image = image.into_rgba16();
+            if let Some(gradient) = gradient {
+                image
+                    .pixels_mut()
+                    .zip(gradient.into_rgba16().pixels())
+                    .for_each(|(p, g)| p.blend(g));
+            }
+            if brightness < 1.0 {
+                image
+                    .pixels_mut()
+                    .for_each(|p| p.apply_without_alpha(|c| (c as f32 * brightness) as u16));
+            }
+            let pixels = match dither {
+                Some(Dither::Ordered) => ordered_dither(&image),
+                Some(Dither::ErrorDiffusion) => error_diffusion(&image),
+                None => pack_10bit(&image, format),
+            };
+            Ok(Some((format, pixels)))
+        }
+    }
+
+    /// Draw `pixels`, `width`x`height`, through the fragment shader in `shader`, compiling it
+    /// the first time. A shader that fails is logged once and skipped until `shader` changes
+    #[cfg(feature = "shader")]
+    fn run_shader(&mut self, mut pixels: Vec<u8>, width: u32, height: u32) -> Vec<u8> {
+        let path = match &self.wallpaper_info.shader {
+            Some(path) => path.clone(),
+            None => {
+                self.shader = None;
+                return pixels;
+            }
+        };
+        let name = self.name().to_string();
+        if self
+            .shader
+            .as_ref()
+            .map_or(true, |(loaded, _)| *loaded != path)
+        {
+            let shader = Shader::new(&path)
+                .map_err(|err| log::error!("{name}: {err:?}"))
+                .ok();
+            self.shader = Some((path.clone(), shader));
+        }
+        if let Some((_, Some(shader))) = &mut self.shader {
+            if let Err(err) = shader.run(&mut pixels, width, height) {
+                log::error!("{name}: running the shader {path:?}: {err:?}");
+                self.shader = Some((path, None));
+            }
+        }
+        pixels
+    }
+
+    /// Add a timer to draw the shader again after `1 / shader-fps` seconds, for the animated
+    /// ones
+    #[cfg(feature = "shader")]
+    pub fn schedule_shader(&mut self, handle: LoopHandle<Wpaperd>) {
+        if self.shader_timer_armed || !matches!(self.shader, Some((_, Some(_)))) {
+            return;
+        }
+        let fps = match self.wallpaper_info.shader_fps {
+            Some(fps) => fps,
+            None => return,
+        };
+        self.shader_timer_armed = true;
+        let name = self.name().to_string();
+        handle
+            .insert_source(
+                Timer::from_duration(shader::interval(fps)),
+                move |_, _, wpaperd| {
+                    if let Ok(surface) = wpaperd.surface_by_name(&name) {
+                        surface.shader_timer_armed = false;
+                        surface.need_redraw = true;
+                    }
+                    TimeoutAction::Drop
+                },
+            )
+            .expect("Failed to insert event source!");
+    }
+
+    /// Draw the thumbnails of the last images in the history in a grid of `width`x`height`
+    /// pixels, by row. `image` is the current one, the first of the history
+    fn mosaic(&mut self, image: DynamicImage, width: u32, height: u32) -> DynamicImage {
+        let (rows, cols) = self
+            .wallpaper_info
+            .mosaic_grid
+            .unwrap_or(DEFAULT_MOSAIC_GRID);
+        let count = self
+            .wallpaper_info
+            .mosaic_count
+            .unwrap_or(rows * cols)
+            .min(rows * cols) as usize;
+        let cell = ((width / cols).max(1), (height / rows).max(1));
+        let mut thumbnails = match self.thumbnails.take() {
+            Some((cell_size, thumbnails)) if cell_size == cell => thumbnails,
+            _ => HashMap::new(),
+        };
+        let mut recent: Vec<PathBuf> = self
+            .history
+            .iter()
+            .map(|(img_path, _)| img_path.clone())
+            .take(count)
+            .collect();
+        // With `history-length` set to 0, only the current image is shown
+        if recent.is_empty() {
+            recent.push(self.current_img.clone());
+        }
+        // Keep only the thumbnails still shown
+        thumbnails.retain(|img_path, _| recent.contains(img_path));
+
+        let [r, g, b] = self.wallpaper_info.background_color.unwrap_or([0, 0, 0]);
+        let mut canvas = ImageBuffer::from_pixel(width, height, Rgba([r, g, b, u8::MAX]));
+        let mut image = Some(image);
+        for (index, img_path) in recent.iter().enumerate() {
+            if !thumbnails.contains_key(img_path) {
+                let source = match image.take().filter(|_| index == 0) {
+                    Some(image) => image,
+                    None => match self.open_image(img_path) {
+                        Ok(source) => source,
+                        Err(err) => {
+                            warn!("opening the image {img_path:?} for the mosaic: {err:?}");
+                            continue;
+                        }
+                    },
+                };
+                let thumbnail = source.resize_to_fill(cell.0, cell.1, SCALING_FILTER);
+                thumbnails.insert(img_path.clone(), thumbnail);
+            }
+            let (row, col) = (index as u32 / cols, index as u32 % cols);
+            overlay(
+                &mut canvas,
+                &thumbnails[img_path].to_rgba8(),
+                i64::from(col * cell.0),
+                i64::from(row * cell.1),
+            );
+        }
+        self.thumbnails = Some((cell, thumbnails));
+        DynamicImage::ImageRgba8(canvas)
+    }
+
+    /// Use a 10 bit format only when asked in the configuration, supported by the compositor
+    /// and when the image has more than 8 bits per channel to fill it
+    fn buffer_format(&self, image: &DynamicImage) -> wl_shm::Format {
+        match self.ten_bit_format {
+            Some(format)
+                if is_deep(image) && self.wallpaper_info.high_bit_depth.unwrap_or(false) =>
+            {
+                format
+            }
+            _ => BUFFER_FORMAT,
+        }
+    }
+
+    /// The size of the frame the image is drawn in, clamped to the size of the surface
+    fn frame_size(&self, width: u32, height: u32) -> (u32, u32) {
+        match self.wallpaper_info.frame_size {
+            Some((frame_width, frame_height)) => (frame_width.min(width), frame_height.min(height)),
+            None => (width, height),
+        }
+    }
+
+    /// The gradient blended on the top of the wallpaper, when `apply-shadow` is set
+    fn shadow_gradient(&self, width: u32, scale: i32) -> Option<DynamicImage> {
+        if !self.wallpaper_info.apply_shadow.unwrap_or(false) {
+            return None;
+        }
+        type RgbaImage = image::ImageBuffer<image::Rgba<u8>, Vec<u8>>;
+        let gradient = DynamicImage::ImageRgba8(
+            RgbaImage::from_raw(
+                1,
+                GRADIENT_HEIGHT,
+                vec![
+                    0, 0, 0, 225, 0, 0, 0, 202, 0, 0, 0, 178, 0, 0, 0, 154, 0, 0, 0, 130, 0, 0, 0,
+                    107, 0, 0, 0, 83, 0, 0, 0, 59, 0, 0, 0, 36, 0, 0, 0, 12, 0, 0, 0, 0,
+                ],
+            )
+            .unwrap(),
+        );
+        Some(gradient.resize_exact(
+            width,
+            GRADIENT_HEIGHT * 4 * scale as u32,
+            FilterType::Triangle,
+        ))
+    }
+
+    /// The image currently drawn on the surface
+    pub fn current_source(&self) -> &Path {
+        match &self.wallpaper_info.path {
+            Some(path) if !self.wallpaper_info.is_pool() => path,
+            // Without `path`, a test pattern is drawn and `current_img` is never set
+            _ => &self.current_img,
+        }
+    }
+
+    /// Describe the state of the surface, one property per line
+    pub fn status(&self) -> String {
+        let mut status = vec![
+            self.name().to_string(),
+            format!("  image: {:?}", self.current_source()),
+            format!("  seed: {}", self.seed),
+        ];
+        if let Some(pattern) = self.wallpaper_info.test_pattern {
+            status[1] = format!("  test pattern: {pattern:?}");
+        }
+        if let Some(pair_img) = &self.pair_img {
+            status.push(format!("  paired with: {pair_img:?}"));
+        }
+        if let (Some(configured_at), Some(first_draw_at)) = (self.configured_at, self.first_draw_at)
+        {
+            status.push(format!(
+                "  first draw: {:?} after creation, {:?} after configure",
+                first_draw_at - self.created_at,
+                first_draw_at - configured_at
+            ));
         }
+        status.join("\n")
     }
 
+    /// Describe, in order, the operations applied to draw the wallpaper, with their
+    /// parameters
+    pub fn pipeline(&self) -> Vec<String> {
+        // The scale of the buffer actually committed, lowered by `max-buffer-size` or raised
+        // by `hq-redraw`
+        let scale = self.buffer_scale;
+        let width = self.dimensions.0 as i32 * scale;
+        let height = self.dimensions.1 as i32 * scale;
+        if let Some(pattern) = self.wallpaper_info.test_pattern {
+            return vec![format!("test-pattern {pattern:?} size={width}x{height}")];
+        }
+        let (frame_width, frame_height) = self.frame_size(width as u32, height as u32);
+        let mut steps = vec![format!("decode path={:?}", self.current_source())];
+        if self.wallpaper_info.mode == Some(Mode::Mosaic) {
+            let (rows, cols) = self
+                .wallpaper_info
+                .mosaic_grid
+                .unwrap_or(DEFAULT_MOSAIC_GRID);
+            steps.push(format!("mosaic grid={rows}x{cols}"));
+        }
+        if let Some((_, crop)) = self
+            .crop
+            .as_ref()
+            .filter(|_| self.resize_policy() == ResizePolicy::PreserveCrop)
+        {
+            steps.push(format!(
+                "crop x={} y={} size={}x{}",
+                crop.x, crop.y, crop.width, crop.height
+            ));
+            steps.push(format!(
+                "scale mode=exact filter={SCALING_FILTER:?} size={frame_width}x{frame_height}"
+            ));
+        } else {
+            steps.push(format!(
+                "scale mode=fill filter={SCALING_FILTER:?} size={frame_width}x{frame_height}"
+            ));
+        }
+        if let Some((_, (low, high))) = self
+            .contrast_range
+            .as_ref()
+            .filter(|_| self.wallpaper_info.auto_contrast.unwrap_or(false))
+        {
+            steps.push(format!("auto-contrast range={low}-{high}"));
+        }
+        if (frame_width, frame_height) != (width as u32, height as u32) {
+            let [r, g, b] = self.wallpaper_info.background_color.unwrap_or([0, 0, 0]);
+            steps.push(format!(
+                "letterbox size={width}x{height} color=#{r:02x}{g:02x}{b:02x}"
+            ));
+        }
+        for region in &self.wallpaper_info.region_effects {
+            let mut step = format!(
+                "region-effect x={} y={} size={}x{}",
+                region.x * scale as u32,
+                region.y * scale as u32,
+                region.width * scale as u32,
+                region.height * scale as u32
+            );
+            if let Some(sigma) = region.blur {
+                step.push_str(&format!(" blur={}", sigma * scale as f32));
+            }
+            if let Some(amount) = region.dim {
+                step.push_str(&format!(" dim={amount}"));
+            }
+            steps.push(step);
+        }
+        if self.wallpaper_info.apply_shadow.unwrap_or(false) {
+            steps.push(format!(
+                "shadow height={}",
+                GRADIENT_HEIGHT * 4 * scale as u32
+            ));
+        }
+        if let Some(Effect::Dim(amount)) = self.effect {
+            steps.push(format!("dim amount={amount}"));
+        }
+        if self.brightness < 1.0 {
+            steps.push(format!("idle-dim brightness={:.2}", self.brightness));
+        }
+        steps.push(format!(
+            "commit format={:?} size={width}x{height} buffer-scale={}",
+            self.buffer_format, scale
+        ));
+        steps
+    }
+
+    /// The brightness multiplier resulting from the idle dimming and the transient effect
+    fn brightness_factor(&self) -> f32 {
+        let mut brightness = self.brightness;
+        if let Some(Effect::Dim(amount)) = self.effect {
+            brightness *= 1.0 - amount;
+        }
+        brightness
+    }
+
+    /// Set the transient effect and redraw the surface if it has changed
+    pub fn set_effect(&mut self, effect: Option<Effect>) {
+        if self.effect != effect {
+            self.effect = effect;
+            self.need_redraw = true;
+        }
+    }
+
+    fn same_image_policy(&self) -> SameImagePolicy {
+        self.wallpaper_info
+            .on_same_image
+            .unwrap_or(SameImagePolicy::Reroll)
+    }
+
+    fn resize_policy(&self) -> ResizePolicy {
+        self.wallpaper_info.on_resize.unwrap_or(ResizePolicy::Refit)
+    }
+
+    /// Update the size of the surface, as configured by the compositor
+    pub fn set_dimensions(&mut self, dimensions: (u32, u32)) {
+        if self.dimensions == dimensions {
+            return;
+        }
+        // The first configure is not a resize
+        let resized = self.dimensions != (0, 0);
+        self.dimensions = dimensions;
+        self.need_redraw = true;
+        if resized && self.resize_policy() == ResizePolicy::Reset {
+            self.timer_expired = true;
+        }
+    }
+
+    /// Return the color of the pixel at `x`, `y` in the last buffer attached, as 8 bit RGBA
+    pub fn pixel(&self, x: u32, y: u32) -> Result<[u8; 4]> {
+        let (width, pixels) = self
+            .last_frame
+            .as_ref()
+            .ok_or_else(|| eyre!("nothing has been drawn on {} yet", self.name()))?;
+        let height = pixels.len() as u32 / (4 * width);
+        ensure!(
+            x < *width && y < height,
+            "the pixel {x},{y} is outside of the buffer ({width}x{height})"
+        );
+        let offset = 4 * (y * width + x) as usize;
+        let bytes: [u8; 4] = pixels[offset..offset + 4].try_into()?;
+        if self.buffer_format == BUFFER_FORMAT {
+            return Ok(bytes);
+        }
+        let word = u32::from_le_bytes(bytes);
+        // Drop the 2 least significant bits of each 10 bit channel
+        let channel = |shift: u32| ((word >> shift) & 0x3ff) >> 2;
+        let (r, b) = if self.buffer_format == wl_shm::Format::Xrgb2101010 {
+            (channel(20), channel(0))
+        } else {
+            (channel(0), channel(20))
+        };
+        Ok([r as u8, channel(10) as u8, b as u8, u8::MAX])
+    }
+
+    /// Describe how the image drawn has been cropped and scaled into the buffer
+    pub fn scaling_info(&self) -> Result<Vec<String>> {
+        let scaling = self
+            .scaling
+            .as_ref()
+            .filter(|_| self.wallpaper_info.test_pattern.is_none())
+            .ok_or_else(|| eyre!("no image has been scaled on {} yet", self.name()))?;
+        let rect = |r: &Crop| format!("{}x{} at {},{}", r.width, r.height, r.x, r.y);
+        Ok(vec![
+            format!("source: {}x{}", scaling.source.0, scaling.source.1),
+            format!("source rectangle: {}", rect(&scaling.crop)),
+            format!("destination rectangle: {}", rect(&scaling.destination)),
+            format!("filter: {:?}", scaling.filter),
+            format!(
+                "scale: {} (compositor scale {})",
+                scaling.scale, self.compositor_scale
+            ),
+        ])
+    }
+
+    /// The dominant colors of the image drawn, as `#rrggbb`, the most common first
+    pub fn palette(&self) -> Result<Vec<String>> {
+        let (_, palette) = self
+            .palette
+            .as_ref()
+            .ok_or_else(|| eyre!("nothing has been drawn on {} yet", self.name()))?;
+        Ok(palette
+            .iter()
+            .map(|[r, g, b]| format!("#{r:02x}{g:02x}{b:02x}"))
+            .collect())
+    }
+
+    /// How long the next commit has to wait to respect `max-commit-fps`
+    fn commit_delay(&self, now: &Instant) -> Option<Duration> {
+        let fps = self.wallpaper_info.max_commit_fps.or(self.max_commit_fps)?;
+        let interval = Duration::from_secs_f64(1.0 / f64::from(fps));
+        let elapsed = now.saturating_duration_since(self.last_commit?);
+        interval
+            .checked_sub(elapsed)
+            .filter(|delay| !delay.is_zero())
+    }
+
+    /// When a redraw has been held back by `max-commit-fps`, wake up the event loop when it
+    /// can be committed. All the redraws requested until then are drawn at once
+    pub fn schedule_pending_commit(&mut self, handle: LoopHandle<Wpaperd>, now: &Instant) {
+        if self.commit_timer_armed
+            || (!self.need_redraw && !self.timer_expired && !self.transition_step)
+        {
+            return;
+        }
+        if let Some(delay) = self.commit_delay(now) {
+            self.commit_timer_armed = true;
+            let name = self.name().to_string();
+            handle
+                .insert_source(Timer::from_duration(delay), move |_, _, wpaperd| {
+                    if let Ok(surface) = wpaperd.surface_by_name(&name) {
+                        surface.commit_timer_armed = false;
+                    }
+                    TimeoutAction::Drop
+                })
+                .expect("Failed to insert event source!");
+        }
+    }
+
+    /// Add a timer to choose the image from the files found so far when the first listing of
+    /// the directory takes more than `scan-timeout`
+    pub fn schedule_scan_timeout(&mut self, handle: LoopHandle<Wpaperd>) {
+        if self.scan_timer_armed || !self.waiting_scan {
+            return;
+        }
+        let timeout = self
+            .wallpaper_info
+            .scan_timeout
+            .unwrap_or(DEFAULT_SCAN_TIMEOUT);
+        if let Some(wait) = self
+            .dir_scan
+            .as_ref()
+            .and_then(|dir_scan| dir_scan.until_timeout(timeout))
+        {
+            self.scan_timer_armed = true;
+            let name = self.name().to_string();
+            handle
+                .insert_source(Timer::from_duration(wait), move |_, _, wpaperd| {
+                    if let Ok(surface) = wpaperd.surface_by_name(&name) {
+                        surface.scan_timer_armed = false;
+                    }
+                    TimeoutAction::Drop
+                })
+                .expect("Failed to insert event source!");
+        }
+    }
+
+    /// Redraw the current image once at the native resolution of the output, ignoring
+    /// `scale` and `--use-scaled-window`, e.g. before taking a screenshot
+    pub fn request_hq_redraw(&mut self) {
+        self.hq_redraw = true;
+        self.need_redraw = true;
+    }
+
+    /// Set the brightness multiplier and redraw the surface if it has changed
+    pub fn set_brightness(&mut self, brightness: f32) {
+        if self.brightness != brightness {
+            self.brightness = brightness;
+            self.need_redraw = true;
+        }
+    }
+
+    /// Return the image to draw, choosing a new one from the pool when `update` is true. Return
+    /// None when the current image is chosen again and it does not need to be drawn, or while
+    /// the new one is being decoded
     fn get_image(
         &mut self,
         update: bool,
         now: &Instant,
-    ) -> Result<DynamicImage, color_eyre::Report> {
-        let path = self.wallpaper_info.path.as_ref().unwrap();
+    ) -> Result<Option<DynamicImage>, color_eyre::Report> {
+        let wallpaper_info = self.wallpaper_info.clone();
+        let path = wallpaper_info.path.as_ref().unwrap();
         let mut tries = 0;
-        if path.is_dir() {
+        self.waiting_scan = false;
+        if wallpaper_info.is_pool() {
+            if let Some(decoding) = self.decoding.take() {
+                match decoding.result.try_recv() {
+                    Ok(Ok(image)) => {
+                        if is_archive(path) {
+                            self.archive_cache
+                                .insert(decoding.img_path.clone(), image.clone());
+                        }
+                        let image =
+                            self.show_chosen(decoding.img_path, &decoding.files, image, now);
+                        return Ok(Some(image));
+                    }
+                    Ok(Err(err)) => warn!("{err:?}"),
+                    // Keep the image drawn until the new one is ready
+                    Err(TryRecvError::Empty) => {
+                        self.decoding = Some(decoding);
+                        return Ok(None);
+                    }
+                    Err(TryRecvError::Disconnected) => {
+                        warn!("decoding the image {:?} has failed", decoding.img_path)
+                    }
+                }
+                tries = decoding.tries + 1;
+                ensure!(
+                    tries < 5,
+                    "tried reading an image from the directory {path:?} without success",
+                );
+            }
             if !update {
-                if let Ok(image) = open(&self.current_img) {
-                    return Ok(image);
+                if let Ok(image) = self.open_image(&self.current_img.clone()) {
+                    return Ok(Some(self.draw_pair(image)));
                 }
             }
             loop {
-                let files: Vec<PathBuf> = WalkDir::new(path)
-                    .into_iter()
-                    .filter_map(|e| e.ok())
-                    .filter(|e| {
-                        if let Some(guess) = new_mime_guess::from_path(e.path()).first() {
-                            guess.type_() == "image"
-                        } else {
-                            false
+                let files = if let Some(fifo_pool) = &self.fifo_pool {
+                    fifo_pool.images()
+                } else if let Some(dir_scan) = &self.dir_scan {
+                    let timeout = wallpaper_info.scan_timeout.unwrap_or(DEFAULT_SCAN_TIMEOUT);
+                    match dir_scan.files(timeout) {
+                        Some(files) => files
+                            .into_iter()
+                            .filter(|file| wallpaper_info.can_open(file))
+                            .collect(),
+                        None => {
+                            self.waiting_scan = true;
+                            return Ok(self.placeholder());
                         }
-                    })
-                    .map(|e| e.path().to_path_buf())
-                    .collect();
-                let img_path = files[rand::random::<usize>() % files.len()].clone();
-                match open(&img_path).with_context(|| format!("opening the image {img_path:?}")) {
-                    Ok(image) => {
-                        self.time_changed = *now;
-                        self.current_img = img_path;
-                        break Ok(image);
                     }
+                } else {
+                    wallpaper_info.list_images()?
+                };
+                let files = self.distributed_share(files);
+                ensure!(!files.is_empty(), "no images found in {path:?}");
+                let mut img_path = match wallpaper_info.cohesion {
+                    Some(cohesion) => self.cohesive_choice(&files, cohesion),
+                    None => files[self.rng.gen_range(0..files.len())].clone(),
+                };
+                if img_path == self.current_img {
+                    match self.same_image_policy() {
+                        SameImagePolicy::Redraw => {}
+                        SameImagePolicy::Reroll => {
+                            let others: Vec<&PathBuf> = files
+                                .iter()
+                                .filter(|img_path| **img_path != self.current_img)
+                                .collect();
+                            if !others.is_empty() {
+                                img_path = others[self.rng.gen_range(0..others.len())].clone();
+                            }
+                        }
+                        // Count it as a change, without drawing anything
+                        SameImagePolicy::Skip if !self.need_redraw => {
+                            self.time_changed = *now;
+                            return Ok(None);
+                        }
+                        SameImagePolicy::Skip => {}
+                    }
+                }
+                // Decoded out of the event loop, unless it is already in memory
+                if let Some(job) = self.decode_job(&img_path) {
+                    debug!("{}: decoding {img_path:?}", self.name());
+                    self.decoding = Some(Decoding {
+                        result: self.decode_pool.run(job),
+                        img_path,
+                        files,
+                        tries,
+                    });
+                    return Ok(None);
+                }
+                match self
+                    .open_image(&img_path)
+                    .with_context(|| format!("opening the image {img_path:?}"))
+                {
+                    Ok(image) => break Ok(Some(self.show_chosen(img_path, &files, image, now))),
                     Err(err) => {
                         warn!("{err:?}");
                         tries += 1;
@@ -210,7 +1589,436 @@ impl Surface {
                 );
             }
         } else {
-            open(path).with_context(|| format!("opening the image {:?}", &path))
+            self.open_image(path)
+                .map(Some)
+                .with_context(|| format!("opening the image {:?}", &path))
+        }
+    }
+
+    /// Make `img_path`, chosen from `files`, the current image and return the image to draw
+    fn show_chosen(
+        &mut self,
+        img_path: PathBuf,
+        files: &[PathBuf],
+        image: DynamicImage,
+        now: &Instant,
+    ) -> DynamicImage {
+        self.time_changed = *now;
+        self.push_history(img_path.clone(), *now);
+        self.pair_img = self.choose_pair(files, &img_path, &image);
+        self.current_img = img_path;
+        self.draw_pair(image)
+    }
+
+    /// Decode `img_path` in `decode_pool`, unless it can be taken from the caches
+    fn decode_job(
+        &self,
+        img_path: &Path,
+    ) -> Option<impl FnOnce() -> Result<DynamicImage> + Send + 'static> {
+        if self.wallpaper_info.grid.is_some() || self.archive_cache.contains(img_path) {
+            return None;
+        }
+        let wallpaper_info = self.wallpaper_info.clone();
+        let img_path = img_path.to_path_buf();
+        let size = self.target_size();
+        Some(move || {
+            wallpaper_info
+                .open_image(&img_path, size)
+                .with_context(|| format!("opening the image {img_path:?}"))
+        })
+    }
+
+    /// While waiting for the directory to be listed, keep the image drawn, or draw the
+    /// background when there is none
+    fn placeholder(&self) -> Option<DynamicImage> {
+        let [r, g, b] = self.wallpaper_info.background_color.unwrap_or([0, 0, 0]);
+        let placeholder = RgbaImage::from_pixel(1, 1, Rgba([r, g, b, u8::MAX]));
+        self.last_frame
+            .is_none()
+            .then(|| DynamicImage::ImageRgba8(placeholder))
+    }
+
+    /// Whether the change of image is waiting for the directory to be listed or for the new
+    /// image to be decoded
+    fn waiting(&self) -> bool {
+        self.waiting_scan || self.decoding.is_some()
+    }
+
+    /// Draw the current image again, after its file has been written. What has been
+    /// computed from the previous content is dropped
+    pub fn reload_current(&mut self) {
+        self.crop = None;
+        self.contrast_range = None;
+        self.palette = None;
+        self.thumbnails = None;
+        self.need_redraw = true;
+    }
+
+    /// Choose an image from `files`, favouring by `cohesion` the ones whose palette is close
+    /// to the one of the current image. Only `COHESION_CANDIDATES` random images are compared;
+    /// the ones whose palette is not known yet count as the farthest, and have it extracted in
+    /// the background for the next changes
+    fn cohesive_choice(&mut self, files: &[PathBuf], cohesion: f32) -> PathBuf {
+        let current = match &self.palette {
+            Some((source, palette)) if source == self.current_source() => palette.clone(),
+            // The first image is chosen before any palette is known
+            _ => return files[self.rng.gen_range(0..files.len())].clone(),
+        };
+        // Don't compare the current image when it would be rerolled anyway
+        let mut pool: Vec<&PathBuf> = files
+            .iter()
+            .filter(|img_path| **img_path != self.current_img)
+            .collect();
+        if pool.is_empty() || self.same_image_policy() != SameImagePolicy::Reroll {
+            pool = files.iter().collect();
+        }
+        let candidates: Vec<PathBuf> = pool
+            .choose_multiple(&mut self.rng, COHESION_CANDIDATES)
+            .map(|img_path| (*img_path).clone())
+            .collect();
+        let weights: Vec<f32> = candidates
+            .iter()
+            .map(|img_path| {
+                let distance = self
+                    .candidate_palette(img_path)
+                    .map_or(f32::INFINITY, |palette| {
+                        palette_distance(&current, &palette)
+                    });
+                (1.0 - cohesion) + cohesion * (-distance / COHESION_DISTANCE).exp()
+            })
+            .collect();
+        match WeightedIndex::new(&weights) {
+            Ok(index) => candidates[index.sample(&mut self.rng)].clone(),
+            // None of the palettes of the candidates is known
+            Err(_) => candidates[self.rng.gen_range(0..candidates.len())].clone(),
+        }
+    }
+
+    /// The palette of an image of the pool, if it is known, without waiting for it to be
+    /// extracted
+    fn candidate_palette(&self, img_path: &Path) -> Option<Vec<[u8; 3]>> {
+        let wallpaper_info = self.wallpaper_info.clone();
+        let path = img_path.to_path_buf();
+        let size = self.target_size();
+        self.palettes.get_or_extract(img_path, move || {
+            let image = wallpaper_info
+                .open_image(&path, size)
+                .with_context(|| format!("opening the image {path:?}"))?;
+            Ok(median_cut(&image, PALETTE_SIZE))
+        })
+    }
+
+    /// Keep, from the sorted images, the ones at `index`, `index + count`, `index + 2 * count`
+    /// and so on. All of them are kept when there aren't enough for every output
+    fn distributed_share(&self, mut files: Vec<PathBuf>) -> Vec<PathBuf> {
+        match self.distribution {
+            Some((index, count)) if count > 1 && files.len() > index => {
+                files.sort();
+                files.into_iter().skip(index).step_by(count).collect()
+            }
+            _ => files,
+        }
+    }
+
+    /// Choose the portrait image drawn next to `image`, when `portrait-pair` is set, `image` is
+    /// a portrait and the output a landscape. Return None when the pool has no other portraits
+    fn choose_pair(
+        &mut self,
+        files: &[PathBuf],
+        img_path: &Path,
+        image: &DynamicImage,
+    ) -> Option<PathBuf> {
+        let (width, height) = GenericImageView::dimensions(image);
+        if !self.wallpaper_info.portrait_pair.unwrap_or(false)
+            || width >= height
+            || self.dimensions.0 <= self.dimensions.1
+        {
+            return None;
+        }
+        // Only the header of the images is read; the ones in archives and grids are skipped
+        let portraits: Vec<&PathBuf> = files
+            .iter()
+            .filter(|path| *path != img_path)
+            .filter(|path| image_dimensions(path).map_or(false, |(width, height)| width < height))
+            .collect();
+        if portraits.is_empty() {
+            return None;
+        }
+        Some(portraits[self.rng.gen_range(0..portraits.len())].clone())
+    }
+
+    /// Put `image` and `pair_img` side by side, or return `image` alone when there is no pair
+    fn draw_pair(&mut self, image: DynamicImage) -> DynamicImage {
+        let pair_img = match self.pair_img.clone() {
+            Some(pair_img) => pair_img,
+            None => return image,
+        };
+        match self.open_image(&pair_img) {
+            Ok(pair) => side_by_side(&image, &pair),
+            Err(err) => {
+                warn!("opening the image {pair_img:?}: {err:?}");
+                self.pair_img = None;
+                image
+            }
+        }
+    }
+
+    /// Remember at most `history_length` images, dropping the oldest ones
+    pub fn set_history_length(&mut self, history_length: usize) {
+        self.history.truncate(history_length);
+        self.history_length = history_length;
+    }
+
+    /// Set the global `max-commit-fps`, still overridden by the one of the output
+    pub fn set_max_commit_fps(&mut self, max_commit_fps: Option<u32>) {
+        self.max_commit_fps = max_commit_fps;
+    }
+
+    fn push_history(&mut self, img_path: PathBuf, now: Instant) {
+        if let Some((shown, since)) = self.shown.replace((img_path.clone(), now)) {
+            self.viewed
+                .push((shown, now.saturating_duration_since(since)));
+        }
+        if self.history_length == 0 {
+            return;
+        }
+        self.history.truncate(self.history_length - 1);
+        self.history.push_front((img_path, now));
+    }
+
+    /// Return the images shown since the last call, with how long they have been shown
+    pub fn take_viewed(&mut self) -> Vec<(PathBuf, Duration)> {
+        std::mem::take(&mut self.viewed)
+    }
+
+    /// List the images in the history, the most recent first, with their index
+    pub fn history(&self, now: &Instant) -> Vec<String> {
+        self.history
+            .iter()
+            .enumerate()
+            .map(|(index, (img_path, shown_at))| {
+                let age = format_age(now.saturating_duration_since(*shown_at));
+                format!("{index}: {img_path:?} ({age} ago)")
+            })
+            .collect()
+    }
+
+    /// Draw again the image at `index` in the history and restart the timer from it
+    pub fn goto_history(&mut self, index: usize, now: &Instant) -> Result<()> {
+        self.ensure_pool()?;
+        let img_path = self
+            .history
+            .get(index)
+            .ok_or_else(|| eyre!("the history has only {} entries", self.history.len()))?
+            .0
+            .clone();
+        self.show_image(img_path, now);
+        Ok(())
+    }
+
+    /// The images of the pool the output chooses from, sorted. A directory is not listed
+    /// again, its last complete listing is used
+    fn sorted_pool(&self) -> Result<Vec<PathBuf>> {
+        let files = if let Some(fifo_pool) = &self.fifo_pool {
+            fifo_pool.images()
+        } else if let Some(dir_scan) = &self.dir_scan {
+            // The positions in the files found so far would change once the listing is done
+            dir_scan
+                .listed()
+                .ok_or_else(|| {
+                    eyre!(
+                        "the index of {} is not ready, its directory is still being listed",
+                        self.name()
+                    )
+                })?
+                .into_iter()
+                .filter(|file| self.wallpaper_info.can_open(file))
+                .collect()
+        } else {
+            self.wallpaper_info.list_images()?
+        };
+        let mut files = self.distributed_share(files);
+        files.sort();
+        Ok(files)
+    }
+
+    /// The position of the current image in the sorted pool, starting from 1, and the number
+    /// of images in the pool
+    pub fn position(&self) -> Result<(Option<usize>, usize)> {
+        self.ensure_pool()?;
+        let files = self.sorted_pool()?;
+        let position = files
+            .iter()
+            .position(|img_path| *img_path == self.current_img)
+            .map(|index| index + 1);
+        Ok((position, files.len()))
+    }
+
+    /// Draw the image at `position` in the sorted pool, starting from 1, and restart the timer
+    /// from it
+    pub fn seek(&mut self, position: usize, now: &Instant) -> Result<()> {
+        self.ensure_pool()?;
+        let files = self.sorted_pool()?;
+        let img_path = position
+            .checked_sub(1)
+            .and_then(|index| files.get(index))
+            .ok_or_else(|| eyre!("the position must be between 1 and {}", files.len()))?
+            .clone();
+        self.show_image(img_path, now);
+        Ok(())
+    }
+
+    fn ensure_pool(&self) -> Result<()> {
+        ensure!(
+            self.wallpaper_info.is_pool(),
+            "the output {} is not choosing its wallpaper from a pool of images",
+            self.name()
+        );
+        Ok(())
+    }
+
+    /// Draw `img_path` from the pool now, as if it had been chosen
+    fn show_image(&mut self, img_path: PathBuf, now: &Instant) {
+        // The image chosen by the timer, if it is still being decoded, is dropped
+        self.decoding = None;
+        self.timer_expired = false;
+        self.manual_change = true;
+        self.push_history(img_path.clone(), *now);
+        self.current_img = img_path;
+        self.pair_img = None;
+        self.time_changed = *now;
+        self.need_redraw = true;
+        // The timer already armed would expire too early, add a new one in the next loop
+        self.timer_armed = false;
+    }
+
+    /// Open an image, going through the cache when it is stored in an archive
+    fn open_image(&mut self, img_path: &Path) -> Result<DynamicImage> {
+        // The sheet is decoded once for all its cells, and again only when it is modified
+        if self.wallpaper_info.grid.is_some() {
+            let path = self.wallpaper_info.path.clone().unwrap();
+            let modified = fs::metadata(&path)
+                .and_then(|metadata| metadata.modified())
+                .with_context(|| format!("reading the modification time of {path:?}"))?;
+            if !matches!(&self.sheet, Some((p, m, _)) if *p == path && *m == modified) {
+                let sheet = image::open(&path)
+                    .with_context(|| format!("opening the sprite sheet {path:?}"))?;
+                let sheet = self.wallpaper_info.assume_color_space(&path, sheet);
+                self.sheet = Some((path, modified, sheet));
+            }
+            let (_, _, sheet) = self.sheet.as_ref().unwrap();
+            return self.wallpaper_info.crop_cell(sheet, img_path);
+        }
+        // Without the archive feature, archives are refused when reading the configuration
+        if self
+            .wallpaper_info
+            .path
+            .as_ref()
+            .map_or(false, |path| is_archive(path))
+        {
+            if let Some(image) = self.archive_cache.get(img_path) {
+                return Ok(image);
+            }
+            let image = self
+                .wallpaper_info
+                .open_image(img_path, self.target_size())?;
+            self.archive_cache
+                .insert(img_path.to_path_buf(), image.clone());
+            return Ok(image);
+        }
+
+        self.wallpaper_info.open_image(img_path, self.target_size())
+    }
+
+    /// The size the images are scaled to
+    fn target_size(&self) -> (u32, u32) {
+        let scale = self.scale as u32;
+        self.frame_size(self.dimensions.0 * scale, self.dimensions.1 * scale)
+    }
+
+    /// Drop the decoded images kept around, freeing their memory
+    pub fn clear_caches(&mut self) {
+        self.archive_cache.clear();
+        self.sheet = None;
+    }
+
+    fn effective_scale(&self) -> i32 {
+        effective_scale(
+            self.wallpaper_info.scale,
+            self.use_scaled_window,
+            self.compositor_scale,
+        )
+    }
+
+    /// Change the image now, as if `duration` had passed, and restart the rotation from it
+    pub fn next(&mut self) -> Result<()> {
+        self.ensure_pool()?;
+        self.timer_expired = true;
+        self.manual_change = true;
+        // The timer already armed would expire too early, add a new one in the next loop
+        self.timer_armed = false;
+        Ok(())
+    }
+
+    /// Apply the effective scale to the surface and redraw it if it has changed
+    pub fn update_scale(&mut self) {
+        let scale = self.effective_scale();
+        // Ignore unnecessary updates
+        if self.scale != scale {
+            // The buffer scale is set when drawing
+            self.scale = scale;
+            self.need_redraw = true;
+        }
+    }
+
+    /// The settings of the output, as configured or changed at runtime, before the directory
+    /// of the current range of `time-ranges` replaced `path`
+    pub fn settings(&self) -> &Arc<WallpaperInfo> {
+        match &self.time_range {
+            Some((_, settings)) => settings,
+            None => &self.wallpaper_info,
+        }
+    }
+
+    /// Choose the images from the directory of the current range of `time-ranges`, changing
+    /// the image at once when a new range starts, and add a timer for the start of the next
+    pub fn update_time_range(&mut self, handle: LoopHandle<Wpaperd>) {
+        let settings = self.settings().clone();
+        if settings.time_ranges.is_empty() {
+            return;
+        }
+        let (minutes, seconds) = time_ranges::local_time();
+        let (start, dir) = match time_ranges::active(&settings.time_ranges, minutes) {
+            Some(range) => range,
+            None => return,
+        };
+        if self.time_range.as_ref().map(|(start, _)| *start) != Some(start) {
+            info!(
+                "{}: choosing the images from {dir:?}, since {}",
+                self.name(),
+                time_ranges::format(start)
+            );
+            self.wallpaper_info = Arc::new(WallpaperInfo {
+                path: Some(dir.to_path_buf()),
+                ..(*settings).clone()
+            });
+            self.time_range = Some((start, settings.clone()));
+            // The image being decoded has been chosen from the previous range
+            self.decoding = None;
+            self.timer_expired = true;
+        }
+        if !self.time_range_timer_armed {
+            self.time_range_timer_armed = true;
+            let name = self.name().to_string();
+            let wait = time_ranges::until_next(&settings.time_ranges, minutes, seconds);
+            handle
+                .insert_source(Timer::from_duration(wait), move |_, _, wpaperd| {
+                    if let Ok(surface) = wpaperd.surface_by_name(&name) {
+                        surface.time_range_timer_armed = false;
+                    }
+                    TimeoutAction::Drop
+                })
+                .expect("Failed to insert event source!");
         }
     }
 
@@ -218,22 +2026,210 @@ impl Surface {
     /// return true if the duration has changed
     pub fn update_wallpaper_info(&mut self, wallpaper_info: Arc<WallpaperInfo>) -> bool {
         let mut duration_changed = false;
-        if self.wallpaper_info != wallpaper_info {
+        if *self.settings() != wallpaper_info {
             if self.wallpaper_info.duration != wallpaper_info.duration {
                 duration_changed = true;
             }
+            if self.wallpaper_info.grid.is_some() && wallpaper_info.grid.is_none() {
+                self.sheet = None;
+            }
+            // The image being decoded has been chosen with the previous configuration
+            self.decoding = None;
+            // The range of `time-ranges` is chosen again in the next loop
+            self.time_range = None;
             self.wallpaper_info = wallpaper_info;
+            self.update_scale();
         }
 
         duration_changed
     }
 
-    pub fn update_duration(&mut self, handle: LoopHandle<Wpaperd>, now: &Instant) {
+    /// Apply the reloaded configuration, after the transition running as set by
+    /// `reload-transition`. Return true if the duration has changed
+    pub fn reload(&mut self, wallpaper_info: Arc<WallpaperInfo>) -> bool {
+        self.pending_reload = None;
+        if self.transition.is_some() && *self.settings() != wallpaper_info {
+            let policy = wallpaper_info
+                .reload_transition
+                .unwrap_or(ReloadTransition::Finish);
+            match reload_transition(&mut self.transition, policy) {
+                Some(pixels) => {
+                    // The next crossfade starts from the end of this one, not from the
+                    // blended frame on screen
+                    if let Some((_, last_pixels)) = &mut self.last_frame {
+                        *last_pixels = pixels;
+                    }
+                    self.need_redraw = true;
+                }
+                None => {
+                    self.pending_reload = Some(wallpaper_info);
+                    return false;
+                }
+            }
+        }
+        self.update_wallpaper_info(wallpaper_info)
+    }
+
+    /// Draw the wallpapers from `path` instead of the configured ones, until the
+    /// configuration is reloaded
+    pub fn apply(&mut self, path: &Path) {
+        let mut wallpaper_info = WallpaperInfo {
+            path: Some(path.to_path_buf()),
+            grid: None,
+            cell_order: None,
+            time_ranges: BTreeMap::new(),
+            ..(*self.wallpaper_info).clone()
+        };
+        if !wallpaper_info.is_pool() {
+            wallpaper_info.duration = None;
+        }
+        if self.update_wallpaper_info(Arc::new(wallpaper_info)) {
+            // Add the timer for the new duration in the next loop
+            self.timer_armed = false;
+        }
+        self.timer_expired = true;
+        self.manual_change = true;
+    }
+
+    /// Draw `source`, chosen for the track played, when it changes; when the playback stops,
+    /// go back to `configured`
+    #[cfg(feature = "media")]
+    pub fn set_media_source(&mut self, source: Option<PathBuf>, configured: Arc<WallpaperInfo>) {
+        if self.media_source == source {
+            return;
+        }
+        match &source {
+            Some(path) => {
+                info!("{}: drawing {path:?} for the track played", self.name());
+                self.apply(path);
+                // The change follows the player, not a command
+                self.manual_change = false;
+            }
+            None => {
+                if self.update_wallpaper_info(configured) {
+                    self.timer_armed = false;
+                }
+                self.timer_expired = true;
+            }
+        }
+        self.media_source = source;
+    }
+
+    /// Draw `source`, chosen for the active workspace, when it changes; on a workspace
+    /// without one, go back to `configured`
+    #[cfg(feature = "workspace")]
+    pub fn set_workspace_source(
+        &mut self,
+        source: Option<PathBuf>,
+        configured: Arc<WallpaperInfo>,
+    ) {
+        if self.workspace_source == source {
+            return;
+        }
+        match &source {
+            Some(path) => {
+                info!("{}: drawing {path:?} for the active workspace", self.name());
+                self.apply(path);
+                // The change follows the workspace, not a command
+                self.manual_change = false;
+            }
+            None => {
+                if self.update_wallpaper_info(configured) {
+                    self.timer_armed = false;
+                }
+                self.timer_expired = true;
+            }
+        }
+        self.workspace_source = source;
+    }
+
+    /// Draw `first` and remember `second`, to switch between them with `toggle_ab`
+    pub fn set_ab(&mut self, first: &Path, second: &Path) {
+        self.apply(first);
+        self.ab_pair = Some((first.to_path_buf(), second.to_path_buf(), false));
+    }
+
+    /// Draw the other image of the pair set with `set_ab` and return it
+    pub fn toggle_ab(&mut self) -> Result<PathBuf> {
+        let name = self.name().to_string();
+        let (first, second, second_drawn) = self
+            .ab_pair
+            .as_mut()
+            .ok_or_else(|| eyre!("no A/B pair has been set for {name}"))?;
+        *second_drawn = !*second_drawn;
+        let path = if *second_drawn { second } else { first }.clone();
+        self.apply(&path);
+        Ok(path)
+    }
+
+    /// Forget the pair set with `set_ab`. Return false if there was none
+    pub fn clear_ab(&mut self) -> bool {
+        self.ab_pair.take().is_some()
+    }
+
+    /// Hold the frame of the animations drawn, without stopping the changes of wallpaper.
+    /// Return false if the output was already frozen
+    pub fn freeze(&mut self) -> bool {
+        !mem::replace(&mut self.frozen, true)
+    }
+
+    /// Play the animations again from the frame held by `freeze`. Return false if the output
+    /// was not frozen
+    pub fn unfreeze(&mut self, now: &Instant) -> bool {
+        if !mem::replace(&mut self.frozen, false) {
+            return false;
+        }
+        if let Some(animation) = &mut self.animation {
+            animation.restart(now);
+        }
+        true
+    }
+
+    /// When `paused` is true, the time until the change of wallpaper stops running; it
+    /// continues from where it was when `paused` is false again. When `defer` is true, it
+    /// waits until `update_duration` is called with `defer` false
+    pub fn update_duration(
+        &mut self,
+        handle: LoopHandle<Wpaperd>,
+        now: &Instant,
+        paused: bool,
+        defer: bool,
+    ) {
+        if paused {
+            self.paused_at.get_or_insert(*now);
+            return;
+        }
+        if let Some(paused_at) = self.paused_at.take() {
+            self.time_changed += now.saturating_duration_since(paused_at);
+            if let Some(duration) = self.wallpaper_info.duration {
+                // Wake up the event loop when the time left has passed
+                let remaining =
+                    duration.saturating_sub(now.saturating_duration_since(self.time_changed));
+                handle
+                    .insert_source(Timer::from_duration(remaining), |_, _, _| {
+                        TimeoutAction::Drop
+                    })
+                    .expect("Failed to insert event source!");
+            }
+        }
         if self.check_duration(now) {
+            if defer {
+                // The duration stays expired, no need to add a timer
+                self.timer_expired = false;
+                return;
+            }
             self.set_next_duration(handle);
         }
     }
 
+    /// When the image is going to change next, if it is rotated and not paused
+    pub fn next_change(&self) -> Option<Instant> {
+        self.wallpaper_info
+            .duration
+            .filter(|_| self.paused_at.is_none())
+            .map(|duration| self.time_changed + duration)
+    }
+
     /// Check if enough time has passed since we have drawn a wallpaper
     pub fn check_duration(&mut self, now: &Instant) -> bool {
         if let Some(duration) = self.wallpaper_info.duration {
@@ -248,7 +2244,8 @@ impl Surface {
     }
 
     /// Add the next timer in the event_loop for the current duration
-    pub(crate) fn set_next_duration(&self, handle: LoopHandle<Wpaperd>) {
+    pub(crate) fn set_next_duration(&mut self, handle: LoopHandle<Wpaperd>) {
+        self.timer_armed = true;
         if let Some(duration) = self.wallpaper_info.duration {
             let timer = Timer::from_duration(duration);
             handle
@@ -262,4 +2259,575 @@ impl Surface {
     pub fn name(&self) -> &str {
         self.info.name.as_ref().unwrap()
     }
+
+    /// Continue from the state of the surface of the same output before it was disconnected,
+    /// drawing its image again instead of choosing a new one. The state of the rotation is
+    /// only kept if the configuration of the output has not changed in the meantime
+    pub fn resume(&mut self, old: Surface) {
+        self.effect = old.effect;
+        self.history = old.history;
+        self.archive_cache = old.archive_cache;
+        if *old.settings() == *self.settings() {
+            self.wallpaper_info = old.wallpaper_info;
+            self.time_range = old.time_range;
+            self.rng = old.rng;
+            self.current_img = old.current_img;
+            self.shown = old.shown;
+            self.pair_img = old.pair_img;
+            self.time_changed = old.time_changed;
+            self.paused_at = old.paused_at;
+            self.crop = old.crop;
+            self.contrast_range = old.contrast_range;
+            self.palette = old.palette;
+            self.timer_expired = false;
+            self.need_redraw = true;
+        }
+    }
+}
+
+/// Return true if the image has more than 8 bits per channel
+fn is_deep(image: &DynamicImage) -> bool {
+    let color = image.color();
+    color.bytes_per_pixel() > color.channel_count()
+}
+
+/// Find the range of luminance in the image, ignoring the `CONTRAST_CLIP` darkest and
+/// brightest pixels
+fn contrast_range(image: &DynamicImage) -> (u8, u8) {
+    let luma = image.to_luma8();
+    let mut histogram = [0_u64; 256];
+    luma.pixels()
+        .for_each(|p| histogram[usize::from(p.0[0])] += 1);
+    let pixels = u64::from(luma.width()) * u64::from(luma.height());
+    let clip = (pixels as f64 * CONTRAST_CLIP) as u64;
+    (
+        clipped_level(&histogram, clip, 0..256),
+        clipped_level(&histogram, clip, (0..256).rev()),
+    )
+}
+
+/// The first of `levels` past the `clip` pixels counted in `histogram`
+fn clipped_level(histogram: &[u64; 256], clip: u64, levels: impl Iterator<Item = usize>) -> u8 {
+    let mut count = 0;
+    for level in levels {
+        count += histogram[level];
+        if count > clip {
+            return level as u8;
+        }
+    }
+    0
+}
+
+/// Map linearly the luminance range `(low, high)` to the whole range, keeping the depth
+fn stretch_contrast(image: DynamicImage, (low, high): (u8, u8)) -> DynamicImage {
+    if high <= low {
+        return image;
+    }
+    let (low, high) = (f32::from(low) / 255.0, f32::from(high) / 255.0);
+    let stretch = |c: f32| ((c - low) / (high - low)).clamp(0.0, 1.0);
+    if is_deep(&image) {
+        let mut image = image.into_rgba16();
+        image.pixels_mut().for_each(|p| {
+            p.apply_without_alpha(|c| (stretch(f32::from(c) / 65535.0) * 65535.0) as u16)
+        });
+        DynamicImage::ImageRgba16(image)
+    } else {
+        let mut image = image.into_rgba8();
+        image
+            .pixels_mut()
+            .for_each(|p| p.apply_without_alpha(|c| (stretch(f32::from(c) / 255.0) * 255.0) as u8));
+        DynamicImage::ImageRgba8(image)
+    }
+}
+
+/// The `scale` set in the configuration takes precedence over `use-scaled-window`, which in
+/// turn takes precedence over the scale advertised by the compositor
+fn effective_scale(configured: Option<i32>, use_scaled_window: bool, compositor_scale: i32) -> i32 {
+    if let Some(scale) = configured {
+        scale.clamp(MIN_SCALE, MAX_SCALE)
+    } else if use_scaled_window {
+        1
+    } else {
+        compositor_scale
+    }
+}
+
+/// The scale of the next buffer and the filter overriding `SCALING_FILTER` for it, if any:
+/// `hq-redraw` draws once at least at the native resolution of the output, with `HQ_FILTER`
+fn redraw_quality(hq_redraw: bool, scale: i32, compositor_scale: i32) -> (i32, Option<FilterType>) {
+    if hq_redraw {
+        (scale.max(compositor_scale), Some(HQ_FILTER))
+    } else {
+        (scale, None)
+    }
+}
+
+/// The maximum size in bytes of a buffer: `max-buffer-size`, in MiB, clamped to the sizes
+/// supported by wl_shm
+fn buffer_limit(max_buffer_size: Option<u64>) -> u64 {
+    max_buffer_size
+        .map_or(u64::MAX, |mib| mib.saturating_mul(1024 * 1024))
+        .min(i32::MAX as u64)
+}
+
+/// Lower `scale` until the buffer of a surface of `dimensions` fits in `max` bytes, but not
+/// below 1. Return the scale and the size of its buffer, still bigger than `max` if even
+/// the scale 1 doesn't fit
+fn fit_scale(dimensions: (u32, u32), scale: i32, max: u64) -> (i32, u64) {
+    let bytes = |scale: i32| {
+        let scale = scale as u64;
+        4 * u64::from(dimensions.0) * scale * u64::from(dimensions.1) * scale
+    };
+    let mut fitted = scale;
+    while fitted > 1 && bytes(fitted) > max {
+        fitted -= 1;
+    }
+    (fitted, bytes(fitted))
+}
+
+/// Generate `pattern` in a buffer of `width`x`height` pixels. The cells of the grid are
+/// `GRID_CELL` logical pixels wide, its lines are always a pixel wide to stay crisp
+fn test_pattern(pattern: TestPattern, width: u32, height: u32, scale: u32) -> RgbaImage {
+    const BARS: [[u8; 3]; 7] = [
+        [255, 255, 255],
+        [255, 255, 0],
+        [0, 255, 255],
+        [0, 255, 0],
+        [255, 0, 255],
+        [255, 0, 0],
+        [0, 0, 255],
+    ];
+    const GRID_CELL: u32 = 64;
+    ImageBuffer::from_fn(width, height, |x, y| {
+        let [r, g, b] = match pattern {
+            TestPattern::Bars => BARS[(x * BARS.len() as u32 / width) as usize],
+            TestPattern::Ramp => {
+                let level = (x * 255 / (width - 1).max(1)) as u8;
+                // A gray ramp on the top half, then red, green and blue ones
+                match y * 6 / height {
+                    0..=2 => [level, level, level],
+                    3 => [level, 0, 0],
+                    4 => [0, level, 0],
+                    _ => [0, 0, level],
+                }
+            }
+            TestPattern::Grid => {
+                let cell = GRID_CELL * scale;
+                let on_line = |c: u32, size: u32| c % cell == 0 || c == size - 1;
+                if on_line(x, width) || on_line(y, height) {
+                    [255, 255, 255]
+                } else {
+                    [0, 0, 0]
+                }
+            }
+        };
+        Rgba([r, g, b, u8::MAX])
+    })
+}
+
+/// Scale the images to the same height, the lowest one, and put them next to each other
+fn side_by_side(left: &DynamicImage, right: &DynamicImage) -> DynamicImage {
+    let height = left.height().min(right.height());
+    let left = left.resize(u32::MAX, height, SCALING_FILTER);
+    let right = right.resize(u32::MAX, height, SCALING_FILTER);
+    let (width, x) = (left.width() + right.width(), i64::from(left.width()));
+    if is_deep(&left) || is_deep(&right) {
+        let mut canvas = ImageBuffer::new(width, height);
+        overlay(&mut canvas, &left.to_rgba16(), 0, 0);
+        overlay(&mut canvas, &right.to_rgba16(), x, 0);
+        DynamicImage::ImageRgba16(canvas)
+    } else {
+        let mut canvas = ImageBuffer::new(width, height);
+        overlay(&mut canvas, &left.to_rgba8(), 0, 0);
+        overlay(&mut canvas, &right.to_rgba8(), x, 0);
+        DynamicImage::ImageRgba8(canvas)
+    }
+}
+
+/// Mix two RGBA buffers of the same size, `progress` going from `from` (0) to `to` (1)
+fn crossfade(from: &[u8], to: &[u8], progress: f32) -> Vec<u8> {
+    from.chunks_exact(4)
+        .zip(to.chunks_exact(4))
+        .flat_map(|(a, b)| blend(a, b, progress))
+        .collect()
+}
+
+/// Mix the RGBA pixels `a` and `b`, `progress` going from `a` (0) to `b` (1). The colors are
+/// weighted by their alpha, so that a transparent pixel, whatever its color, doesn't darken
+/// the other one
+fn blend(a: &[u8], b: &[u8], progress: f32) -> [u8; 4] {
+    let alpha = |pixel: &[u8]| f32::from(pixel[3]) / f32::from(u8::MAX);
+    let (alpha_a, alpha_b) = (alpha(a), alpha(b));
+    let alpha = alpha_a + (alpha_b - alpha_a) * progress;
+    let mut pixel = [0; 4];
+    if alpha > 0.0 {
+        for (channel, (&a, &b)) in pixel.iter_mut().zip(a.iter().zip(b)).take(3) {
+            let (a, b) = (f32::from(a) * alpha_a, f32::from(b) * alpha_b);
+            *channel = ((a + (b - a) * progress) / alpha).round().min(255.0) as u8;
+        }
+    }
+    pixel[3] = (alpha * f32::from(u8::MAX)).round() as u8;
+    pixel
+}
+
+/// Mix the RGBA `pixels` with the opaque `color`, `amount` going from `pixels` (0) to
+/// `color` (1)
+fn fade(pixels: &[u8], color: [u8; 3], amount: f32) -> Vec<u8> {
+    let [r, g, b] = color;
+    pixels
+        .chunks_exact(4)
+        .flat_map(|pixel| blend(pixel, &[r, g, b, u8::MAX], amount))
+        .collect()
+}
+
+/// Center `image` on a canvas of `width`x`height` filled with `color`, keeping its depth
+fn letterbox(image: &DynamicImage, width: u32, height: u32, color: [u8; 3]) -> DynamicImage {
+    let (image_width, image_height) = GenericImageView::dimensions(image);
+    let x = i64::from((width - image_width) / 2);
+    let y = i64::from((height - image_height) / 2);
+    let [r, g, b] = color;
+    if is_deep(image) {
+        let [r, g, b] = [r, g, b].map(|c| u16::from(c) * 257);
+        let mut canvas = ImageBuffer::from_pixel(width, height, Rgba([r, g, b, u16::MAX]));
+        overlay(&mut canvas, &image.to_rgba16(), x, y);
+        DynamicImage::ImageRgba16(canvas)
+    } else {
+        let mut canvas = ImageBuffer::from_pixel(width, height, Rgba([r, g, b, u8::MAX]));
+        overlay(&mut canvas, &image.to_rgba8(), x, y);
+        DynamicImage::ImageRgba8(canvas)
+    }
+}
+
+/// Apply the effects of each region on the part of `image` it covers, in order. The regions
+/// are in logical pixels and are clipped to the image
+fn region_effects(image: DynamicImage, regions: &[RegionEffect], scale: u32) -> DynamicImage {
+    if is_deep(&image) {
+        let mut image = image.into_rgba16();
+        for region in regions {
+            region_effect(&mut image, region, scale, |p: &mut Rgba<u16>, factor| {
+                p.apply_without_alpha(|c| (c as f32 * factor) as u16)
+            });
+        }
+        DynamicImage::ImageRgba16(image)
+    } else {
+        let mut image = image.into_rgba8();
+        for region in regions {
+            region_effect(&mut image, region, scale, |p: &mut Rgba<u8>, factor| {
+                p.apply_without_alpha(|c| (c as f32 * factor) as u8)
+            });
+        }
+        DynamicImage::ImageRgba8(image)
+    }
+}
+
+/// Blur and then dim the region of `image`, scaling it by the multiplier passed to `dim`
+fn region_effect<P: Pixel + 'static>(
+    image: &mut ImageBuffer<P, Vec<P::Subpixel>>,
+    region: &RegionEffect,
+    scale: u32,
+    dim: impl Fn(&mut P, f32),
+) {
+    let (width, height) = image.dimensions();
+    let x = region.x.saturating_mul(scale).min(width);
+    let y = region.y.saturating_mul(scale).min(height);
+    let region_width = region.width.saturating_mul(scale).min(width - x);
+    let region_height = region.height.saturating_mul(scale).min(height - y);
+    if region_width == 0 || region_height == 0 {
+        return;
+    }
+    let mut area = imageops::crop_imm(&*image, x, y, region_width, region_height).to_image();
+    if let Some(sigma) = region.blur {
+        area = imageops::blur(&area, sigma * scale as f32);
+    }
+    if let Some(amount) = region.dim {
+        area.pixels_mut().for_each(|p| dim(p, 1.0 - amount));
+    }
+    imageops::replace(image, &area, i64::from(x), i64::from(y));
+}
+
+/// The largest region in the center of the image with the aspect ratio of the surface
+fn fill_crop(image_width: u32, image_height: u32, width: u32, height: u32) -> Crop {
+    let ratio = f64::from(width) / f64::from(height);
+    let (crop_width, crop_height) = if f64::from(image_width) / f64::from(image_height) > ratio {
+        ((f64::from(image_height) * ratio) as u32, image_height)
+    } else {
+        (image_width, (f64::from(image_width) / ratio) as u32)
+    };
+    Crop {
+        x: (image_width - crop_width) / 2,
+        y: (image_height - crop_height) / 2,
+        width: crop_width.max(1),
+        height: crop_height.max(1),
+    }
+}
+
+/// Adapt `crop` to the aspect ratio of the surface, keeping its center and its height (so
+/// that the image stays at the same zoom) unless the image is not big enough
+fn preserve_crop(
+    crop: &Crop,
+    image_width: u32,
+    image_height: u32,
+    width: u32,
+    height: u32,
+) -> Crop {
+    let ratio = f64::from(width) / f64::from(height);
+    let mut crop_height = f64::from(crop.height);
+    let mut crop_width = crop_height * ratio;
+    if crop_width > f64::from(image_width) {
+        crop_width = f64::from(image_width);
+        crop_height = crop_width / ratio;
+    }
+    let (crop_width, crop_height) = (
+        (crop_width as u32).max(1),
+        (crop_height as u32).clamp(1, image_height),
+    );
+    let center_x = crop.x + crop.width / 2;
+    let center_y = crop.y + crop.height / 2;
+    Crop {
+        x: center_x
+            .saturating_sub(crop_width / 2)
+            .min(image_width - crop_width),
+        y: center_y
+            .saturating_sub(crop_height / 2)
+            .min(image_height - crop_height),
+        width: crop_width,
+        height: crop_height,
+    }
+}
+
+/// Pack the pixels in 32 bit little endian words, with 10 bits per color channel
+fn pack_10bit(image: &ImageBuffer<Rgba<u16>, Vec<u16>>, format: wl_shm::Format) -> Vec<u8> {
+    image
+        .pixels()
+        .flat_map(|p| {
+            let [r, g, b, _] = p.0.map(|c| u32::from(c >> 6));
+            let word = if format == wl_shm::Format::Xrgb2101010 {
+                (r << 20) | (g << 10) | b
+            } else {
+                (b << 20) | (g << 10) | r
+            };
+            word.to_le_bytes()
+        })
+        .collect()
+}
+
+/// Quantize the pixels to 8 bits per channel, adding the 4x4 Bayer matrix as threshold
+fn ordered_dither(image: &ImageBuffer<Rgba<u16>, Vec<u16>>) -> Vec<u8> {
+    const BAYER: [[u8; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+    image
+        .enumerate_pixels()
+        .flat_map(|(x, y, p)| {
+            let threshold = (f32::from(BAYER[y as usize % 4][x as usize % 4]) + 0.5) / 16.0;
+            let [r, g, b, a] = p.0;
+            let quantize = |c: u16| (f32::from(c) / 257.0 + threshold).min(255.0) as u8;
+            [quantize(r), quantize(g), quantize(b), (a >> 8) as u8]
+        })
+        .collect()
+}
+
+/// Quantize the pixels to 8 bits per channel, spreading the error of each one on the next
+/// pixels with the Floyd-Steinberg weights
+fn error_diffusion(image: &ImageBuffer<Rgba<u16>, Vec<u16>>) -> Vec<u8> {
+    let width = image.width() as usize;
+    // The errors for the current and the next row, with a pixel of padding on each side
+    let mut current = vec![[0.0_f32; 3]; width + 2];
+    let mut next = vec![[0.0_f32; 3]; width + 2];
+    let mut pixels = Vec::with_capacity(image.as_raw().len());
+    for row in image.rows() {
+        for (x, p) in row.enumerate() {
+            for (channel, c) in p.0[..3].iter().enumerate() {
+                let value = f32::from(*c) / 257.0 + current[x + 1][channel];
+                let quantized = value.round().clamp(0.0, 255.0);
+                let error = value - quantized;
+                current[x + 2][channel] += error * 7.0 / 16.0;
+                next[x][channel] += error * 3.0 / 16.0;
+                next[x + 1][channel] += error * 5.0 / 16.0;
+                next[x + 2][channel] += error / 16.0;
+                pixels.push(quantized as u8);
+            }
+            pixels.push((p.0[3] >> 8) as u8);
+        }
+        std::mem::swap(&mut current, &mut next);
+        next.iter_mut().for_each(|error| *error = [0.0; 3]);
+    }
+    pixels
+}
+
+/// Format a duration in a compact, human readable form, e.g. `1h 5m`
+pub fn format_age(age: Duration) -> String {
+    let secs = age.as_secs();
+    if secs < 60 {
+        format!("{secs}s")
+    } else if secs < 60 * 60 {
+        format!("{}m", secs / 60)
+    } else {
+        format!("{}h {}m", secs / (60 * 60), secs / 60 % 60)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: u64 = 1024 * 1024;
+
+    #[test]
+    fn scale_override() {
+        // Without an override, the scale of the compositor is used
+        assert_eq!(effective_scale(None, false, 2), 2);
+        assert_eq!(effective_scale(None, true, 2), 1);
+        // The configured scale takes precedence over both
+        assert_eq!(effective_scale(Some(3), false, 1), 3);
+        assert_eq!(effective_scale(Some(2), true, 1), 2);
+        assert_eq!(effective_scale(Some(1), false, 2), 1);
+    }
+
+    #[test]
+    fn scale_override_clamped() {
+        assert_eq!(effective_scale(Some(0), false, 2), MIN_SCALE);
+        assert_eq!(effective_scale(Some(-3), false, 2), MIN_SCALE);
+        assert_eq!(effective_scale(Some(MAX_SCALE + 1), false, 1), MAX_SCALE);
+        assert_eq!(effective_scale(Some(i32::MAX), true, 1), MAX_SCALE);
+    }
+
+    #[test]
+    fn hq_redraw_quality() {
+        assert_eq!(redraw_quality(false, 1, 2), (1, None));
+        // Drawn at the native resolution, with the best filter
+        assert_eq!(redraw_quality(true, 1, 2), (2, Some(FilterType::Lanczos3)));
+        // The scale is never lowered
+        assert_eq!(redraw_quality(true, 3, 2), (3, Some(FilterType::Lanczos3)));
+    }
+
+    #[test]
+    fn fit_scale_integer() {
+        let max = buffer_limit(None);
+        assert_eq!(fit_scale((1920, 1080), 1, max), (1, 4 * 1920 * 1080));
+        assert_eq!(fit_scale((1920, 1080), 2, max), (2, 4 * 3840 * 2160));
+        // 20 MiB fit a 1080p buffer, but not a 4K one
+        assert_eq!(
+            fit_scale((1920, 1080), 2, buffer_limit(Some(20))),
+            (1, 4 * 1920 * 1080)
+        );
+        assert_eq!(
+            fit_scale((1920, 1080), 3, buffer_limit(Some(32))),
+            (2, 4 * 3840 * 2160)
+        );
+    }
+
+    #[test]
+    fn fit_scale_fractional() {
+        // A 2560x1600 output at the scale 1.5 has a logical size of 1707x1067, and the
+        // compositor advertises the scale rounded up
+        let max = buffer_limit(None);
+        assert_eq!(fit_scale((1707, 1067), 2, max), (2, 4 * 3414 * 2134));
+        assert_eq!(
+            fit_scale((1707, 1067), 2, buffer_limit(Some(16))),
+            (1, 4 * 1707 * 1067)
+        );
+    }
+
+    #[test]
+    fn buffer_limit_clamped() {
+        assert_eq!(buffer_limit(None), i32::MAX as u64);
+        assert_eq!(buffer_limit(Some(u64::MAX)), i32::MAX as u64);
+        assert_eq!(buffer_limit(Some(4096)), i32::MAX as u64);
+        assert_eq!(buffer_limit(Some(64)), 64 * MIB);
+        assert_eq!(buffer_limit(Some(0)), 0);
+        // A 1440p output at the scale 3 needs a 7680x4320 buffer, bigger than 64 MiB
+        let (width, height) = (2560, 1440);
+        let (scale, bytes) = fit_scale((width, height), 3, buffer_limit(Some(64)));
+        let clamped = (width * scale as u32, height * scale as u32);
+        assert_eq!(clamped, (5120, 2880));
+        assert!(bytes <= 64 * MIB);
+        // The buffer is scaled down as a whole, keeping the aspect ratio of the output
+        assert_eq!(clamped.0 * height, clamped.1 * width);
+    }
+
+    #[test]
+    fn fit_scale_clamped() {
+        // An 8K output at the scale 5 is bigger than what wl_shm supports
+        assert_eq!(
+            fit_scale((7680, 4320), 5, buffer_limit(None)),
+            (4, 4 * 30720 * 17280)
+        );
+        // The scale is never lowered below 1, even if the buffer doesn't fit
+        let (scale, bytes) = fit_scale((1920, 1080), 3, buffer_limit(Some(1)));
+        assert_eq!(scale, 1);
+        assert!(bytes > MIB);
+    }
+
+    #[test]
+    fn reload_mid_transition() {
+        let now = Instant::now();
+        let black = [0, 0, 0, 255].repeat(4);
+        let white = vec![255; 16];
+        let transition = || Transition {
+            from: black.clone(),
+            to: white.clone(),
+            started: now,
+            duration: Duration::from_millis(500),
+            dip: None,
+        };
+        let middle = now + Duration::from_millis(250);
+
+        // Snapping ends the transition with its last frame, not the blended one on screen
+        let mut snapped = Some(transition());
+        assert_ne!(snapped.as_ref().unwrap().step(&middle), Some(white.clone()));
+        assert_eq!(
+            reload_transition(&mut snapped, ReloadTransition::Snap),
+            Some(white.clone())
+        );
+        assert!(snapped.is_none());
+
+        // Finishing keeps it running until the last frame is drawn
+        let mut finished = Some(transition());
+        assert_eq!(
+            reload_transition(&mut finished, ReloadTransition::Finish),
+            None
+        );
+        let finished = finished.unwrap();
+        assert!(finished.step(&middle).is_some());
+        assert_eq!(finished.step(&(now + Duration::from_millis(500))), None);
+        assert_eq!(finished.to, white);
+    }
+
+    #[test]
+    fn dip_through_color() {
+        let now = Instant::now();
+        let red = [255, 0, 0, 255].repeat(4);
+        let blue = [0, 0, 255, 255].repeat(4);
+        let transition = Transition {
+            from: red,
+            to: blue.clone(),
+            started: now,
+            duration: Duration::from_secs(4),
+            dip: Some([0, 0, 0]),
+        };
+        let at = |secs| transition.step(&(now + Duration::from_secs(secs)));
+
+        // Halfway through each half, the image is mixed with the color; in the middle only
+        // the color is drawn
+        assert_eq!(at(1), Some([128, 0, 0, 255].repeat(4)));
+        assert_eq!(at(2), Some([0, 0, 0, 255].repeat(4)));
+        assert_eq!(at(3), Some([0, 0, 128, 255].repeat(4)));
+        assert_eq!(at(4), None);
+        assert_eq!(transition.to, blue);
+    }
+
+    #[test]
+    fn crossfade_midpoint() {
+        // The midpoint of two opaque colors is their average
+        let red = [255, 0, 0, 255].repeat(4);
+        let blue = [0, 0, 255, 255].repeat(4);
+        assert_eq!(crossfade(&red, &blue, 0.5), [128, 0, 128, 255].repeat(4));
+
+        // A transparent pixel only changes the alpha, its color doesn't bleed in
+        let clear = [0, 0, 0, 0].repeat(4);
+        assert_eq!(crossfade(&red, &clear, 0.5), [255, 0, 0, 128].repeat(4));
+        assert_eq!(crossfade(&clear, &blue, 0.5), [0, 0, 255, 128].repeat(4));
+        let half_green = [0, 255, 0, 128].repeat(4);
+        assert_eq!(crossfade(&clear, &half_green, 1.0), half_green);
+        assert_eq!(crossfade(&half_green, &clear, 0.0), half_green);
+    }
 }
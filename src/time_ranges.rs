@@ -0,0 +1,160 @@
+use std::{
+    collections::BTreeMap,
+    mem,
+    path::{Path, PathBuf},
+    ptr,
+    time::Duration,
+};
+
+use dirs::home_dir;
+use nix::libc;
+use serde::{Deserialize, Serializer};
+
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+/// The range of `ranges` that `minutes` since midnight falls in, as the minute it starts at
+/// and its directory. Before the first range starts, the last one of the previous day lasts
+pub fn active(ranges: &BTreeMap<u32, PathBuf>, minutes: u32) -> Option<(u32, &Path)> {
+    ranges
+        .range(..=minutes)
+        .next_back()
+        .or_else(|| ranges.iter().next_back())
+        .map(|(start, dir)| (*start, dir.as_path()))
+}
+
+/// How long from `minutes` and `seconds` since midnight until the next range starts
+pub fn until_next(ranges: &BTreeMap<u32, PathBuf>, minutes: u32, seconds: u32) -> Duration {
+    let next = ranges
+        .range(minutes + 1..)
+        .next()
+        .or_else(|| ranges.iter().next())
+        .map_or(MINUTES_PER_DAY, |(start, _)| *start);
+    let wait = (next + MINUTES_PER_DAY - minutes - 1) % MINUTES_PER_DAY + 1;
+    Duration::from_secs(u64::from(wait) * 60 - u64::from(seconds))
+}
+
+/// The local time, as the minutes since midnight and the seconds into the current minute
+pub fn local_time() -> (u32, u32) {
+    // SAFETY: localtime_r only writes into `tm`, all zeroes is a valid value for it
+    let tm = unsafe {
+        let now = libc::time(ptr::null_mut());
+        let mut tm: libc::tm = mem::zeroed();
+        libc::localtime_r(&now, &mut tm);
+        tm
+    };
+    let minutes = (tm.tm_hour * 60 + tm.tm_min) as u32 % MINUTES_PER_DAY;
+    // A leap second counts as the last one of the minute
+    (minutes, tm.tm_sec.min(59) as u32)
+}
+
+/// Format the minutes since midnight as `HH:MM`
+pub fn format(minutes: u32) -> String {
+    format!("{:02}:{:02}", minutes / 60, minutes % 60)
+}
+
+/// Parse `HH:MM` as the minutes since midnight
+fn parse(time: &str) -> Option<u32> {
+    let (hours, minutes) = time.split_once(':')?;
+    if hours.len() != 2 || minutes.len() != 2 {
+        return None;
+    }
+    let (hours, minutes): (u32, u32) = (hours.parse().ok()?, minutes.parse().ok()?);
+    (hours < 24 && minutes < 60).then(|| hours * 60 + minutes)
+}
+
+/// Read the table of `time-ranges`, from the `HH:MM` each range starts at to its directory
+pub fn deserialize<'de, D>(deserializer: D) -> Result<BTreeMap<u32, PathBuf>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let ranges = BTreeMap::<String, PathBuf>::deserialize(deserializer)?;
+    ranges
+        .into_iter()
+        .map(|(time, dir)| {
+            let start = parse(&time).ok_or_else(|| {
+                <D::Error as serde::de::Error>::custom(format!(
+                    "invalid time {time:?} in `time-ranges`, use HH:MM"
+                ))
+            })?;
+            let dir = dir
+                .strip_prefix("~")
+                .map_or(dir.clone(), |p| home_dir().unwrap().join(p));
+            Ok((start, dir))
+        })
+        .collect()
+}
+
+pub fn serialize<S>(ranges: &BTreeMap<u32, PathBuf>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.collect_map(ranges.iter().map(|(start, dir)| (format(*start), dir)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranges() -> BTreeMap<u32, PathBuf> {
+        [(7 * 60, "morning"), (19 * 60, "evening")]
+            .into_iter()
+            .map(|(start, dir)| (start, PathBuf::from(dir)))
+            .collect()
+    }
+
+    #[test]
+    fn active_range() {
+        let ranges = ranges();
+        assert_eq!(
+            active(&ranges, 7 * 60),
+            Some((7 * 60, Path::new("morning")))
+        );
+        assert_eq!(
+            active(&ranges, 12 * 60),
+            Some((7 * 60, Path::new("morning")))
+        );
+        assert_eq!(
+            active(&ranges, 23 * 60),
+            Some((19 * 60, Path::new("evening")))
+        );
+        // The evening range lasts until the morning
+        assert_eq!(
+            active(&ranges, 3 * 60),
+            Some((19 * 60, Path::new("evening")))
+        );
+        assert_eq!(active(&BTreeMap::new(), 0), None);
+    }
+
+    #[test]
+    fn until_next_range() {
+        let ranges = ranges();
+        assert_eq!(
+            until_next(&ranges, 7 * 60, 0),
+            Duration::from_secs(12 * 3600)
+        );
+        assert_eq!(
+            until_next(&ranges, 18 * 60 + 59, 30),
+            Duration::from_secs(30)
+        );
+        assert_eq!(
+            until_next(&ranges, 23 * 60, 0),
+            Duration::from_secs(8 * 3600)
+        );
+        // With a single range, it starts again the next day
+        let single = [(7 * 60, PathBuf::from("all"))].into_iter().collect();
+        assert_eq!(
+            until_next(&single, 7 * 60, 0),
+            Duration::from_secs(24 * 3600)
+        );
+    }
+
+    #[test]
+    fn parse_time() {
+        assert_eq!(parse("00:00"), Some(0));
+        assert_eq!(parse("07:30"), Some(7 * 60 + 30));
+        assert_eq!(parse("23:59"), Some(MINUTES_PER_DAY - 1));
+        assert_eq!(parse("24:00"), None);
+        assert_eq!(parse("7:30"), None);
+        assert_eq!(parse("07:60"), None);
+    }
+}
@@ -5,12 +5,25 @@ use std::{
     sync::Arc,
 };
 
-use color_eyre::{eyre::ensure, Result};
+use color_eyre::{
+    eyre::{bail, ensure, eyre, WrapErr},
+    Result,
+};
+use image::image_dimensions;
 use serde::Deserialize;
+use toml_edit::{Document, Item};
+
+use crate::archive::is_archive;
+#[cfg(feature = "shader")]
+use crate::shader::Shader;
+use crate::sqlite::is_index;
+use crate::time_ranges;
+use crate::wallpaper_info::{Mode, PathType, WallpaperInfo};
 
-use crate::wallpaper_info::WallpaperInfo;
+/// The (rows, columns) of the mosaic, when `mosaic-grid` is not set
+pub const DEFAULT_MOSAIC_GRID: (u32, u32) = (3, 3);
 
-#[derive(Deserialize, PartialEq)]
+#[derive(Deserialize)]
 pub struct WallpaperConfig {
     #[serde(flatten)]
     data: HashMap<String, Arc<WallpaperInfo>>,
@@ -18,6 +31,8 @@ pub struct WallpaperConfig {
     default_config: Arc<WallpaperInfo>,
     #[serde(skip)]
     pub path: PathBuf,
+    /// Set when the file has been read again, until the surfaces have been updated. The
+    /// surfaces created in the meantime read the new configuration when they are created
     #[serde(skip)]
     pub reloaded: bool,
 }
@@ -32,24 +47,379 @@ impl WallpaperConfig {
             .unwrap_or(&Arc::new(WallpaperInfo::default()))
             .clone();
         for (name, config) in &config_manager.data {
-            let path = config.path.as_ref().unwrap();
             ensure!(
-                path.exists(),
-                "File or directory {path:?} for input {name} does not exist"
+                config.max_commit_fps != Some(0),
+                "for input '{name}', `max-commit-fps` must be greater than 0"
+            );
+            ensure!(
+                config.shader_fps != Some(0),
+                "for input '{name}', `shader-fps` must be greater than 0"
+            );
+            #[cfg(feature = "shader")]
+            if let Some(shader) = &config.shader {
+                Shader::new(shader)
+                    .with_context(|| format!("for input '{name}', loading `shader`"))?;
+            }
+            #[cfg(not(feature = "color-management"))]
+            ensure!(
+                config.assume_color_space.is_none(),
+                "for input '{name}', `assume-color-space` is set but wpaperd has been built without the `color-management` feature"
+            );
+            #[cfg(not(feature = "shader"))]
+            ensure!(
+                config.shader.is_none(),
+                "for input '{name}', `shader` is set but wpaperd has been built without the `shader` feature"
+            );
+            for (start, dir) in &config.time_ranges {
+                ensure!(
+                    config.path.is_none(),
+                    "for input '{name}', `path` and `time-ranges` can't be both set"
+                );
+                ensure!(
+                    dir.is_dir(),
+                    "for input '{name}', the range starting at {} in `time-ranges` is set to {dir:?}, that is not a directory",
+                    time_ranges::format(*start)
+                );
+            }
+            for (genre, dir) in &config.media_genres {
+                ensure!(
+                    dir.is_dir(),
+                    "for input '{name}', the genre {genre:?} in `media-genres` is set to {dir:?}, that is not a directory"
+                );
+            }
+            for (workspace, path) in &config.workspaces {
+                ensure!(
+                    path.exists(),
+                    "for input '{name}', the workspace {workspace:?} in `workspaces` is set to {path:?}, that does not exist"
+                );
+            }
+            #[cfg(not(feature = "workspace"))]
+            ensure!(
+                config.workspaces.is_empty(),
+                "for input '{name}', `workspaces` is set but wpaperd has been built without the `workspace` feature"
+            );
+            #[cfg(not(feature = "media"))]
+            ensure!(
+                config.media_art.is_none() && config.media_genres.is_empty(),
+                "for input '{name}', `media-art` or `media-genres` is set but wpaperd has been built without the `media` feature"
+            );
+            let path = match &config.path {
+                Some(path) => path,
+                // The test pattern is drawn instead of the images, the directories of the
+                // time ranges instead of `path`
+                None if config.test_pattern.is_some() || !config.time_ranges.is_empty() => continue,
+                None => bail!("for input '{name}', `path` is not set"),
+            };
+            match (PathType::detect(path), config.path_type) {
+                // The FIFO can be created later by the program writing into it
+                (None, Some(PathType::Fifo)) => {}
+                (None, _) => bail!(
+                    "for input '{name}', `path` is set to {path:?}, that does not exist.
+Fix the path or, for a FIFO created later, set `type = \"fifo\"`"
+                ),
+                (Some(detected), Some(path_type)) if detected != path_type => bail!(
+                    "for input '{name}', `type` is set to {} but {path:?} is {}",
+                    path_type.describe(),
+                    detected.describe()
+                ),
+                _ => {}
+            }
+            #[cfg(not(feature = "archive"))]
+            ensure!(
+                !is_archive(path),
+                "for input '{name}', `path` is set to the archive {path:?} but wpaperd has been built without the `archive` feature"
             );
+            #[cfg(not(feature = "sqlite"))]
             ensure!(
-                config.duration.is_none() || path.is_dir(),
+                !is_index(path),
+                "for input '{name}', `path` is set to the SQLite index {path:?} but wpaperd has been built without the `sqlite` feature"
+            );
+            ensure!(
+                is_index(path) == config.index.is_some(),
+                "for input '{name}', `index` must be set if and only if `path` is an SQLite index"
+            );
+            if let Some((rows, cols)) = config.grid {
+                ensure!(
+                    path.is_file() && !is_archive(path),
+                    "for input '{name}', `grid` is set but `path` is not an image"
+                );
+                ensure!(
+                    rows > 0 && cols > 0,
+                    "for input '{name}', `grid` must have at least a row and a column"
+                );
+                let (width, height) = image_dimensions(path)
+                    .with_context(|| format!("reading the dimensions of the image {path:?}"))?;
+                ensure!(
+                    width % cols == 0 && height % rows == 0,
+                    "for input '{name}', the grid {rows}x{cols} does not divide the image {path:?} ({width}x{height}) evenly"
+                );
+                if let Some(cell_order) = &config.cell_order {
+                    ensure!(
+                        cell_order.iter().all(|cell| *cell < rows * cols),
+                        "for input '{name}', `cell-order` contains a cell outside of the grid"
+                    );
+                }
+            }
+            for (extension, command) in &config.decoder_command {
+                ensure!(
+                    !command.trim().is_empty(),
+                    "for input '{name}', the decoder command for {extension:?} is empty"
+                );
+            }
+            if config.mode == Some(Mode::Mosaic) {
+                ensure!(
+                    config.is_pool(),
+                    "for input '{name}', `mode` is set to mosaic but `path` is not a pool of images"
+                );
+                let (rows, cols) = config.mosaic_grid.unwrap_or(DEFAULT_MOSAIC_GRID);
+                ensure!(
+                    rows > 0 && cols > 0,
+                    "for input '{name}', `mosaic-grid` must have at least a row and a column"
+                );
+                ensure!(
+                    config.mosaic_count != Some(0),
+                    "for input '{name}', `mosaic-count` must be greater than 0"
+                );
+            }
+            for region in &config.region_effects {
+                ensure!(
+                    region.width > 0 && region.height > 0,
+                    "for input '{name}', the regions of `region-effects` must be at least 1x1"
+                );
+                ensure!(
+                    region.blur.is_some() || region.dim.is_some(),
+                    "for input '{name}', each region of `region-effects` needs `blur` or `dim`"
+                );
+                ensure!(
+                    region.blur.map_or(true, |sigma| sigma > 0.0),
+                    "for input '{name}', the `blur` of `region-effects` must be greater than 0"
+                );
+                ensure!(
+                    region
+                        .dim
+                        .map_or(true, |amount| (0.0..=1.0).contains(&amount)),
+                    "for input '{name}', the `dim` of `region-effects` must be between 0 and 1"
+                );
+            }
+            if let Some(cohesion) = config.cohesion {
+                ensure!(
+                    config.is_pool(),
+                    "for input '{name}', `cohesion` is set but `path` is not a pool of images"
+                );
+                ensure!(
+                    (0.0..=1.0).contains(&cohesion),
+                    "for input '{name}', `cohesion` must be between 0 and 1"
+                );
+            }
+            if let Some((width, height)) = config.frame_size {
+                ensure!(
+                    width > 0 && height > 0,
+                    "for input '{name}', `frame-size` must be at least 1x1"
+                );
+            }
+            ensure!(
+                config.duration.is_none() || config.is_pool(),
                 "for input '{name}', `path` is set to an image but `duration` is also set.
-Either remove `duration` or set `path` to a directory"
+Either remove `duration` or set `path` to a directory, an archive, a FIFO, an SQLite index or set `grid`"
             );
         }
 
         config_manager.path = path.to_path_buf();
-        config_manager.reloaded = true;
         Ok(config_manager)
     }
 
+    /// Replace the configuration with the one read again from the file, if they differ, and
+    /// mark it as reloaded. Return true if it has been replaced
+    pub fn reload(&mut self, new_config: Self) -> bool {
+        if new_config.data == self.data {
+            return false;
+        }
+        *self = Self {
+            reloaded: true,
+            ..new_config
+        };
+        true
+    }
+
+    /// Return the sections of the configuration, sorted by name
+    pub fn sections(&self) -> Vec<(&str, &WallpaperInfo)> {
+        let mut sections: Vec<(&str, &WallpaperInfo)> = self
+            .data
+            .iter()
+            .map(|(name, info)| (name.as_str(), info.as_ref()))
+            .collect();
+        sections.sort_by_key(|(name, _)| *name);
+        sections
+    }
+
+    /// Return true if the configuration has a section for the output `name`
+    pub fn has_section(&self, name: &str) -> bool {
+        self.data.contains_key(name)
+    }
+
     pub fn get_output_by_name(&self, name: &str) -> Arc<WallpaperInfo> {
         self.data.get(name).unwrap_or(&self.default_config).clone()
     }
+
+    /// Write the settings of the outputs in `outputs` to the configuration file. The file is
+    /// edited in place: only the settings that changed are written, the rest of the file,
+    /// with its comments and formatting, is kept
+    pub fn save(&self, outputs: &[(&str, &WallpaperInfo)]) -> Result<()> {
+        let content = fs::read_to_string(&self.path)
+            .with_context(|| format!("reading the configuration {:?}", self.path))?;
+        let mut document: Document = content
+            .parse()
+            .with_context(|| format!("parsing the configuration {:?}", self.path))?;
+
+        for (name, info) in outputs {
+            let settings = to_table(info)?;
+            // The settings the output has been configured with, as they have been read
+            let old = match self.data.get(*name) {
+                Some(old) => to_table(old)?,
+                None => to_table(&WallpaperInfo::default())?,
+            };
+            let section = document
+                .entry(name)
+                .or_insert_with(toml_edit::table)
+                .as_table_like_mut()
+                .ok_or_else(|| eyre!("`{name}` in the configuration is not a section"))?;
+
+            for (key, value) in &settings {
+                if old.get(key) == Some(value) {
+                    continue;
+                }
+                let mut item = to_item(key, value)?;
+                // Keep the comment at the end of the line, if any
+                if let (Some(old_item), Item::Value(new_value)) = (section.get(key), &mut item) {
+                    if let Some(old_value) = old_item.as_value() {
+                        *new_value.decor_mut() = old_value.decor().clone();
+                    }
+                }
+                section.insert(key, item);
+            }
+            for key in old.keys().filter(|key| !settings.contains_key(*key)) {
+                section.remove(key);
+            }
+        }
+
+        fs::write(&self.path, document.to_string())
+            .with_context(|| format!("writing the configuration to {:?}", self.path))
+    }
+}
+
+/// Serialize the settings of an output as a TOML table
+fn to_table(info: &WallpaperInfo) -> Result<toml::Table> {
+    match toml::Value::try_from(info).context("serializing the configuration")? {
+        toml::Value::Table(table) => Ok(table),
+        _ => bail!("the settings of an output must be serialized as a table"),
+    }
+}
+
+/// Convert the setting `key` to an item of the document; tables are written inline, so that
+/// the setting stays in the section of its output
+fn to_item(key: &str, value: &toml::Value) -> Result<Item> {
+    let mut table = toml::Table::new();
+    table.insert(key.to_string(), value.clone());
+    let document: Document = toml::to_string(&table)
+        .context("serializing the configuration")?
+        .parse()
+        .context("parsing the serialized configuration")?;
+    let item = document
+        .get(key)
+        .cloned()
+        .ok_or_else(|| eyre!("serializing the setting `{key}`"))?;
+    Ok(item
+        .into_value()
+        .map(Item::Value)
+        .unwrap_or_else(|item| item))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::wallpaper_info::TestPattern;
+
+    /// Write `content` as the configuration file of a new temporary directory; `$DIR` is
+    /// replaced by the directory, so that it can be used as the `path` of an output
+    fn config_file(test: &str, content: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("wpaperd-{test}-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("wallpaper.toml");
+        fs::write(&path, content.replace("$DIR", dir.to_str().unwrap())).unwrap();
+        path
+    }
+
+    #[test]
+    fn save_keeps_comments() {
+        let path = config_file(
+            "save",
+            "# My wallpapers\n[eDP-1]\npath = \"$DIR\" # the pictures\nduration = \"30m\" # slow\n",
+        );
+        let config = WallpaperConfig::new_from_path(&path).unwrap();
+        let mut info = (*config.get_output_by_name("eDP-1")).clone();
+        info.duration = Some(Duration::from_secs(60));
+        config.save(&[("eDP-1", &info)]).unwrap();
+
+        let saved = fs::read_to_string(&path).unwrap();
+        let dir = path.parent().unwrap().to_str().unwrap();
+        assert_eq!(
+            saved,
+            format!("# My wallpapers\n[eDP-1]\npath = \"{dir}\" # the pictures\nduration = \"1m\" # slow\n")
+        );
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn reloaded_only_when_changed() {
+        let path = config_file("reload", "[default]\npath = \"$DIR\"\n");
+        let mut config = WallpaperConfig::new_from_path(&path).unwrap();
+        assert!(!config.reloaded);
+
+        // The file has been saved without changes
+        let same = WallpaperConfig::new_from_path(&path).unwrap();
+        assert!(!config.reload(same));
+        assert!(!config.reloaded);
+
+        fs::write(
+            &path,
+            fs::read_to_string(&path).unwrap() + "duration = \"10m\"\n",
+        )
+        .unwrap();
+        let changed = WallpaperConfig::new_from_path(&path).unwrap();
+        assert!(config.reload(changed));
+        assert!(config.reloaded);
+        assert_eq!(
+            config.get_output_by_name("eDP-1").duration,
+            Some(Duration::from_secs(600))
+        );
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn time_ranges_without_path() {
+        let path = config_file(
+            "time-ranges",
+            "[eDP-1]\ntime-ranges = { \"07:00\" = \"$DIR\", \"19:00\" = \"$DIR\" }\n",
+        );
+        let config = WallpaperConfig::new_from_path(&path).unwrap();
+        let info = config.get_output_by_name("eDP-1");
+        assert_eq!(info.path, None);
+        assert_eq!(
+            info.time_ranges.keys().copied().collect::<Vec<_>>(),
+            [7 * 60, 19 * 60]
+        );
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_pattern_without_path() {
+        let path = config_file("test-pattern", "[eDP-1]\ntest-pattern = \"grid\"\n");
+        let config = WallpaperConfig::new_from_path(&path).unwrap();
+        let info = config.get_output_by_name("eDP-1");
+        assert_eq!(info.path, None);
+        assert_eq!(info.test_pattern, Some(TestPattern::Grid));
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
 }
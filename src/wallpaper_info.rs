@@ -1,17 +1,509 @@
 use dirs::home_dir;
-use std::{path::Path, path::PathBuf, time::Duration};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+    path::PathBuf,
+    process::{Command, Stdio},
+    time::Duration,
+};
 
-use serde::Deserialize;
+use color_eyre::{
+    eyre::{ensure, eyre, WrapErr},
+    Result,
+};
+use image::{load_from_memory, open, DynamicImage};
+use serde::{Deserialize, Serialize};
+use walkdir::WalkDir;
 
-#[derive(Default, Deserialize, PartialEq)]
+#[cfg(feature = "archive")]
+use crate::archive;
+use crate::archive::is_archive;
+#[cfg(feature = "color-management")]
+use crate::color;
+use crate::fifo::is_fifo;
+#[cfg(feature = "sqlite")]
+use crate::sqlite;
+use crate::sqlite::{is_index, IndexQuery};
+
+#[derive(Clone, Default, Deserialize, PartialEq, Serialize)]
 pub struct WallpaperInfo {
-    #[serde(deserialize_with = "tilde_expansion_deserialize")]
+    #[serde(default, deserialize_with = "tilde_expansion_deserialize")]
     pub path: Option<PathBuf>,
-    pub mode: Option<()>,
+    /// What `path` points to, when it can't be told from the filesystem
+    #[serde(rename = "type")]
+    pub path_type: Option<PathType>,
+    pub mode: Option<Mode>,
+    /// The (rows, columns) of the mosaic drawn when `mode` is `mosaic`
+    #[serde(rename = "mosaic-grid")]
+    pub mosaic_grid: Option<(u32, u32)>,
+    /// How many of the last images the mosaic shows, at most one per cell
+    #[serde(rename = "mosaic-count")]
+    pub mosaic_count: Option<u32>,
     #[serde(default, with = "humantime_serde")]
     pub duration: Option<Duration>,
+    /// How long the crossfade from the previous wallpaper to the new one lasts
+    #[serde(default, rename = "transition-time", with = "humantime_serde")]
+    pub transition_time: Option<Duration>,
+    /// Crossfade also to the images asked with a command, like `next`; otherwise only the
+    /// changes of the rotation are crossfaded
+    #[serde(rename = "manual-transition")]
+    pub manual_transition: Option<bool>,
+    /// How the previous wallpaper goes to the new one over `transition-time`
+    pub transition: Option<TransitionStyle>,
+    /// The color the wallpaper fades to and from with `transition = dip`, as `#rrggbb`
+    #[serde(
+        default,
+        rename = "dip-color",
+        deserialize_with = "color_deserialize",
+        serialize_with = "color_serialize"
+    )]
+    pub dip_color: Option<[u8; 3]>,
+    #[serde(rename = "reload-transition")]
+    pub reload_transition: Option<ReloadTransition>,
+    /// Crossfade between the frames of the animated images, each one over its delay
+    #[serde(rename = "interpolate-frames")]
+    pub interpolate_frames: Option<bool>,
+    /// The directories to choose the images from instead of `path`, by the minute of the day
+    /// each range starts at. A range lasts until the next one starts
+    #[serde(
+        default,
+        rename = "time-ranges",
+        with = "crate::time_ranges",
+        skip_serializing_if = "BTreeMap::is_empty"
+    )]
+    pub time_ranges: BTreeMap<u32, PathBuf>,
+    /// Draw the album art of the track played, when the player has it in a local file
+    #[serde(rename = "media-art")]
+    pub media_art: Option<bool>,
+    /// The directories to choose the images from while a track of the genres used as keys
+    /// is played
+    #[serde(
+        default,
+        rename = "media-genres",
+        skip_serializing_if = "HashMap::is_empty"
+    )]
+    pub media_genres: HashMap<String, PathBuf>,
+    /// The images or the directories drawn while the workspaces used as keys, by name, are
+    /// active on the output
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub workspaces: HashMap<String, PathBuf>,
+    /// How long to wait for the first listing of the directory in `path` before using the
+    /// images found so far
+    #[serde(default, rename = "scan-timeout", with = "humantime_serde")]
+    pub scan_timeout: Option<Duration>,
     #[serde(rename = "apply-shadow")]
     pub apply_shadow: Option<bool>,
+    pub scale: Option<i32>,
+    /// Split the image in `path` in a grid of (rows, columns) cells, each one used as a
+    /// different wallpaper
+    pub grid: Option<(u32, u32)>,
+    /// The cells of `grid` to use, in order. They are numbered by row, starting from 0
+    #[serde(rename = "cell-order")]
+    pub cell_order: Option<Vec<u32>>,
+    /// Draw the images with more than 8 bits per channel in a 10 bit buffer, when the
+    /// compositor supports it
+    #[serde(rename = "high-bit-depth")]
+    pub high_bit_depth: Option<bool>,
+    #[serde(rename = "on-resize")]
+    pub on_resize: Option<ResizePolicy>,
+    #[serde(rename = "on-same-image")]
+    pub on_same_image: Option<SameImagePolicy>,
+    /// The outputs with a lower priority draw their first wallpaper first, the ones
+    /// without it come last
+    #[serde(rename = "startup-priority")]
+    pub startup_priority: Option<u32>,
+    /// Overrides the global `max-commit-fps` for this output
+    #[serde(rename = "max-commit-fps")]
+    pub max_commit_fps: Option<u32>,
+    /// Stretch the contrast of the images to use the whole range of luminance
+    #[serde(rename = "auto-contrast")]
+    pub auto_contrast: Option<bool>,
+    /// Draw a pattern generated at the resolution of the output instead of an image
+    #[serde(rename = "test-pattern")]
+    pub test_pattern: Option<TestPattern>,
+    /// Draw the current image again when its file is written
+    #[serde(rename = "watch-current")]
+    pub watch_current: Option<bool>,
+    /// Split the images of `path` between the outputs sharing it, so that they never show
+    /// the same one
+    pub distribute: Option<bool>,
+    /// How much later than the output on its left each output changing image at the same
+    /// time starts its transitions
+    #[serde(default, rename = "group-transition-stagger", with = "humantime_serde")]
+    pub group_transition_stagger: Option<Duration>,
+    /// Draw two portrait images side by side on landscape outputs
+    #[serde(rename = "portrait-pair")]
+    pub portrait_pair: Option<bool>,
+    /// How much the choice of the next image favours the ones with a palette close to the
+    /// current one, between 0 and 1
+    pub cohesion: Option<f32>,
+    /// Dither the 8 bit buffers, to hide the banding of smooth gradients
+    pub dither: Option<Dither>,
+    /// Draw the image in a frame of (width, height) pixels, centered on the output
+    #[serde(rename = "frame-size")]
+    pub frame_size: Option<(u32, u32)>,
+    /// The color around the frame, as `#rrggbb`
+    #[serde(
+        default,
+        rename = "background-color",
+        deserialize_with = "color_deserialize",
+        serialize_with = "color_serialize"
+    )]
+    pub background_color: Option<[u8; 3]>,
+    /// The commands decoding the images with the extensions used as keys, for the formats
+    /// not supported by wpaperd. See `decode_with_command`
+    #[serde(
+        default,
+        rename = "decoder-command",
+        skip_serializing_if = "HashMap::is_empty"
+    )]
+    pub decoder_command: HashMap<String, String>,
+    /// The effects applied only to a rectangle of the output, e.g. under a panel
+    #[serde(
+        default,
+        rename = "region-effects",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub region_effects: Vec<RegionEffect>,
+    /// The GLSL fragment shader the wallpaper is drawn through
+    #[serde(default, deserialize_with = "tilde_expansion_deserialize")]
+    pub shader: Option<PathBuf>,
+    /// How many times per second the shader is drawn again, for the animated ones
+    #[serde(rename = "shader-fps")]
+    pub shader_fps: Option<u32>,
+    /// The images to query when `path` is an SQLite index
+    pub index: Option<IndexQuery>,
+    /// The color space of the images without an embedded profile, converted to sRGB
+    #[serde(rename = "assume-color-space")]
+    pub assume_color_space: Option<ColorSpace>,
+}
+
+/// The kinds of sources of images `path` can point to
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PathType {
+    /// A single image, drawn until the configuration changes
+    Image,
+    /// A directory, whose images are rotated
+    Directory,
+    /// A zip or tar archive, used like a directory
+    Archive,
+    /// A FIFO listing the images to rotate. It can be created after wpaperd has started
+    Fifo,
+    /// An SQLite database queried with `index`
+    Index,
+}
+
+impl PathType {
+    /// Detect the type of the source at `path`, or return None if it doesn't exist
+    pub fn detect(path: &Path) -> Option<Self> {
+        if is_fifo(path) {
+            Some(Self::Fifo)
+        } else if path.is_dir() {
+            Some(Self::Directory)
+        } else if is_archive(path) {
+            Some(Self::Archive)
+        } else if is_index(path) {
+            Some(Self::Index)
+        } else if path.is_file() {
+            Some(Self::Image)
+        } else {
+            None
+        }
+    }
+
+    pub fn describe(&self) -> &'static str {
+        match self {
+            Self::Image => "an image",
+            Self::Directory => "a directory",
+            Self::Archive => "an archive",
+            Self::Fifo => "a FIFO",
+            Self::Index => "an SQLite index",
+        }
+    }
+}
+
+/// The patterns drawn to check the scaling, the colors and the alignment of the outputs
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TestPattern {
+    /// Vertical bars of the primary and secondary colors
+    Bars,
+    /// Ramps from black to white, red, green and blue
+    Ramp,
+    /// A grid of lines a pixel wide
+    Grid,
+}
+
+/// How the pixels are quantized to the 8 bits per channel of the buffer
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Dither {
+    /// Add a 4x4 Bayer matrix, cheap and stable between frames
+    Ordered,
+    /// Spread the error of each pixel on its neighbours (Floyd-Steinberg), slower but with a
+    /// less regular pattern
+    ErrorDiffusion,
+}
+
+/// A rectangle of the output, in logical pixels, and the effects applied inside it
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct RegionEffect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    /// The standard deviation of the gaussian blur, in logical pixels
+    pub blur: Option<f32>,
+    /// Darken the region by the given fraction, between 0 and 1
+    pub dim: Option<f32>,
+}
+
+/// How the images are drawn on the output
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Mode {
+    /// Draw the last images of the history in a grid, the most recent first
+    Mosaic,
+}
+
+/// What to do when the image chosen from the pool is the one already drawn
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SameImagePolicy {
+    /// Draw it again, applying the effects again
+    Redraw,
+    /// Choose a different image, if the pool has any
+    Reroll,
+    /// Keep the image drawn, as if it had been changed
+    Skip,
+}
+
+/// How the drawn region of the image is recomputed when the size of the output changes
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ResizePolicy {
+    /// Crop the center of the image again to fill the new size
+    Refit,
+    /// Keep the center and the zoom of the previous crop, adapting it to the new aspect ratio
+    PreserveCrop,
+    /// Choose a new image, as if the output had just been connected
+    Reset,
+}
+
+/// The styles of the transitions between two images
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TransitionStyle {
+    /// Blend the previous image into the new one
+    Crossfade,
+    /// Fade the previous image to `dip-color` over the first half, then from it to the new
+    /// image
+    Dip,
+}
+
+/// What happens to the transition running when the configuration is reloaded
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ReloadTransition {
+    /// Complete the transition, then apply the new configuration
+    Finish,
+    /// Draw the end of the transition at once, then apply the new configuration
+    Snap,
+}
+
+/// The color spaces the untagged images can be assumed to be in
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ColorSpace {
+    Srgb,
+    /// The primaries of DCI-P3 with the white point and the transfer function of sRGB
+    DisplayP3,
+    /// The primaries of sRGB without its transfer function
+    Linear,
+}
+
+impl WallpaperInfo {
+    /// Return true if `path` points to a pool of images to choose from (a directory, an
+    /// archive, a FIFO, an SQLite index or an image split in a grid) instead of a single image.
+    /// The directories of `time-ranges` are pools too
+    pub fn is_pool(&self) -> bool {
+        self.grid.is_some()
+            || !self.time_ranges.is_empty()
+            || self.path_type().map_or(false, |t| t != PathType::Image)
+    }
+
+    /// The type set in the configuration or, when missing, the one detected from `path`
+    pub fn path_type(&self) -> Option<PathType> {
+        self.path_type
+            .or_else(|| self.path.as_deref().and_then(PathType::detect))
+    }
+
+    /// List the images that can be chosen from `path`, either a directory, an archive, an
+    /// SQLite index or the cells of the grid. The index is queried again every time
+    pub fn list_images(&self) -> Result<Vec<PathBuf>> {
+        let path = self.path.as_ref().unwrap();
+        // Like the archive entries, each cell is joined to the path of the image
+        if let Some((rows, cols)) = self.grid {
+            let cells = match &self.cell_order {
+                Some(cell_order) => cell_order.clone(),
+                None => (0..rows * cols).collect(),
+            };
+            return Ok(cells
+                .into_iter()
+                .map(|cell| path.join(cell.to_string()))
+                .collect());
+        }
+        #[cfg(feature = "archive")]
+        if is_archive(path) {
+            return archive::list_images(path);
+        }
+        #[cfg(feature = "sqlite")]
+        if let Some(query) = self.index.as_ref().filter(|_| is_index(path)) {
+            return Ok(sqlite::list_images(path, query)?
+                .into_iter()
+                .filter(|image| self.can_open(image))
+                .collect());
+        }
+
+        Ok(WalkDir::new(path)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| self.can_open(e.path()))
+            .map(|e| e.path().to_path_buf())
+            .collect())
+    }
+
+    /// Return true if `path` is an image that can be decoded, either by wpaperd or by one of
+    /// the commands in `decoder-command`
+    pub fn can_open(&self, path: &Path) -> bool {
+        is_image(path) || self.decoder(path).is_some()
+    }
+
+    fn decoder(&self, path: &Path) -> Option<&str> {
+        let extension = path.extension()?.to_str()?.to_lowercase();
+        self.decoder_command.get(&extension).map(String::as_str)
+    }
+
+    /// Crop the cell `img_path`, one of the images returned by `list_images` when `grid` is
+    /// set, from `sheet`, the decoded image in `path`
+    pub fn crop_cell(&self, sheet: &DynamicImage, img_path: &Path) -> Result<DynamicImage> {
+        let (rows, cols) = self.grid.ok_or_else(|| eyre!("`grid` is not set"))?;
+        let cell: u32 = img_path
+            .strip_prefix(self.path.as_ref().unwrap())?
+            .to_string_lossy()
+            .parse()
+            .with_context(|| format!("invalid cell {img_path:?}"))?;
+        let (cell_width, cell_height) = (sheet.width() / cols, sheet.height() / rows);
+        Ok(sheet.crop_imm(
+            (cell % cols) * cell_width,
+            (cell / cols) * cell_height,
+            cell_width,
+            cell_height,
+        ))
+    }
+
+    /// Open `path` or one of the images returned by `list_images`. `size` is the size the
+    /// image will be scaled to, passed to the decoder commands
+    pub fn open_image(&self, img_path: &Path, size: (u32, u32)) -> Result<DynamicImage> {
+        if self.grid.is_some() {
+            let sheet = open(self.path.as_ref().unwrap())?;
+            let sheet = self.assume_color_space(self.path.as_ref().unwrap(), sheet);
+            return self.crop_cell(&sheet, img_path);
+        }
+        let image = self.decode_image(img_path, size)?;
+        Ok(self.assume_color_space(img_path, image))
+    }
+
+    /// Convert `image`, decoded from `path`, from `assume-color-space` to sRGB when the file
+    /// has no ICC profile. The images in archives and from the decoder commands are never
+    /// tagged
+    pub fn assume_color_space(&self, path: &Path, image: DynamicImage) -> DynamicImage {
+        #[cfg(feature = "color-management")]
+        if let Some(space) = self.assume_color_space {
+            if !color::has_profile(path) {
+                return color::to_srgb(image, space);
+            }
+        }
+        #[cfg(not(feature = "color-management"))]
+        let _ = path;
+        image
+    }
+
+    fn decode_image(&self, img_path: &Path, size: (u32, u32)) -> Result<DynamicImage> {
+        #[cfg(feature = "archive")]
+        if let Some(archive) = self.path.as_ref().filter(|path| is_archive(path)) {
+            if let Ok(entry) = img_path.strip_prefix(archive) {
+                return Ok(load_from_memory(&archive::read_entry(archive, entry)?)?);
+            }
+        }
+
+        if let Some(command) = self.decoder(img_path) {
+            return decode_with_command(command, img_path, size);
+        }
+
+        Ok(open(img_path)?)
+    }
+}
+
+/// Run `command <path> <width> <height>` and decode the image it writes on its standard
+/// output, in any of the supported formats (PNG is always available). The size is 0x0 when
+/// it is not known yet
+fn decode_with_command(command: &str, path: &Path, size: (u32, u32)) -> Result<DynamicImage> {
+    let mut args = command.split_whitespace();
+    let program = args
+        .next()
+        .ok_or_else(|| eyre!("the decoder command is empty"))?;
+    let output = Command::new(program)
+        .args(args)
+        .arg(path)
+        .arg(size.0.to_string())
+        .arg(size.1.to_string())
+        .stdin(Stdio::null())
+        .output()
+        .with_context(|| format!("running the decoder {program:?}"))?;
+    ensure!(
+        output.status.success(),
+        "the decoder {program:?} failed on {path:?} ({}): {}",
+        output.status,
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+    load_from_memory(&output.stdout)
+        .with_context(|| format!("decoding the output of {program:?} for {path:?}"))
+}
+
+/// Guess from its extension if `path` is an image
+pub fn is_image(path: &Path) -> bool {
+    if let Some(guess) = new_mime_guess::from_path(path).first() {
+        guess.type_() == "image"
+    } else {
+        false
+    }
+}
+
+fn color_deserialize<'de, D>(deserializer: D) -> Result<Option<[u8; 3]>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let color = String::deserialize(deserializer)?;
+    let invalid =
+        || <D::Error as serde::de::Error>::custom(format!("invalid color {color:?}, use #rrggbb"));
+    let hex = color
+        .strip_prefix('#')
+        .filter(|hex| hex.len() == 6 && hex.is_ascii())
+        .ok_or_else(invalid)?;
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| invalid());
+    Ok(Some([channel(0)?, channel(2)?, channel(4)?]))
+}
+
+fn color_serialize<S>(color: &Option<[u8; 3]>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    match color {
+        Some([r, g, b]) => serializer.serialize_str(&format!("#{r:02x}{g:02x}{b:02x}")),
+        None => serializer.serialize_none(),
+    }
 }
 
 pub fn tilde_expansion_deserialize<'de, D>(deserializer: D) -> Result<Option<PathBuf>, D::Error>
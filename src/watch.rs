@@ -0,0 +1,77 @@
+use std::{
+    collections::HashSet,
+    mem,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use color_eyre::{eyre::WrapErr, Result};
+use hotwatch::{Event, Hotwatch};
+use log::warn;
+use smithay_client_toolkit::reexports::calloop::channel::Sender;
+
+/// Watch the images currently drawn by the outputs with `watch-current` set, to draw them
+/// again when they are written in place
+pub struct CurrentWatcher {
+    hotwatch: Hotwatch,
+    watched: HashSet<PathBuf>,
+    /// The watched images written since the last call to `take_changed`
+    changed: Arc<Mutex<HashSet<PathBuf>>>,
+    ev_tx: Sender<()>,
+}
+
+impl CurrentWatcher {
+    /// `ev_tx` wakes up the event loop every time a watched image changes
+    pub fn new(ev_tx: Sender<()>) -> Result<Self> {
+        Ok(Self {
+            hotwatch: Hotwatch::new().context("hotwatch failed to initialize")?,
+            watched: HashSet::new(),
+            changed: Arc::new(Mutex::new(HashSet::new())),
+            ev_tx,
+        })
+    }
+
+    /// Watch exactly the images in `paths`, dropping the watches of the ones not drawn anymore
+    pub fn update(&mut self, paths: HashSet<PathBuf>) {
+        for path in self.watched.difference(&paths) {
+            if let Err(err) = self.hotwatch.unwatch(path) {
+                warn!("unwatching the image {path:?}: {err:?}");
+            }
+        }
+        self.watched.retain(|path| paths.contains(path));
+
+        for path in paths {
+            if self.watched.contains(&path) {
+                continue;
+            }
+            let changed = self.changed.clone();
+            let ev_tx = self.ev_tx.clone();
+            let watched_path = path.clone();
+            let res = self.hotwatch.watch(&path, move |event: Event| {
+                // Replacing the file with a new one (e.g. by renaming it) counts too
+                if let Event::Write(_) | Event::Create(_) | Event::Rename(_, _) = event {
+                    changed.lock().unwrap().insert(watched_path.clone());
+                    let _ = ev_tx.send(());
+                }
+            });
+            match res {
+                Ok(()) => {
+                    self.watched.insert(path);
+                }
+                Err(err) => warn!("watching the image {path:?}: {err:?}"),
+            }
+        }
+    }
+
+    /// Return the images changed since the last call. Their watches are dropped, to be
+    /// added again by `update` in case the file has been replaced
+    pub fn take_changed(&mut self) -> HashSet<PathBuf> {
+        let changed = mem::take(&mut *self.changed.lock().unwrap());
+        for path in &changed {
+            if self.watched.remove(path) {
+                let _ = self.hotwatch.unwatch(path);
+            }
+        }
+        changed
+    }
+}
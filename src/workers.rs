@@ -0,0 +1,209 @@
+use std::{
+    cell::Cell,
+    panic::{self, AssertUnwindSafe},
+    rc::Rc,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+};
+
+use log::error;
+use nix::unistd::{sysconf, SysconfVar};
+use smithay_client_toolkit::reexports::calloop::channel::Sender as LoopSender;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Long-lived threads running the slow operations out of the event loop. At most
+/// `max_threads` jobs run at the same time, the others wait in a queue. The threads are only
+/// started when a job is queued and all the others are busy, so there are never more of them
+/// than jobs in flight. `max_threads` can be changed while the jobs are running
+#[derive(Clone)]
+pub struct WorkerPool {
+    name: &'static str,
+    jobs: Sender<Job>,
+    queue: Arc<Mutex<Receiver<Job>>>,
+    /// The threads started so far, only changed by the event loop
+    threads: Rc<Cell<usize>>,
+    /// The threads waiting for a job
+    idle: Arc<AtomicUsize>,
+    /// Taken by the threads while they run a job, `max_threads` of them
+    permits: Arc<Semaphore>,
+    /// Wakes up the event loop when a job has completed
+    ev_tx: LoopSender<()>,
+}
+
+impl WorkerPool {
+    pub fn new(name: &'static str, max_threads: usize, ev_tx: LoopSender<()>) -> Self {
+        let (jobs, queue) = mpsc::channel();
+        Self {
+            name,
+            jobs,
+            queue: Arc::new(Mutex::new(queue)),
+            threads: Rc::new(Cell::new(0)),
+            idle: Arc::new(AtomicUsize::new(0)),
+            permits: Arc::new(Semaphore::new(max_threads.max(1))),
+            ev_tx,
+        }
+    }
+
+    /// Change how many jobs can run at the same time. The jobs already running above the new
+    /// limit complete, the next ones wait for a permit
+    pub fn set_max_threads(&self, max_threads: usize) {
+        self.permits.set_limit(max_threads.max(1));
+    }
+
+    /// Queue `job`; its result can be read from the returned receiver once the event loop
+    /// has been woken up. If the job panics, the sender is dropped without a result
+    pub fn run<T, F>(&self, job: F) -> Receiver<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let (result_tx, result_rx) = mpsc::channel();
+        let job: Job = Box::new(move || {
+            let _ = result_tx.send(job());
+        });
+        if self.idle.load(Ordering::SeqCst) == 0
+            && self.threads.get() < self.permits.limit()
+            && !self.spawn()
+            && self.threads.get() == 0
+        {
+            // There is no thread to run it
+            job();
+            let _ = self.ev_tx.send(());
+            return result_rx;
+        }
+        // The receiving side lives as long as the threads, that hold `queue`
+        let _ = self.jobs.send(job);
+        result_rx
+    }
+
+    /// Start a new thread, return false if it couldn't be started
+    fn spawn(&self) -> bool {
+        let queue = self.queue.clone();
+        let idle = self.idle.clone();
+        let permits = self.permits.clone();
+        let ev_tx = self.ev_tx.clone();
+        let name = self.name;
+        let spawned = thread::Builder::new()
+            .name(format!("wpaperd-{name}"))
+            .spawn(move || loop {
+                idle.fetch_add(1, Ordering::SeqCst);
+                let job = queue.lock().unwrap().recv();
+                idle.fetch_sub(1, Ordering::SeqCst);
+                let job = match job {
+                    Ok(job) => job,
+                    // The pool has been dropped
+                    Err(_) => break,
+                };
+                permits.acquire();
+                let panicked = panic::catch_unwind(AssertUnwindSafe(job)).is_err();
+                permits.release();
+                if panicked {
+                    error!("a {name} job has panicked");
+                }
+                let _ = ev_tx.send(());
+            });
+        match spawned {
+            Ok(_) => {
+                self.threads.set(self.threads.get() + 1);
+                true
+            }
+            Err(err) => {
+                error!("starting a {name} thread: {err:?}");
+                false
+            }
+        }
+    }
+}
+
+/// A counting semaphore whose number of permits can be changed while they are taken
+struct Semaphore {
+    /// The permits taken and how many can be
+    state: Mutex<(usize, usize)>,
+    released: Condvar,
+}
+
+impl Semaphore {
+    fn new(limit: usize) -> Self {
+        Self {
+            state: Mutex::new((0, limit)),
+            released: Condvar::new(),
+        }
+    }
+
+    fn limit(&self) -> usize {
+        self.state.lock().unwrap().1
+    }
+
+    fn set_limit(&self, limit: usize) {
+        self.state.lock().unwrap().1 = limit;
+        // The waiting threads can take the new permits
+        self.released.notify_all();
+    }
+
+    /// Wait until fewer permits than the limit are taken, and take one
+    fn acquire(&self) {
+        let mut state = self
+            .released
+            .wait_while(self.state.lock().unwrap(), |(taken, limit)| *taken >= *limit)
+            .unwrap();
+        state.0 += 1;
+    }
+
+    fn release(&self) {
+        self.state.lock().unwrap().0 -= 1;
+        self.released.notify_one();
+    }
+}
+
+/// The number of CPUs online, 1 if it can't be read
+pub fn cpu_count() -> usize {
+    match sysconf(SysconfVar::_NPROCESSORS_ONLN) {
+        Ok(Some(count)) if count > 0 => count as usize,
+        _ => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use smithay_client_toolkit::reexports::calloop::channel;
+
+    use super::*;
+
+    /// Run `count` jobs of 10ms in `pool` and return how many ran at the same time at most
+    fn max_in_flight(pool: &WorkerPool, count: usize) -> usize {
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let results: Vec<Receiver<()>> = (0..count)
+            .map(|_| {
+                let (running, peak) = (running.clone(), peak.clone());
+                pool.run(move || {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    peak.fetch_max(now, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(10));
+                    running.fetch_sub(1, Ordering::SeqCst);
+                })
+            })
+            .collect();
+        results.iter().for_each(|result| result.recv().unwrap());
+        peak.load(Ordering::SeqCst)
+    }
+
+    #[test]
+    fn bounded_in_flight() {
+        let (ev_tx, _channel) = channel::channel();
+        let pool = WorkerPool::new("test", 2, ev_tx);
+        assert!(max_in_flight(&pool, 8) <= 2);
+        pool.set_max_threads(4);
+        assert!(max_in_flight(&pool, 8) <= 4);
+        // The threads started for the previous limit wait for a permit
+        pool.set_max_threads(1);
+        assert_eq!(max_in_flight(&pool, 8), 1);
+    }
+}
@@ -0,0 +1,179 @@
+use std::collections::HashMap;
+
+use color_eyre::{eyre::WrapErr, Result};
+use log::warn;
+use smithay_client_toolkit::reexports::client::backend::ObjectId;
+use smithay_client_toolkit::reexports::client::globals::GlobalList;
+use smithay_client_toolkit::reexports::client::protocol::wl_output::WlOutput;
+use smithay_client_toolkit::reexports::client::{
+    event_created_child, Connection, Dispatch, Proxy, QueueHandle,
+};
+
+use crate::wpaperd::Wpaperd;
+
+use self::protocol::{
+    ext_workspace_group_handle_v1::{self, ExtWorkspaceGroupHandleV1},
+    ext_workspace_handle_v1::{self, ExtWorkspaceHandleV1},
+    ext_workspace_manager_v1::{self, ExtWorkspaceManagerV1},
+};
+
+/// The client code of ext-workspace, not in the version of wayland-protocols used
+#[allow(dead_code, non_camel_case_types, unused_imports, clippy::all)]
+mod protocol {
+    use smithay_client_toolkit::reexports::client as wayland_client;
+    use wayland_client::protocol::*;
+
+    pub mod __interfaces {
+        use smithay_client_toolkit::reexports::client::protocol::__interfaces::*;
+        wayland_scanner::generate_interfaces!("protocols/ext-workspace-v1.xml");
+    }
+    use self::__interfaces::*;
+
+    wayland_scanner::generate_client_code!("protocols/ext-workspace-v1.xml");
+}
+
+/// The bit of the state of an active workspace
+const STATE_ACTIVE: u32 = 1;
+
+/// The workspaces of the compositor, to draw a different wallpaper on each one
+pub struct Workspaces {
+    _manager: ExtWorkspaceManagerV1,
+    /// The outputs and the workspaces of each group
+    groups: HashMap<ObjectId, (Vec<WlOutput>, Vec<ObjectId>)>,
+    /// The name of each workspace and whether it is active
+    workspaces: HashMap<ObjectId, (Option<String>, bool)>,
+}
+
+impl Workspaces {
+    /// Bind the workspace manager, failing when the compositor doesn't support ext-workspace
+    pub fn new(globals: &GlobalList, qh: &QueueHandle<Wpaperd>) -> Result<Self> {
+        let manager = globals
+            .bind(qh, 1..=1, ())
+            .context("the compositor does not support the ext-workspace protocol")?;
+        Ok(Self {
+            _manager: manager,
+            groups: HashMap::new(),
+            workspaces: HashMap::new(),
+        })
+    }
+
+    /// The name of the active workspace shown on `output`
+    pub fn active(&self, output: &WlOutput) -> Option<&str> {
+        let (_, workspaces) = self
+            .groups
+            .values()
+            .find(|(outputs, _)| outputs.contains(output))?;
+        workspaces
+            .iter()
+            .find_map(|id| match self.workspaces.get(id) {
+                Some((Some(name), true)) => Some(name.as_str()),
+                _ => None,
+            })
+    }
+}
+
+impl Dispatch<ExtWorkspaceManagerV1, ()> for Wpaperd {
+    fn event(
+        state: &mut Self,
+        _manager: &ExtWorkspaceManagerV1,
+        event: ext_workspace_manager_v1::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        let workspaces = match &mut state.workspaces {
+            Some(workspaces) => workspaces,
+            None => return,
+        };
+        match event {
+            ext_workspace_manager_v1::Event::WorkspaceGroup { workspace_group } => {
+                workspaces
+                    .groups
+                    .insert(workspace_group.id(), (Vec::new(), Vec::new()));
+            }
+            ext_workspace_manager_v1::Event::Workspace { workspace } => {
+                workspaces.workspaces.insert(workspace.id(), (None, false));
+            }
+            ext_workspace_manager_v1::Event::Finished => {
+                warn!("the compositor has stopped sending the workspaces");
+            }
+            // The wallpapers are updated in the next loop, after all the events
+            _ => {}
+        }
+    }
+
+    event_created_child!(Wpaperd, ExtWorkspaceManagerV1, [
+        ext_workspace_manager_v1::EVT_WORKSPACE_GROUP_OPCODE => (ExtWorkspaceGroupHandleV1, ()),
+        ext_workspace_manager_v1::EVT_WORKSPACE_OPCODE => (ExtWorkspaceHandleV1, ()),
+    ]);
+}
+
+impl Dispatch<ExtWorkspaceGroupHandleV1, ()> for Wpaperd {
+    fn event(
+        state: &mut Self,
+        group: &ExtWorkspaceGroupHandleV1,
+        event: ext_workspace_group_handle_v1::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        let workspaces = match &mut state.workspaces {
+            Some(workspaces) => workspaces,
+            None => return,
+        };
+        if let ext_workspace_group_handle_v1::Event::Removed = event {
+            workspaces.groups.remove(&group.id());
+            group.destroy();
+            return;
+        }
+        let (outputs, members) = match workspaces.groups.get_mut(&group.id()) {
+            Some(group) => group,
+            None => return,
+        };
+        match event {
+            ext_workspace_group_handle_v1::Event::OutputEnter { output } => outputs.push(output),
+            ext_workspace_group_handle_v1::Event::OutputLeave { output } => {
+                outputs.retain(|entered| *entered != output);
+            }
+            ext_workspace_group_handle_v1::Event::WorkspaceEnter { workspace } => {
+                members.push(workspace.id());
+            }
+            ext_workspace_group_handle_v1::Event::WorkspaceLeave { workspace } => {
+                members.retain(|id| *id != workspace.id());
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<ExtWorkspaceHandleV1, ()> for Wpaperd {
+    fn event(
+        state: &mut Self,
+        workspace: &ExtWorkspaceHandleV1,
+        event: ext_workspace_handle_v1::Event,
+        _data: &(),
+        _conn: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        let workspaces = match &mut state.workspaces {
+            Some(workspaces) => workspaces,
+            None => return,
+        };
+        if let ext_workspace_handle_v1::Event::Removed = event {
+            workspaces.workspaces.remove(&workspace.id());
+            workspace.destroy();
+            return;
+        }
+        let (name, active) = match workspaces.workspaces.get_mut(&workspace.id()) {
+            Some(workspace) => workspace,
+            None => return,
+        };
+        match event {
+            ext_workspace_handle_v1::Event::Name { name: new_name } => *name = Some(new_name),
+            ext_workspace_handle_v1::Event::State { state } => {
+                *active = state & STATE_ACTIVE != 0;
+            }
+            _ => {}
+        }
+    }
+}
@@ -1,8 +1,19 @@
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use color_eyre::Result;
+use color_eyre::{
+    eyre::{bail, ensure, eyre, WrapErr},
+    Result,
+};
+use flexi_logger::{LogSpecification, LoggerHandle};
+use log::{info, warn, LevelFilter};
 use smithay_client_toolkit::compositor::{CompositorHandler, CompositorState};
 use smithay_client_toolkit::output::{OutputHandler, OutputState};
+use smithay_client_toolkit::reexports::calloop::channel::Sender;
+use smithay_client_toolkit::reexports::calloop::timer::{TimeoutAction, Timer};
+use smithay_client_toolkit::reexports::calloop::LoopHandle;
 use smithay_client_toolkit::reexports::client::globals::GlobalList;
 use smithay_client_toolkit::reexports::client::protocol::{wl_output, wl_surface};
 use smithay_client_toolkit::reexports::client::{Connection, QueueHandle};
@@ -16,8 +27,26 @@ use smithay_client_toolkit::{
     registry_handlers,
 };
 
-use crate::surface::Surface;
+use crate::fifo::FifoPool;
+#[cfg(feature = "idle")]
+use crate::idle::{IdleDim, IdleRotation};
+#[cfg(feature = "media")]
+use crate::media::MediaMonitor;
+use crate::output_log::OutputLevels;
+use crate::palette::PaletteCache;
+use crate::scan::DirScans;
+use crate::stats::UsageStats;
+use crate::surface::{Surface, SurfaceOptions};
 use crate::wallpaper_config::WallpaperConfig;
+use crate::wallpaper_info::{PathType, WallpaperInfo};
+use crate::watch::CurrentWatcher;
+use crate::workers::{cpu_count, WorkerPool};
+#[cfg(feature = "workspace")]
+use crate::workspace::Workspaces;
+
+/// How far apart the next changes of image of two outputs can be to count as the same
+/// deadline, for `group-transition-stagger`
+const SAME_DEADLINE: Duration = Duration::from_secs(1);
 
 pub struct Wpaperd {
     pub compositor_state: CompositorState,
@@ -27,7 +56,65 @@ pub struct Wpaperd {
     pub registry_state: RegistryState,
     pub surfaces: Vec<Surface>,
     wallpaper_config: Arc<Mutex<WallpaperConfig>>,
-    use_scaled_window: bool,
+    options: Options,
+    qh: QueueHandle<Self>,
+    /// The FIFOs being read, shared by all the surfaces using them
+    fifo_pools: HashMap<PathBuf, FifoPool>,
+    /// The directories being listed, shared by all the surfaces using them
+    dir_scans: DirScans,
+    current_watcher: CurrentWatcher,
+    ev_tx: Sender<()>,
+    /// Used to change the log specification at runtime
+    pub logger: Option<LoggerHandle>,
+    /// The log levels raised for single outputs, shared with the filter of `logger`
+    pub output_levels: OutputLevels,
+    /// Whether the log specification letting through the raised levels has been pushed
+    output_log_spec: bool,
+    /// Whether a timer has been added to put the next output back to the global log level
+    output_levels_timer_armed: bool,
+    /// How many times and for how long each output has shown its images
+    pub stats: UsageStats,
+    /// The surfaces of the outputs that have been disconnected and when, to resume their
+    /// state if the outputs come back within `disconnect-grace`
+    disconnected: Vec<(Instant, Surface)>,
+    /// Whether a timer has been added to drop the first surface of `disconnected` once its
+    /// grace has passed
+    disconnect_timer_armed: bool,
+    #[cfg(feature = "idle")]
+    pub idle_dim: Option<IdleDim>,
+    #[cfg(feature = "idle")]
+    pub idle_rotation: Option<IdleRotation>,
+    /// Set while the rotation is paused because the battery is low
+    #[cfg(feature = "battery")]
+    pub battery_paused: bool,
+    /// Follows the track played, for `media-art` and `media-genres`
+    #[cfg(feature = "media")]
+    pub media: Option<MediaMonitor>,
+    /// The active workspace of each output, for `workspaces`
+    #[cfg(feature = "workspace")]
+    pub workspaces: Option<Workspaces>,
+}
+
+/// The global options, set from the command line or wpaperd.conf. Some of them can be
+/// changed at runtime with `set-runtime`
+pub struct Options {
+    pub use_scaled_window: bool,
+    pub random_seed: u64,
+    pub history_length: usize,
+    pub max_commit_fps: Option<u32>,
+    /// The maximum size of a buffer, in MiB
+    pub max_buffer_size: Option<u64>,
+    /// How many images decoded from archives each surface keeps
+    pub archive_cache_size: usize,
+    /// How long the surfaces of the disconnected outputs are kept, see `Wpaperd::disconnected`
+    pub disconnect_grace: Duration,
+    /// Decodes the images of all the surfaces, `decode-threads` at a time
+    pub decode_pool: WorkerPool,
+    /// The palettes compared by `cohesion`, extracted in `decode_pool`
+    pub palettes: PaletteCache,
+    /// `decode-threads`; when it is not set, as many images as outputs are decoded at the
+    /// same time, up to the number of CPUs
+    pub decode_threads: Option<usize>,
 }
 
 impl Wpaperd {
@@ -36,7 +123,8 @@ impl Wpaperd {
         globals: &GlobalList,
         _conn: &Connection,
         wallpaper_config: Arc<Mutex<WallpaperConfig>>,
-        use_scaled_window: bool,
+        ev_tx: Sender<()>,
+        options: Options,
     ) -> Result<Self> {
         let shm_state = ShmState::bind(globals, qh)?;
         Ok(Self {
@@ -47,9 +135,634 @@ impl Wpaperd {
             registry_state: RegistryState::new(globals),
             surfaces: Vec::new(),
             wallpaper_config,
-            use_scaled_window,
+            options,
+            qh: qh.clone(),
+            fifo_pools: HashMap::new(),
+            dir_scans: DirScans::new(ev_tx.clone())
+                .context("watching the directories of the outputs")?,
+            current_watcher: CurrentWatcher::new(ev_tx.clone())?,
+            ev_tx,
+            logger: None,
+            output_levels: OutputLevels::default(),
+            output_log_spec: false,
+            output_levels_timer_armed: false,
+            stats: UsageStats::default(),
+            disconnected: Vec::new(),
+            disconnect_timer_armed: false,
+            #[cfg(feature = "idle")]
+            idle_dim: None,
+            #[cfg(feature = "idle")]
+            idle_rotation: None,
+            #[cfg(feature = "battery")]
+            battery_paused: false,
+            #[cfg(feature = "media")]
+            media: None,
+            #[cfg(feature = "workspace")]
+            workspaces: None,
         })
     }
+
+    /// Draw the album art or the images of the genre of the track played on the outputs
+    /// configured for it, and their images again once the playback stops
+    #[cfg(feature = "media")]
+    pub fn update_media(&mut self) {
+        let track = match &self.media {
+            Some(media) => media.track(),
+            None => return,
+        };
+        let wallpaper_config = self.wallpaper_config.lock().unwrap();
+        for surface in &mut self.surfaces {
+            let configured = wallpaper_config.get_output_by_name(surface.name());
+            if configured.media_art.is_none() && configured.media_genres.is_empty() {
+                continue;
+            }
+            let source = track.as_ref().and_then(|track| {
+                track
+                    .art
+                    .clone()
+                    .filter(|_| configured.media_art.unwrap_or(false))
+                    .or_else(|| {
+                        track.genres.iter().find_map(|genre| {
+                            configured
+                                .media_genres
+                                .iter()
+                                .find(|(name, _)| name.to_lowercase() == *genre)
+                                .map(|(_, dir)| dir.clone())
+                        })
+                    })
+            });
+            surface.set_media_source(source, configured);
+        }
+    }
+
+    /// Draw the images of the active workspace on the outputs configured for it, and their
+    /// images on the other workspaces
+    #[cfg(feature = "workspace")]
+    pub fn update_workspaces(&mut self) {
+        let workspaces = match &self.workspaces {
+            Some(workspaces) => workspaces,
+            None => return,
+        };
+        let wallpaper_config = self.wallpaper_config.lock().unwrap();
+        for surface in &mut self.surfaces {
+            let configured = wallpaper_config.get_output_by_name(surface.name());
+            if configured.workspaces.is_empty() {
+                continue;
+            }
+            let source = workspaces
+                .active(&surface.output)
+                .and_then(|workspace| configured.workspaces.get(workspace))
+                .cloned();
+            surface.set_workspace_source(source, configured);
+        }
+    }
+
+    /// Point the surfaces with `time-ranges` to the directory of the current range
+    pub fn update_time_ranges(&mut self, handle: LoopHandle<Wpaperd>) {
+        for surface in &mut self.surfaces {
+            surface.update_time_range(handle.clone());
+        }
+    }
+
+    /// Give the surfaces whose `path` is a FIFO the images listed in it, and choose a new
+    /// image for the ones whose current image has been removed from the list
+    pub fn update_fifo_pools(&mut self) {
+        for surface in &mut self.surfaces {
+            let path = match &surface.wallpaper_info.path {
+                Some(path) if surface.wallpaper_info.path_type() == Some(PathType::Fifo) => path,
+                _ => {
+                    surface.fifo_pool = None;
+                    continue;
+                }
+            };
+
+            let fifo_pool = self
+                .fifo_pools
+                .entry(path.to_path_buf())
+                .or_insert_with(|| FifoPool::new(path, self.ev_tx.clone()))
+                .clone();
+            if !fifo_pool.images().is_empty() && !fifo_pool.contains(&surface.current_img) {
+                surface.timer_expired = true;
+            }
+            surface.fifo_pool = Some(fifo_pool);
+        }
+    }
+
+    /// Count the images the surfaces have changed away from and save the statistics
+    pub fn update_stats(&mut self) {
+        let mut changed = false;
+        for surface in &mut self.surfaces {
+            for (img_path, shown) in surface.take_viewed() {
+                self.stats.record(surface.name(), img_path, shown);
+                changed = true;
+            }
+        }
+        if changed {
+            if let Err(err) = self.stats.save() {
+                warn!("{err:?}");
+            }
+        }
+    }
+
+    /// Forget the statistics of `outputs`, or of all of them when it is empty
+    pub fn reset_stats(&mut self, outputs: &[&str]) -> Result<()> {
+        self.stats.reset(outputs);
+        self.stats.save()
+    }
+
+    /// Give the surfaces whose `path` is a directory its cached listing, so that listing a
+    /// slow directory doesn't block the event loop, then list again the directories that have
+    /// changed and forget the ones no longer used
+    pub fn update_dir_scans(&mut self) {
+        let mut used = HashSet::new();
+        for surface in &mut self.surfaces {
+            surface.dir_scan = match &surface.wallpaper_info.path {
+                Some(path) if surface.wallpaper_info.path_type() == Some(PathType::Directory) => {
+                    used.insert(path.to_path_buf());
+                    Some(self.dir_scans.get(path))
+                }
+                _ => None,
+            };
+        }
+        self.dir_scans.update(&used);
+    }
+
+    /// Draw again the images written in place by the outputs with `watch-current` set, then
+    /// watch the images drawn now
+    pub fn update_current_watches(&mut self) {
+        let changed = self.current_watcher.take_changed();
+        for surface in &mut self.surfaces {
+            if changed.contains(surface.current_source()) {
+                info!(
+                    "{:?} has changed, drawing it again",
+                    surface.current_source()
+                );
+                surface.reload_current();
+            }
+        }
+        let drawn: HashSet<PathBuf> = self
+            .surfaces
+            .iter()
+            .filter(|surface| surface.wallpaper_info.watch_current.unwrap_or(false))
+            .map(|surface| surface.current_source().to_path_buf())
+            // Nothing has been chosen from the pool yet
+            .filter(|path| path.is_file())
+            .collect();
+        self.current_watcher.update(drawn);
+    }
+
+    /// Number the outputs distributing the same `path` by name, so that each one
+    /// chooses from its own share of the images
+    pub fn update_distribution(&mut self) {
+        let mut groups: HashMap<PathBuf, Vec<String>> = HashMap::new();
+        for surface in &self.surfaces {
+            if let Some(path) = surface
+                .wallpaper_info
+                .path
+                .as_ref()
+                .filter(|_| surface.wallpaper_info.distribute.unwrap_or(false))
+            {
+                groups
+                    .entry(path.clone())
+                    .or_default()
+                    .push(surface.name().to_string());
+            }
+        }
+        groups.values_mut().for_each(|names| names.sort());
+        for surface in &mut self.surfaces {
+            surface.distribution = surface
+                .wallpaper_info
+                .path
+                .as_ref()
+                .filter(|_| surface.wallpaper_info.distribute.unwrap_or(false))
+                .and_then(|path| groups.get(path))
+                .and_then(|names| {
+                    let index = names.iter().position(|name| name == surface.name())?;
+                    Some((index, names.len()))
+                });
+        }
+    }
+
+    /// Delay the transitions of the outputs whose next change of image falls on the same
+    /// deadline by their position from left to right, `group-transition-stagger` after each
+    /// other, so that the new images ripple across them
+    pub fn update_transition_stagger(&mut self) {
+        let mut outputs: Vec<(Instant, i32, String, usize)> = self
+            .surfaces
+            .iter()
+            .enumerate()
+            .filter(|(_, surface)| surface.wallpaper_info.group_transition_stagger.is_some())
+            .filter_map(|(index, surface)| {
+                let (x, _) = surface
+                    .info
+                    .logical_position
+                    .unwrap_or(surface.info.location);
+                let deadline = surface.next_change()?;
+                Some((deadline, x, surface.name().to_string(), index))
+            })
+            .collect();
+        outputs.sort();
+        for surface in &mut self.surfaces {
+            surface.transition_delay = Duration::ZERO;
+        }
+        let mut start = 0;
+        while start < outputs.len() {
+            let first = outputs[start].0;
+            let end = outputs[start..]
+                .iter()
+                .position(|(deadline, ..)| {
+                    deadline.saturating_duration_since(first) > SAME_DEADLINE
+                })
+                .map_or(outputs.len(), |len| start + len);
+            let group = &mut outputs[start..end];
+            group.sort_by(|(_, x, name, _), (_, other_x, other_name, _)| {
+                (x, name).cmp(&(other_x, other_name))
+            });
+            for (position, (.., index)) in group.iter().enumerate() {
+                let surface = &mut self.surfaces[*index];
+                if let Some(stagger) = surface.wallpaper_info.group_transition_stagger {
+                    surface.transition_delay = stagger * position as u32;
+                }
+            }
+            start = end;
+        }
+    }
+
+    /// Order the surfaces by `startup-priority` and let each one draw its first wallpaper
+    /// `delay` after the previous one
+    pub fn schedule_first_draws(&mut self, handle: LoopHandle<Wpaperd>, delay: Duration) {
+        self.surfaces.sort_by_key(|surface| {
+            (
+                surface.wallpaper_info.startup_priority.unwrap_or(u32::MAX),
+                surface.name().to_string(),
+            )
+        });
+        let now = Instant::now();
+        for (rank, surface) in self.surfaces.iter_mut().enumerate() {
+            let wait = delay * rank as u32;
+            surface.draw_not_before = Some(now + wait);
+            if !wait.is_zero() {
+                // Wake up the event loop to draw it
+                handle
+                    .insert_source(Timer::from_duration(wait), |_, _, _| TimeoutAction::Drop)
+                    .expect("Failed to insert event source!");
+            }
+        }
+    }
+
+    /// Return `name`, or `name-2`, `name-3`... if another output already uses it
+    fn unique_name(&self, output: &wl_output::WlOutput, name: &str) -> String {
+        let taken = |candidate: &str| {
+            self.surfaces
+                .iter()
+                .any(|surface| surface.output != *output && surface.name() == candidate)
+        };
+        if !taken(name) {
+            return name.to_string();
+        }
+        let unique = (2..)
+            .map(|index| format!("{name}-{index}"))
+            .find(|candidate| !taken(candidate))
+            .unwrap();
+        warn!("another output is called {name}, this one will be called {unique}");
+        unique
+    }
+
+    /// Create the surface drawing the wallpaper on `output`
+    fn create_surface(&self, qh: &QueueHandle<Self>, output: wl_output::WlOutput) -> Surface {
+        // TODO: Error handling
+        let surface = self.compositor_state.create_surface(qh);
+
+        let mut info = self.output_state.info(&output).unwrap();
+        let name = self.unique_name(&output, info.name.as_ref().unwrap());
+        // The surface is known by its unique name, for the configuration and the commands
+        info.name = Some(name.clone());
+
+        let options = SurfaceOptions {
+            wallpaper_info: self
+                .wallpaper_config
+                .lock()
+                .unwrap()
+                .get_output_by_name(&name),
+            use_scaled_window: self.options.use_scaled_window,
+            seed: output_seed(self.options.random_seed, &name),
+            history_length: self.options.history_length,
+            max_commit_fps: self.options.max_commit_fps,
+            max_buffer_size: self.options.max_buffer_size,
+            archive_cache_size: self.options.archive_cache_size,
+            decode_pool: self.options.decode_pool.clone(),
+            palettes: self.options.palettes.clone(),
+        };
+        Surface::new(
+            qh,
+            output,
+            &self.layer_state,
+            surface,
+            &self.shm_state,
+            info,
+            options,
+        )
+    }
+
+    /// Change one of the global options while running. Only the ones that don't need to
+    /// recreate the surfaces or the Wayland objects can be changed
+    pub fn set_runtime(&mut self, key: &str, value: &str) -> Result<()> {
+        let parse_count = || {
+            value
+                .parse::<usize>()
+                .with_context(|| format!("parsing the value {value:?} of {key}"))
+        };
+        match key {
+            "log-level" => {
+                // The levels raised for single outputs were relative to the previous one
+                self.reset_output_log_level(&[])?;
+                self.logger
+                    .as_mut()
+                    .ok_or_else(|| eyre!("the logger has not been started"))?
+                    .parse_new_spec(value)
+                    .with_context(|| format!("parsing the log specification {value:?}"))?
+            }
+            "max-commit-fps" => {
+                let fps = value
+                    .parse::<u32>()
+                    .with_context(|| format!("parsing the value {value:?} of {key}"))?;
+                ensure!(fps > 0, "max-commit-fps must be greater than 0");
+                self.options.max_commit_fps = Some(fps);
+                for surface in &mut self.surfaces {
+                    surface.set_max_commit_fps(Some(fps));
+                }
+            }
+            "history-length" => {
+                self.options.history_length = parse_count()?;
+                for surface in &mut self.surfaces {
+                    surface.set_history_length(self.options.history_length);
+                }
+            }
+            "cache-size" => {
+                let size = parse_count()?;
+                ensure!(size > 0, "cache-size must be greater than 0");
+                self.options.archive_cache_size = size;
+                for surface in &mut self.surfaces {
+                    surface.archive_cache.set_capacity(size);
+                }
+            }
+            "decode-threads" => {
+                let threads = parse_count()?;
+                ensure!(threads > 0, "decode-threads must be greater than 0");
+                self.options.decode_threads = Some(threads);
+                self.update_decode_threads();
+            }
+            "config" | "output-config" | "no-daemon" | "daemon-output" | "use-scaled-window"
+            | "random-seed" | "pause-below-battery" | "startup-stagger" | "idle-dim-timeout"
+            | "idle-dim-brightness" | "rotate-only-when-idle" | "max-buffer-size"
+            | "disconnect-grace" | "selftest" => bail!("{key} can't be changed while wpaperd is running, restart it"),
+            _ => bail!(
+                "unknown option {key:?}, use log-level, max-commit-fps, history-length, cache-size or decode-threads"
+            ),
+        }
+        Ok(())
+    }
+
+    /// Let `decode-threads` images be decoded at the same time, or by default one per output
+    /// up to the number of CPUs
+    fn update_decode_threads(&self) {
+        let threads = self
+            .options
+            .decode_threads
+            .unwrap_or_else(|| self.surfaces.len().min(cpu_count()));
+        self.options.decode_pool.set_max_threads(threads);
+    }
+
+    /// Log the records about `output` up to `level`, for `duration` if any, leaving the
+    /// other outputs at the global level
+    pub fn set_output_log_level(
+        &mut self,
+        output: &str,
+        level: &str,
+        duration: Option<Duration>,
+    ) -> Result<()> {
+        self.surface_by_name(output)?;
+        let level = level
+            .parse::<LevelFilter>()
+            .with_context(|| format!("parsing the log level {level:?}"))?;
+        ensure!(self.logger.is_some(), "the logger has not been started");
+        self.output_levels.set(output, level, duration);
+        // A timer is added for the new duration in the next loop
+        self.output_levels_timer_armed = false;
+        self.update_log_spec()
+    }
+
+    /// Put the outputs in `outputs`, or all of them if it's empty, back to the global log
+    /// level
+    pub fn reset_output_log_level(&mut self, outputs: &[&str]) -> Result<()> {
+        self.output_levels.reset(outputs);
+        self.update_log_spec()
+    }
+
+    /// Put back the outputs whose `set-output-log-level` duration has passed, and add a timer
+    /// for the next one
+    pub fn update_output_levels(&mut self, handle: LoopHandle<Wpaperd>) {
+        if self.output_levels.expire() {
+            if let Err(err) = self.update_log_spec() {
+                warn!("{err:?}");
+            }
+        }
+        if self.output_levels_timer_armed {
+            return;
+        }
+        if let Some(wait) = self.output_levels.until_next_expiry() {
+            self.output_levels_timer_armed = true;
+            handle
+                .insert_source(Timer::from_duration(wait), |_, _, wpaperd| {
+                    wpaperd.output_levels_timer_armed = false;
+                    TimeoutAction::Drop
+                })
+                .expect("Failed to insert event source!");
+        }
+    }
+
+    /// Let through the levels raised for single outputs, or go back to the global log
+    /// specification when there are none
+    fn update_log_spec(&mut self) -> Result<()> {
+        let logger = self
+            .logger
+            .as_mut()
+            .ok_or_else(|| eyre!("the logger has not been started"))?;
+        if self.output_log_spec {
+            logger.pop_temp_spec();
+            self.output_log_spec = false;
+        }
+        if let Some(level) = self.output_levels.max_level() {
+            let spec = LogSpecification::parse(level.as_str())
+                .with_context(|| format!("parsing the log specification {level}"))?;
+            logger.push_temp_spec(spec);
+            self.output_log_spec = true;
+        }
+        Ok(())
+    }
+
+    /// Drop the surfaces of the outputs that have been disconnected for longer than
+    /// `disconnect_grace`
+    fn forget_disconnected(&mut self, now: &Instant) {
+        let grace = self.options.disconnect_grace;
+        self.disconnected
+            .retain(|(disconnected_at, _)| now.saturating_duration_since(*disconnected_at) < grace);
+    }
+
+    /// Add a timer to drop the surface of the output disconnected first once its grace has
+    /// passed, so that it doesn't wait for another output to be connected
+    pub fn schedule_forget_disconnected(&mut self, handle: LoopHandle<Wpaperd>) {
+        if self.disconnect_timer_armed {
+            return;
+        }
+        if let Some(disconnected_at) = self.disconnected.iter().map(|(at, _)| *at).min() {
+            self.disconnect_timer_armed = true;
+            let deadline = disconnected_at + self.options.disconnect_grace;
+            handle
+                .insert_source(Timer::from_deadline(deadline), |_, _, wpaperd| {
+                    wpaperd.disconnect_timer_armed = false;
+                    wpaperd.forget_disconnected(&Instant::now());
+                    TimeoutAction::Drop
+                })
+                .expect("Failed to insert event source!");
+        }
+    }
+
+    /// Destroy the surface of the output `name` and create it again from its configuration
+    pub fn restart_surface(&mut self, name: &str) -> Result<()> {
+        let index = self
+            .surfaces
+            .iter()
+            .position(|surface| surface.name() == name)
+            .ok_or_else(|| eyre!("output {name} not found"))?;
+        let output = self.surfaces[index].output.clone();
+        let qh = self.qh.clone();
+        // The old surface is dropped, destroying its layer surface
+        self.surfaces[index] = self.create_surface(&qh, output);
+        Ok(())
+    }
+
+    pub fn surface_by_name(&mut self, name: &str) -> Result<&mut Surface> {
+        self.surfaces
+            .iter_mut()
+            .find(|surface| surface.name() == name)
+            .ok_or_else(|| eyre!("output {name} not found"))
+    }
+
+    /// Save the settings the outputs are drawn with, if they differ from the configured
+    /// ones, as their new configuration. Return the outputs saved
+    pub fn save_config(&self) -> Result<Vec<String>> {
+        let wallpaper_config = self.wallpaper_config.lock().unwrap();
+        let outputs: Vec<(&str, &WallpaperInfo)> = self
+            .surfaces
+            .iter()
+            .filter(|surface| {
+                **surface.settings() != *wallpaper_config.get_output_by_name(surface.name())
+            })
+            .map(|surface| (surface.name(), surface.settings().as_ref()))
+            .collect();
+        wallpaper_config.save(&outputs)?;
+        Ok(outputs.iter().map(|(name, _)| name.to_string()).collect())
+    }
+
+    /// Describe which section of the configuration each output uses, and list the sections
+    /// that don't match any connected output
+    pub fn audit(&self) -> Vec<String> {
+        let wallpaper_config = self.wallpaper_config.lock().unwrap();
+        let has_default = wallpaper_config.has_section("default");
+        let mut report: Vec<String> = self
+            .surfaces
+            .iter()
+            .map(|surface| {
+                let name = surface.name();
+                if wallpaper_config.has_section(name) {
+                    format!("{name}: section [{name}]")
+                } else if has_default {
+                    format!("{name}: section [default]")
+                } else {
+                    format!("{name}: UNMATCHED, no section and no [default]")
+                }
+            })
+            .collect();
+        for (section, _) in wallpaper_config.sections() {
+            if section != "default" && !self.surfaces.iter().any(|s| s.name() == section) {
+                report.push(format!("[{section}]: no connected output with this name"));
+            }
+        }
+        report
+    }
+
+    /// Describe the position and the size of the outputs, in logical pixels, and what they
+    /// draw, sorted by position. With `ascii`, add a diagram of their arrangement
+    pub fn layout(&self, ascii: bool) -> Vec<String> {
+        let mut outputs: Vec<(&Surface, (i32, i32))> = self
+            .surfaces
+            .iter()
+            .map(|surface| {
+                let position = surface
+                    .info
+                    .logical_position
+                    .unwrap_or(surface.info.location);
+                (surface, position)
+            })
+            .collect();
+        outputs.sort_by_key(|(_, (x, y))| (*y, *x));
+        let mut layout: Vec<String> = outputs
+            .iter()
+            .map(|(surface, (x, y))| {
+                let (width, height) = surface.dimensions;
+                let drawn = match surface.wallpaper_info.test_pattern {
+                    Some(pattern) => format!("test pattern {pattern:?}"),
+                    None => format!("{:?}", surface.current_source()),
+                };
+                format!(
+                    "{}: {width}x{height} at {x},{y}, scale {}, {drawn}",
+                    surface.name(),
+                    surface.scale
+                )
+            })
+            .collect();
+        if ascii && !outputs.is_empty() {
+            let rects: Vec<(&str, (i32, i32), (u32, u32))> = outputs
+                .iter()
+                .map(|(surface, position)| (surface.name(), *position, surface.dimensions))
+                .collect();
+            layout.push(String::new());
+            layout.extend(layout_diagram(&rects));
+        }
+        layout
+    }
+
+    /// Forget the A/B pair of the output `name` and draw its configured wallpaper again
+    pub fn clear_ab(&mut self, name: &str) -> Result<()> {
+        let wallpaper_info = self
+            .wallpaper_config
+            .lock()
+            .unwrap()
+            .get_output_by_name(name);
+        let surface = self.surface_by_name(name)?;
+        ensure!(surface.clear_ab(), "no A/B pair has been set for {name}");
+        surface.update_wallpaper_info(wallpaper_info);
+        surface.timer_expired = true;
+        // Add the timer for the configured duration in the next loop
+        surface.timer_armed = false;
+        Ok(())
+    }
+
+    /// Return the surfaces of the outputs in `names`, or all of them when `names` is empty
+    pub fn surfaces_by_name(&mut self, names: &[&str]) -> Result<Vec<&mut Surface>> {
+        for name in names {
+            ensure!(
+                self.surfaces.iter().any(|surface| surface.name() == *name),
+                "output {name} not found"
+            );
+        }
+        Ok(self
+            .surfaces
+            .iter_mut()
+            .filter(|surface| names.is_empty() || names.contains(&surface.name()))
+            .collect())
+    }
 }
 
 impl CompositorHandler for Wpaperd {
@@ -60,19 +773,10 @@ impl CompositorHandler for Wpaperd {
         surface: &wl_surface::WlSurface,
         new_factor: i32,
     ) {
-        let mut surface = self
-            .surfaces
-            .iter_mut()
-            .enumerate()
-            .find(|(_, s)| surface == &s.surface)
-            .unwrap()
-            .1;
-
-        // Ignore unnecessary updates
-        if surface.scale != new_factor {
-            surface.scale = new_factor;
-            surface.surface.set_buffer_scale(new_factor);
-            surface.need_redraw = true;
+        // The surface could have been destroyed in the meantime
+        if let Some(surface) = self.surfaces.iter_mut().find(|s| surface == &s.surface) {
+            surface.compositor_scale = new_factor;
+            surface.update_scale();
         }
     }
 
@@ -80,9 +784,13 @@ impl CompositorHandler for Wpaperd {
         &mut self,
         _conn: &Connection,
         _qh: &QueueHandle<Self>,
-        _surface: &wl_surface::WlSurface,
+        surface: &wl_surface::WlSurface,
         _time: u32,
     ) {
+        // The surface could have been destroyed in the meantime
+        if let Some(surface) = self.surfaces.iter_mut().find(|s| surface == &s.surface) {
+            surface.frame_done();
+        }
     }
 }
 
@@ -97,31 +805,22 @@ impl OutputHandler for Wpaperd {
         qh: &QueueHandle<Self>,
         output: wl_output::WlOutput,
     ) {
-        // TODO: Error handling
-        let surface = self.compositor_state.create_surface(qh);
-
-        let info = self.output_state.info(&output).unwrap();
-        let scale = if self.use_scaled_window {
-            1
-        } else {
-            info.scale_factor
-        };
-        surface.set_buffer_scale(scale);
-
-        let name = info.name.as_ref().unwrap().to_string();
-
-        self.surfaces.push(Surface::new(
-            qh,
-            output,
-            &self.layer_state,
-            surface,
-            &self.shm_state,
-            info,
-            self.wallpaper_config
-                .lock()
-                .unwrap()
-                .get_output_by_name(&name),
-        ));
+        let mut surface = self.create_surface(qh, output);
+        self.forget_disconnected(&Instant::now());
+        if let Some(index) = self
+            .disconnected
+            .iter()
+            .position(|(_, disconnected)| disconnected.name() == surface.name())
+        {
+            let (_, disconnected) = self.disconnected.swap_remove(index);
+            info!(
+                "output {} reconnected, resuming its wallpaper",
+                surface.name()
+            );
+            surface.resume(disconnected);
+        }
+        self.surfaces.push(surface);
+        self.update_decode_threads();
     }
 
     fn update_output(
@@ -140,7 +839,7 @@ impl OutputHandler for Wpaperd {
         output: wl_output::WlOutput,
     ) {
         // Find the destroyed output and remove it
-        self.surfaces.swap_remove(
+        let surface = self.surfaces.swap_remove(
             self.surfaces
                 .iter()
                 .enumerate()
@@ -148,6 +847,11 @@ impl OutputHandler for Wpaperd {
                 .unwrap()
                 .0,
         );
+        let now = Instant::now();
+        self.forget_disconnected(&now);
+        if !self.options.disconnect_grace.is_zero() {
+            self.disconnected.push((now, surface));
+        }
     }
 }
 
@@ -162,19 +866,22 @@ impl LayerShellHandler for Wpaperd {
         configure: LayerSurfaceConfigure,
         _serial: u32,
     ) {
-        let surface = self
+        // The surface could have been destroyed, e.g. by `restart-surface`, before the
+        // configure event has been dispatched
+        let surface = match self
             .surfaces
             .iter_mut()
             .find(|surface| &surface.layer == layer)
-            // We always know the surface that it is being configured
-            .unwrap();
+        {
+            Some(surface) => surface,
+            None => return,
+        };
 
-        if surface.dimensions != configure.new_size {
-            // Update dimensions
-            surface.dimensions = configure.new_size;
-            surface.need_redraw = true;
-        }
+        surface.set_dimensions(configure.new_size);
 
+        if !surface.configured {
+            surface.configured_at = Some(Instant::now());
+        }
         surface.configured = true;
     }
 }
@@ -197,3 +904,66 @@ impl ProvidesRegistryState for Wpaperd {
     }
     registry_handlers![OutputState];
 }
+
+/// Draw the outputs as boxes of characters, scaled to `DIAGRAM_WIDTH` columns. A character is
+/// about twice as high as it is wide, so the rows are halved
+fn layout_diagram(outputs: &[(&str, (i32, i32), (u32, u32))]) -> Vec<String> {
+    const DIAGRAM_WIDTH: f64 = 72.0;
+    let left = outputs.iter().map(|(_, (x, _), _)| *x).min().unwrap_or(0);
+    let top = outputs.iter().map(|(_, (_, y), _)| *y).min().unwrap_or(0);
+    let right = outputs
+        .iter()
+        .map(|(_, (x, _), (width, _))| *x + *width as i32)
+        .max()
+        .unwrap_or(0);
+    let bottom = outputs
+        .iter()
+        .map(|(_, (_, y), (_, height))| *y + *height as i32)
+        .max()
+        .unwrap_or(0);
+    let ratio = DIAGRAM_WIDTH / f64::from((right - left).max(1));
+    let column = |x: i32| (f64::from(x - left) * ratio).round() as usize;
+    let row = |y: i32| (f64::from(y - top) * ratio / 2.0).round() as usize;
+
+    let mut grid = vec![vec![' '; column(right) + 1]; row(bottom) + 1];
+    for (name, (x, y), (width, height)) in outputs {
+        let (x0, y0) = (column(*x), row(*y));
+        // Keep room for the borders even for the smallest outputs
+        let x1 = column(*x + *width as i32).max(x0 + 2);
+        let y1 = row(*y + *height as i32).max(y0 + 2);
+        for (r, cells) in grid.iter_mut().enumerate().take(y1 + 1).skip(y0) {
+            for (c, cell) in cells.iter_mut().enumerate().take(x1 + 1).skip(x0) {
+                match (r == y0 || r == y1, c == x0 || c == x1) {
+                    (true, true) => *cell = '+',
+                    (true, false) => *cell = '-',
+                    (false, true) => *cell = '|',
+                    (false, false) => {}
+                }
+            }
+        }
+        if let Some(cells) = grid.get_mut(y0 + 1).filter(|_| y0 + 1 < y1) {
+            for (cell, ch) in cells.iter_mut().take(x1).skip(x0 + 1).zip(name.chars()) {
+                *cell = ch;
+            }
+        }
+    }
+    grid.into_iter()
+        .map(|cells| cells.into_iter().collect::<String>().trim_end().to_string())
+        .collect()
+}
+
+/// Derive the seed of an output from the global one, so that each output chooses a
+/// different sequence of images that can still be reproduced. FNV-1a is used because,
+/// unlike the std hashers, its output is stable across Rust versions
+fn output_seed(random_seed: u64, name: &str) -> u64 {
+    const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    random_seed
+        .to_le_bytes()
+        .iter()
+        .chain(name.as_bytes())
+        .fold(FNV_OFFSET_BASIS, |hash, byte| {
+            (hash ^ u64::from(*byte)).wrapping_mul(FNV_PRIME)
+        })
+}